@@ -0,0 +1,75 @@
+//! Times generating a page that uses one element 1000 times, once with a
+//! definition that has nothing to expand and once with one that does. The
+//! first only costs a copy of the definition per use, since instantiation
+//! skips the passes a definition has no need for.
+//!
+//! Run with `cargo bench --bench instantiation`.
+
+use html_generator::{Generator, Options};
+use std::{fs, path, time};
+
+const USES: usize = 1000;
+const RUNS: u32 = 20;
+
+// Generate a page using `definition` as <card> USES times, RUNS times over,
+// returning the average time taken and the generated page
+fn time_site(name: &str, definition: &str) -> (time::Duration, String) {
+    let root =
+        std::env::temp_dir().join(format!("baumkuchen-bench-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("pages")).unwrap();
+    fs::create_dir_all(root.join("elements")).unwrap();
+    fs::write(root.join("elements/card.html"), definition).unwrap();
+    let cards: String = (0..USES)
+        .map(|n| format!(r#"<card title="Card {n}"><p>{n}</p></card>"#))
+        .collect();
+    fs::write(
+        root.join("pages/index.html"),
+        format!("<html><body>{}</body></html>", cards),
+    )
+    .unwrap();
+
+    let options = Options {
+        quiet: true,
+        ..Options::default()
+    };
+    let mut generator = Generator::new(
+        &root.join("pages"),
+        &[root.join("elements")],
+        &root.join("output"),
+        options,
+    )
+    .unwrap();
+    let start = time::Instant::now();
+    for _ in 0..RUNS {
+        generator.run().unwrap();
+    }
+    let elapsed = start.elapsed() / RUNS;
+
+    let page = fs::read_to_string(root.join(path::Path::new("output/index.html"))).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+    (elapsed, page)
+}
+
+fn main() {
+    let (plain, plain_page) = time_site("plain", r#"<div class="card"><hr/></div>"#);
+    let (expanded, expanded_page) = time_site(
+        "expanded",
+        r#"<div class="card" title="${self.title}"><self.inner/></div>"#,
+    );
+    assert_eq!(plain_page.matches("<hr>").count(), USES);
+    assert_eq!(expanded_page.matches("<p>").count(), USES);
+
+    println!(
+        "{} uses of a definition with nothing to expand: {:?} per page, {:?} per use",
+        USES,
+        plain,
+        plain / USES as u32
+    );
+    println!(
+        "{} uses of a definition with expressions: {:?} per page, {:?} per use",
+        USES,
+        expanded,
+        expanded / USES as u32
+    );
+}
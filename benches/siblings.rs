@@ -0,0 +1,68 @@
+//! Times generating pages with 1000 and 2000 sibling invocations of an
+//! element. Since the children of an element are expanded in a single pass,
+//! twice as many siblings should take about twice as long, rather than four
+//! times as long as when every expansion started over from the first child.
+//!
+//! Run with `cargo bench --bench siblings`.
+
+use html_generator::{Generator, Options};
+use std::{fs, time};
+
+const RUNS: u32 = 10;
+
+// Generate a page of `count` sibling invocations RUNS times over, returning
+// the average time taken
+fn time_siblings(count: usize) -> time::Duration {
+    let root = std::env::temp_dir().join(format!(
+        "baumkuchen-bench-siblings-{}-{}",
+        count,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("pages")).unwrap();
+    fs::create_dir_all(root.join("elements")).unwrap();
+    fs::write(
+        root.join("elements/item.html"),
+        r#"<li class="item"><self.inner /></li>"#,
+    )
+    .unwrap();
+    let items: String = (0..count).map(|n| format!("<item>{n}</item>")).collect();
+    fs::write(
+        root.join("pages/index.html"),
+        format!("<html><body><ul>{}</ul></body></html>", items),
+    )
+    .unwrap();
+
+    let options = Options {
+        quiet: true,
+        ..Options::default()
+    };
+    let mut generator = Generator::new(
+        &root.join("pages"),
+        &[root.join("elements")],
+        &root.join("output"),
+        options,
+    )
+    .unwrap();
+    let start = time::Instant::now();
+    for _ in 0..RUNS {
+        generator.run().unwrap();
+    }
+    let elapsed = start.elapsed() / RUNS;
+
+    let page = fs::read_to_string(root.join("output/index.html")).unwrap();
+    assert_eq!(page.matches("<li class=\"item\">").count(), count);
+    fs::remove_dir_all(&root).unwrap();
+    elapsed
+}
+
+fn main() {
+    let thousand = time_siblings(1000);
+    let two_thousand = time_siblings(2000);
+    println!("1000 siblings: {:?} per page", thousand);
+    println!("2000 siblings: {:?} per page", two_thousand);
+    println!(
+        "ratio: {:.2} (2 is linear, 4 quadratic)",
+        two_thousand.as_secs_f64() / thousand.as_secs_f64()
+    );
+}
@@ -0,0 +1,8391 @@
+use rayon::prelude::*;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    path,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time,
+};
+use xot::Xot;
+
+/// Anything that can go wrong while generating a site
+#[derive(Debug)]
+pub enum BuildError {
+    /// Reading, writing, or copying a file failed
+    Io(io::Error),
+    /// A page or element definition is not well-formed
+    Parse {
+        path: path::PathBuf,
+        message: String,
+    },
+    /// Manipulating a document failed
+    Xot(xot::Error),
+    /// Pages left out attributes that an element requires or gave values
+    /// that it doesn't allow, or expanded more elements than
+    /// `Options::max_expansions` allows
+    Invalid(Vec<String>),
+    /// Pages referred to attributes or expressions that don't exist, or had
+    /// something else wrong with them that only fails the build in strict mode
+    Undefined(Vec<String>),
+    /// Several pages failed to generate, when not failing fast
+    Multiple(Vec<BuildError>),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io(err) => write!(f, "{}", err),
+            BuildError::Parse { path, message } => {
+                write!(f, "Failed to parse {}: {}", path.display(), message)
+            }
+            BuildError::Xot(err) => write!(f, "{}", err),
+            BuildError::Invalid(messages) | BuildError::Undefined(messages) => {
+                write!(f, "{} problem(s) found in pages:", messages.len())?;
+                for message in messages {
+                    write!(f, "\n    {}", message)?;
+                }
+                Ok(())
+            }
+            BuildError::Multiple(errors) => {
+                write!(f, "{} pages failed:", errors.len())?;
+                for err in errors {
+                    write!(f, "\n    {}", err.to_string().replace('\n', "\n    "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl BuildError {
+    /// The exit code the command line tool reports the error with:
+    /// 1 for reading and writing files, 2 for malformed pages, elements,
+    /// and config files, 3 for invalid uses of elements and runaway
+    /// expansion, and 4 for problems found in strict mode. Several errors
+    /// are reported with the code of the first one.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BuildError::Io(_) | BuildError::Xot(_) => 1,
+            BuildError::Parse { .. } => 2,
+            BuildError::Invalid(_) => 3,
+            BuildError::Undefined(_) => 4,
+            BuildError::Multiple(errors) => errors.first().map_or(1, BuildError::exit_code),
+        }
+    }
+}
+
+impl BuildError {
+    /// The error as diagnostics, such as for an editor to show. Line and
+    /// column are only known for some malformed files. Problems collected
+    /// in `Invalid` and `Undefined` aren't included, since each was already
+    /// recorded as a diagnostic of its own, which `Generator::diagnostics`
+    /// gives.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            BuildError::Io(err) => vec![Diagnostic::error(None, err.to_string())],
+            BuildError::Xot(err) => vec![Diagnostic::error(None, err.to_string())],
+            BuildError::Parse { path, message } => {
+                let mut diagnostic =
+                    Diagnostic::error(Some(path.display().to_string()), message.clone());
+                let position = Regex::new(r"at (?:line )?(\d+)(?::| column )(\d+)").unwrap();
+                if let Some(captures) = position.captures(message) {
+                    diagnostic.line = captures[1].parse().ok();
+                    diagnostic.column = captures[2].parse().ok();
+                }
+                vec![diagnostic]
+            }
+            BuildError::Invalid(_) | BuildError::Undefined(_) => Vec::new(),
+            BuildError::Multiple(errors) => {
+                errors.iter().flat_map(BuildError::diagnostics).collect()
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A warning or error about the site, in a form meant for other programs
+/// such as editors
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    /// Path of the file the diagnostic is about, if any
+    pub file: Option<String>,
+    /// Line in the file, counting from 1, if known
+    pub line: Option<usize>,
+    /// Column in the line, counting from 1, if known
+    pub column: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// How bad a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    /// The build failed because of it
+    Error,
+    /// The site was generated, but probably not as intended
+    Warning,
+}
+
+impl Diagnostic {
+    fn error(file: Option<String>, message: String) -> Diagnostic {
+        Diagnostic {
+            file,
+            line: None,
+            column: None,
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    /// A warning about the given file, or about the whole build
+    pub fn warning(file: Option<String>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(file, message)
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> BuildError {
+        BuildError::Io(err)
+    }
+}
+
+impl From<xot::Error> for BuildError {
+    fn from(err: xot::Error) -> BuildError {
+        BuildError::Xot(err)
+    }
+}
+
+// Read a text file, which must be UTF-8, ignoring any byte order mark
+fn read_source(path: &path::Path) -> Result<String, BuildError> {
+    let bytes = fs::read(path)?;
+    let mut text = String::from_utf8(bytes).map_err(|err| BuildError::Parse {
+        path: path.to_path_buf(),
+        message: format!(
+            "the file is not valid UTF-8 (invalid byte at offset {})",
+            err.utf8_error().valid_up_to()
+        ),
+    })?;
+    if text.starts_with('\u{FEFF}') {
+        text.drain(..'\u{FEFF}'.len_utf8());
+    }
+    Ok(text)
+}
+
+/// Everything needed to expand elements on the page currently being generated
+#[derive(Clone)]
+pub struct Context<'a> {
+    // path of the document currently being generated, relative
+    // to the root of the source directory
+    file_path: String,
+    // url the document currently being generated is served at
+    url: String,
+    build: &'a Build,
+    // options in effect for the document currently being generated
+    options: &'a Options,
+    // expressions with a fixed value in the current scope, such as
+    // 'self.value' inside of <foreachvalue.*>
+    bindings: HashMap<String, String>,
+    // data with a name in the current scope, such as 'item' inside of <each>,
+    // which takes precedence over the build's data files
+    data: HashMap<String, serde_json::Value>,
+    // the current child of every <foreachchild.x> the current scope is in,
+    // by the name of its loop variable
+    loop_children: HashMap<String, xot::Node>,
+    // how many attribute values are currently being expanded within each
+    // other, to stop attributes that refer to each other in a circle
+    expansion_depth: usize,
+    // what has been found out about the document so far, shared by all
+    // scopes within it
+    document: Rc<RefCell<DocumentState>>,
+    regex_dollar_expansion: Regex,
+    regex_or_expr: Regex,
+}
+
+// Facts about a document collected while expanding its elements, which the
+// passes after expansion depend on
+#[derive(Default)]
+struct DocumentState {
+    // top-level nodes produced by elements marked as inline
+    inline_nodes: HashSet<xot::Node>,
+    // whether any of the elements used carry a scoped stylesheet
+    uses_component_styles: bool,
+    // nodes whose whitespace is significant and must survive minification
+    preformatted_nodes: HashSet<xot::Node>,
+    // text nodes at the edges of slots marked with preserve-space, whose
+    // leading and trailing whitespace minification keeps as a single space
+    spaced_nodes: HashSet<xot::Node>,
+    // whether any element restricting the elements it uses has been used,
+    // before which `element_scopes` doesn't need to be kept track of
+    scoped: bool,
+    // nodes below which only the given elements are expanded, or any for
+    // None, up to the next node with a scope of its own
+    element_scopes: HashMap<xot::Node, Option<ElementScope>>,
+    // how many elements have been expanded on the page so far
+    expansions: usize,
+    // whether expansion was stopped for exceeding `Options::max_expansions`
+    gave_up: bool,
+    // copies of an invocation's children put into an element's markup,
+    // whose contents belong to the page rather than to the element, and so
+    // aren't looked into by the element's loops
+    copied_nodes: HashSet<xot::Node>,
+    // with `Options::source_maps`, the name and definition of every element
+    // expanded on the page, in order
+    mapped_elements: Vec<(String, path::PathBuf)>,
+    // with `Options::source_maps`, the top-level nodes produced by elements,
+    // along with which of `mapped_elements` they came out of, outermost first
+    mapped_nodes: HashMap<xot::Node, Vec<usize>>,
+}
+
+impl DocumentState {
+    // The elements that may be expanded at a node, or None for any
+    fn element_scope(&self, xot: &Xot, node: xot::Node) -> Option<ElementScope> {
+        if !self.scoped {
+            return None;
+        }
+        xot.ancestors(node)
+            .find_map(|ancestor| self.element_scopes.get(&ancestor))
+            .cloned()
+            .flatten()
+    }
+
+    // Markup copied from an invocation into an element's own markup is still
+    // expanded with the elements available where the invocation was
+    fn keep_invocation_scope(&mut self, xot: &Xot, invocation: xot::Node, copy: xot::Node) {
+        if self.scoped {
+            let scope = self.element_scope(xot, invocation);
+            self.element_scopes.insert(copy, scope);
+        }
+    }
+}
+
+impl<'a> Context<'a> {
+    /// A context for generating the page at `file_path`, given relative to
+    /// the root of the source directory with a leading '/'
+    pub fn new(file_path: String, build: &'a Build) -> Context<'a> {
+        let regex_dollar_expansion =
+            Regex::new(r"\$\$\{|\$\{([a-zA-Z0-9_\-\.:\|\[\]]+)(?::(%[^}]*))?}").unwrap();
+        let regex_or_expr = Regex::new(r"^([a-zA-Z0-9_\-\.:]+)\|\|([a-zA-Z0-9_\-\.:]+)$").unwrap();
+
+        Context {
+            url: file_path.clone(),
+            file_path,
+            build,
+            options: &build.options,
+            bindings: HashMap::new(),
+            data: HashMap::new(),
+            loop_children: HashMap::new(),
+            expansion_depth: 0,
+            document: Rc::new(RefCell::new(DocumentState::default())),
+            regex_dollar_expansion,
+            regex_or_expr,
+        }
+    }
+
+    /// The same context, but using the given options instead of those of the
+    /// whole build, e.g. as overridden for a subdirectory
+    pub fn with_options(mut self, options: &'a Options) -> Context<'a> {
+        self.options = options;
+        self
+    }
+
+    /// Path of the page being generated, relative to the root of the source
+    /// directory with a leading '/'
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// The same context, but for a page served at `url` rather than at the
+    /// path of its source file
+    pub fn with_url(mut self, url: String) -> Context<'a> {
+        self.url = url;
+        self
+    }
+
+    // A copy of the context in which the expression `name` evaluates to `value`
+    fn with_binding(&self, name: &str, value: String) -> Context<'a> {
+        let mut context = self.clone();
+        context.bindings.insert(name.to_string(), value);
+        context
+    }
+
+    // Report a reference to something that doesn't exist, which fails the
+    // build in strict mode and is only a warning otherwise
+    fn undefined(&self, message: &str) {
+        if self.options.strict {
+            self.build.error_in(
+                &self.build.undefined,
+                &self.source_path(),
+                &self.file_path,
+                message,
+            );
+        } else {
+            self.warn(message);
+        }
+    }
+
+    // Warn about something odd on the current page
+    fn warn(&self, message: &str) {
+        self.build
+            .warn_in(&self.source_path(), &self.file_path, message);
+    }
+
+    // Fail the build once all pages are generated, because the current page
+    // uses an element in a way that its definition doesn't allow
+    fn error(&self, message: &str) {
+        self.build.error_in(
+            &self.build.invalid,
+            &self.source_path(),
+            &self.file_path,
+            message,
+        );
+    }
+
+    // Path of the current page's source file
+    fn source_path(&self) -> path::PathBuf {
+        self.build
+            .source_root
+            .join(self.file_path.trim_start_matches('/'))
+    }
+
+    // A copy of the context in which `name` refers to the given data
+    fn with_data(&self, name: &str, value: serde_json::Value) -> Context<'a> {
+        let mut context = self.clone();
+        context.data.insert(name.to_string(), value);
+        context
+    }
+
+    // Look up a path like 'site.nav.0.label' in the data in scope. The first
+    // part names the data, the rest are keys of objects or indices of lists.
+    fn lookup_data(&self, path: &str) -> Option<&serde_json::Value> {
+        let mut parts = path.split('.');
+        let name = parts.next()?;
+        let mut value = self.data.get(name).or_else(|| self.build.data.get(name))?;
+        for part in parts {
+            value = match value {
+                serde_json::Value::Object(map) => map.get(part)?,
+                serde_json::Value::Array(list) => list.get(part.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+// The text that a piece of data is substituted with
+fn data_to_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "".to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+// Whether a character is whitespace that html itself collapses. Other
+// whitespace like non-breaking spaces is significant and must be kept.
+fn is_collapsible_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0C')
+}
+
+/// Remove comments and outer whitespace from an existing node
+pub fn minify(xot: &mut Xot, node: xot::Node) -> Result<(), xot::Error> {
+    minify_around(xot, node, &DocumentState::default(), false, false)
+}
+
+// Minify, but keep a single space between any of the document's inline nodes
+// and their siblings where there was whitespace, leave its preformatted nodes
+// alone, and optionally keep comments and text that is all whitespace
+fn minify_around(
+    xot: &mut Xot,
+    node: xot::Node,
+    document: &DocumentState,
+    keep_comments: bool,
+    keep_whitespace_text: bool,
+) -> Result<(), xot::Error> {
+    if document.preformatted_nodes.contains(&node) {
+        return Ok(());
+    }
+
+    if xot.is_comment(node) {
+        if keep_comments {
+            return Ok(());
+        }
+        return xot.remove(node);
+    }
+
+    if let Some(text) = xot.text(node) {
+        let orig_text = text.get();
+
+        // Replace all runs of whitespace with just a single space
+        let mut trimmed = {
+            let mut s = String::new();
+            let mut words = orig_text
+                .split(is_collapsible_whitespace)
+                .filter(|w| !w.is_empty());
+            if let Some(w) = words.next() {
+                s = w.to_string();
+            }
+            while let Some(w) = words.next() {
+                s += " ";
+                s += w;
+            }
+            s
+        };
+
+        // Text at the edge of a slot keeps its outer spaces regardless
+        let spaced = document.spaced_nodes.contains(&node);
+
+        // Add backing a leading space if it was removed and there is a previous node
+        {
+            if (spaced || xot.previous_sibling(node).is_some())
+                && orig_text.starts_with(is_collapsible_whitespace)
+            {
+                trimmed.insert(0, ' ');
+            }
+        }
+
+        // Add backing a trailing space if it was removed and there is a next node
+        {
+            if (spaced || xot.next_sibling(node).is_some())
+                && orig_text.ends_with(is_collapsible_whitespace)
+            {
+                trimmed.push(' ');
+            }
+        }
+
+        // Remove the node outright if it is empty or all white space
+        // NOTE: this implicitly assumes that both adjacent siblings are not inline
+        // elements, unless they were produced by an element marked as inline
+        if trimmed.chars().all(is_collapsible_whitespace) {
+            // Unless asked to keep it as a single space, in case the above
+            // assumption is wrong
+            if keep_whitespace_text && !orig_text.is_empty() {
+                if orig_text != " " {
+                    xot.text_mut(node).unwrap().set(" ");
+                }
+                return Ok(());
+            }
+            if spaced && !trimmed.is_empty() {
+                if orig_text != " " {
+                    xot.text_mut(node).unwrap().set(" ");
+                }
+                return Ok(());
+            }
+            let previous = xot.previous_sibling(node);
+            let next = xot.next_sibling(node);
+            let next_to_inline = previous.is_some_and(|n| document.inline_nodes.contains(&n))
+                || next.is_some_and(|n| document.inline_nodes.contains(&n));
+            if !trimmed.is_empty() && next_to_inline && previous.is_some() && next.is_some() {
+                if orig_text != " " {
+                    xot.text_mut(node).unwrap().set(" ");
+                }
+                return Ok(());
+            }
+            return xot.remove(node);
+        }
+
+        if trimmed != orig_text {
+            xot.text_mut(node).unwrap().set(trimmed);
+        }
+    }
+
+    let children: Vec<xot::Node> = xot.children(node).collect();
+    for child in &children {
+        minify_around(xot, *child, document, keep_comments, keep_whitespace_text)?;
+    }
+
+    Ok(())
+}
+
+/// Look for and replace single instances of a named tag with
+/// the given replacement
+pub fn substitute_tag(
+    xot: &mut Xot,
+    node: xot::Node,
+    tag_name: xot::NameId,
+    replacement: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    debug_assert!(!xot.is_removed(node));
+    debug_assert!(!xot.is_removed(replacement));
+    let xot::Value::Element(elem) = xot.value(node) else {
+        return Ok(());
+    };
+    if context.document.borrow().copied_nodes.contains(&node) {
+        return Ok(());
+    }
+    if elem.name() == tag_name {
+        let r = xot.clone(replacement);
+        let mut document = context.document.borrow_mut();
+        document.keep_invocation_scope(xot, invocation, r);
+        document.copied_nodes.insert(r);
+        drop(document);
+        // expand and propagate any attributes
+        let orig_attrs: Vec<(String, String)> = xot
+            .attributes(node)
+            .iter()
+            .map(|(key, value)| {
+                let key = xot.name_ns_str(key).0.to_string();
+                let value = expand_string(xot, value, invocation, context);
+                (key, value)
+            })
+            .collect();
+        xot.replace(node, r)?;
+        for (key, value) in orig_attrs {
+            let key_id = xot.add_name(&key);
+            let value = match xot.attributes(r).get(key_id) {
+                Some(own) if context.options.merges_attribute(&key) => {
+                    merge_attribute_values(&key, own, &value)
+                }
+                _ => value,
+            };
+            xot.attributes_mut(r).insert(key_id, value);
+        }
+        return Ok(());
+    }
+    let children: Vec<xot::Node> = xot.children(node).collect();
+    for child in children {
+        substitute_tag(xot, child, tag_name, replacement, invocation, context)?;
+    }
+    Ok(())
+}
+
+// The <prop name="..."> child of an invocation that passes markup to the
+// element under the given name, if any
+fn find_prop(xot: &Xot, invocation: xot::Node, name: &str) -> Option<xot::Node> {
+    let name_id = xot.name("name")?;
+    xot.children(invocation).find(|child| {
+        is_prop(xot, *child)
+            && xot
+                .attributes(*child)
+                .get(name_id)
+                .is_some_and(|n| n == name)
+    })
+}
+
+// Whether a child of an invocation is a <prop> rather than content
+fn is_prop(xot: &Xot, node: xot::Node) -> bool {
+    xot.name("prop")
+        .is_some_and(|prop| xot.node_name(node) == Some(prop))
+}
+
+/// Replace a `<foreachchild.x>` element with one copy of its contents for
+/// every child element of the invocation, with `<x>` standing in for the child
+pub fn substitute_foreach(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let loop_var_str = xot
+        .name_ns_str(xot.node_name(node).unwrap())
+        .0
+        .strip_prefix("foreachchild.")
+        .unwrap()
+        .to_string();
+
+    // The loop is still needed when `<x>` itself appears nowhere, such as
+    // for `<x.title>` or for an inner loop with in="x"
+    let loop_var = xot.add_name(&loop_var_str);
+
+    // The contents can be any number of elements, such as a <dt> and <dd>
+    // for every child, along with any text between them. Whitespace around
+    // them is left out so as not to be repeated.
+    let template = trim_padding(xot, xot.children(node).collect());
+    let separator = loop_separator(xot, node);
+
+    // With in="y" inside of a <foreachchild.y>, the children of its current
+    // child are repeated for instead of those of the invocation, as for the
+    // items of each section
+    let parent = match xot.name("in").and_then(|id| xot.attributes(node).get(id)) {
+        None => invocation,
+        Some(outer) => match context.loop_children.get(outer) {
+            Some(outer_child) => *outer_child,
+            None => {
+                context.undefined(&format!(
+                    "<foreachchild.{} in=\"{}\"> isn't inside of a <foreachchild.{}>",
+                    loop_var_str, outer, outer
+                ));
+                xot.detach(node)?;
+                return Ok(());
+            }
+        },
+    };
+
+    // With from="items", only the children of its <items> elements are
+    // repeated for, so that an element can take several groups of children
+    let from = xot.name("from").and_then(|id| xot.attributes(node).get(id));
+    let children: Vec<xot::Node> = match from {
+        None => xot.children(parent).collect(),
+        Some(from) => {
+            let group_name = xot.name(from);
+            xot.children(parent)
+                .filter(|child| group_name.is_some() && xot.node_name(*child) == group_name)
+                .flat_map(|group| xot.children(group))
+                .collect()
+        }
+    };
+    let mut first = true;
+    for inv_child in children {
+        // don't replace outer white space, text, comments, or <prop>s
+        if !xot.is_element(inv_child) || is_prop(xot, inv_child) {
+            continue;
+        }
+        if !std::mem::take(&mut first) {
+            insert_separator(xot, node, separator.as_deref())?;
+        }
+
+        // Within the loop, 'x.abc' refers to the 'abc' attribute of the
+        // current child, e.g. to compare it against the invocation's
+        let mut child_context = context.clone();
+        for (key, value) in xot.attributes(inv_child).iter() {
+            let name = format!("{}.{}", loop_var_str, xot.name_ns_str(key).0);
+            child_context.bindings.insert(name, value.clone());
+        }
+        child_context
+            .loop_children
+            .insert(loop_var_str.clone(), inv_child);
+
+        for node_child in &template {
+            let ch = xot.clone(*node_child);
+
+            xot.insert_before(node, ch)?;
+
+            expand_all_attr_strings(xot, ch, invocation, &child_context)?;
+            substitute_invocation(xot, ch, invocation, &child_context)?;
+            substitute_tag(xot, ch, loop_var, inv_child, invocation, &child_context)?;
+        }
+    }
+    // xot.remove(node)?;
+    xot.detach(node)?;
+    return Ok(());
+}
+
+// Leave out whitespace before the first and after the last of some nodes
+fn trim_padding(xot: &Xot, nodes: Vec<xot::Node>) -> Vec<xot::Node> {
+    let is_padding = |n: &xot::Node| {
+        xot.text(*n)
+            .is_some_and(|text| text.get().chars().all(is_collapsible_whitespace))
+    };
+    let start = nodes
+        .iter()
+        .position(|n| !is_padding(n))
+        .unwrap_or(nodes.len());
+    let end = nodes
+        .iter()
+        .rposition(|n| !is_padding(n))
+        .map_or(start, |i| i + 1);
+    nodes[start..end].to_vec()
+}
+
+// The text put between the iterations of a loop, as given in its
+// 'separator' attribute, if any
+fn loop_separator(xot: &Xot, node: xot::Node) -> Option<String> {
+    xot.name("separator")
+        .and_then(|id| xot.attributes(node).get(id))
+        .cloned()
+}
+
+// The nodes repeated by a loop and the text put between iterations. With a
+// separator, whitespace around the contents is left out, so that only the
+// separator ends up between them, as in "a, b, c".
+fn loop_template(xot: &Xot, node: xot::Node) -> (Vec<xot::Node>, Option<String>) {
+    let template: Vec<xot::Node> = xot.children(node).collect();
+    match loop_separator(xot, node) {
+        Some(separator) => (trim_padding(xot, template), Some(separator)),
+        None => (template, None),
+    }
+}
+
+// Insert the separator between two iterations of a loop, before `node`
+fn insert_separator(
+    xot: &mut Xot,
+    node: xot::Node,
+    separator: Option<&str>,
+) -> Result<(), xot::Error> {
+    if let Some(separator) = separator.filter(|separator| !separator.is_empty()) {
+        let text = xot.new_text(separator);
+        xot.insert_before(node, text)?;
+    }
+    Ok(())
+}
+
+/// Repeat the contents of a `<foreachvalue.xyz>` element once for every value
+/// in the invocation's 'xyz' attribute, split by the separator given in the
+/// 'sep' attribute (a comma by default). Within each repetition, 'self.value'
+/// refers to the current value.
+pub fn substitute_foreachvalue(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let attr_name = xot
+        .name_ns_str(xot.node_name(node).unwrap())
+        .0
+        .strip_prefix("foreachvalue.")
+        .unwrap()
+        .to_string();
+
+    let separator = xot
+        .name("sep")
+        .and_then(|id| xot.attributes(node).get(id))
+        .cloned()
+        .unwrap_or_else(|| ",".to_string());
+
+    // a missing attribute is treated like an empty list
+    let list = xot
+        .name(&attr_name)
+        .and_then(|id| xot.attributes(invocation).get(id))
+        .cloned()
+        .unwrap_or_default();
+
+    let values: Vec<&str> = if separator.is_empty() {
+        vec![list.trim()]
+    } else {
+        list.split(separator.as_str()).map(str::trim).collect()
+    };
+
+    let (template, separator) = loop_template(xot, node);
+    for (index, value) in values.into_iter().filter(|v| !v.is_empty()).enumerate() {
+        if index > 0 {
+            insert_separator(xot, node, separator.as_deref())?;
+        }
+        let value_context = context.with_binding("self.value", value.to_string());
+        for child in &template {
+            let ch = xot.clone(*child);
+            xot.insert_before(node, ch)?;
+            expand_all_attr_strings(xot, ch, invocation, &value_context)?;
+            substitute_invocation(xot, ch, invocation, &value_context)?;
+        }
+    }
+    xot.detach(node)?;
+    Ok(())
+}
+
+/// Repeat the contents of a `<foreachattr>` element once for every attribute
+/// of the invocation, except for those listed in its 'exclude' attribute.
+/// Within each repetition, 'attr.name' and 'attr.value' refer to the name
+/// and value of the current attribute.
+pub fn substitute_foreachattr(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let excluded: Vec<String> = xot
+        .name("exclude")
+        .and_then(|id| xot.attributes(node).get(id))
+        .map(|exclude| {
+            exclude
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let attributes: Vec<(String, String)> = xot
+        .attributes(invocation)
+        .iter()
+        .map(|(key, value)| (xot.name_ns_str(key).0.to_string(), value.clone()))
+        .filter(|(name, _)| !excluded.contains(name))
+        .collect();
+
+    let (template, separator) = loop_template(xot, node);
+    for (index, (name, value)) in attributes.into_iter().enumerate() {
+        if index > 0 {
+            insert_separator(xot, node, separator.as_deref())?;
+        }
+        // Values referring to other attributes are expanded like in expressions
+        let value = if value.contains("${") {
+            expand_string(xot, &value, invocation, context)
+        } else {
+            value
+        };
+        let mut attr_context = context.with_binding("attr.name", name);
+        attr_context
+            .bindings
+            .insert("attr.value".to_string(), value);
+        for child in &template {
+            let ch = xot.clone(*child);
+            xot.insert_before(node, ch)?;
+            expand_all_attr_strings(xot, ch, invocation, &attr_context)?;
+            substitute_invocation(xot, ch, invocation, &attr_context)?;
+        }
+    }
+    xot.detach(node)?;
+    Ok(())
+}
+
+/// Repeat the contents of an `<each in="xyz">` element once for every item in
+/// the list found at the data path 'xyz'. Within each repetition, the item is
+/// available as 'item', or under the name given in the 'as' attribute.
+pub fn substitute_each(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let attribute = |name: &str| {
+        xot.name(name)
+            .and_then(|id| xot.attributes(node).get(id))
+            .cloned()
+    };
+    let item_name = attribute("as").unwrap_or_else(|| "item".to_string());
+
+    let items = match attribute("in") {
+        None => {
+            context.warn("<each> element without an 'in' attribute");
+            Vec::new()
+        }
+        Some(path) => match context.lookup_data(&path) {
+            Some(serde_json::Value::Array(items)) => items.clone(),
+            Some(_) => {
+                context.warn(&format!(
+                    "<each in=\"{}\"> refers to data that is not a list",
+                    path
+                ));
+                Vec::new()
+            }
+            None => {
+                context.warn(&format!(
+                    "<each in=\"{}\"> refers to data that doesn't exist",
+                    path
+                ));
+                Vec::new()
+            }
+        },
+    };
+
+    let (template, separator) = loop_template(xot, node);
+    for (index, item) in items.into_iter().enumerate() {
+        if index > 0 {
+            insert_separator(xot, node, separator.as_deref())?;
+        }
+        let item_context = context.with_data(&item_name, item);
+        for child in &template {
+            let ch = xot.clone(*child);
+            xot.insert_before(node, ch)?;
+            expand_all_attr_strings(xot, ch, invocation, &item_context)?;
+            substitute_invocation(xot, ch, invocation, &item_context)?;
+        }
+    }
+    xot.detach(node)?;
+    Ok(())
+}
+
+fn evaluate_expression(xot: &Xot, expr: &str, invocation: xot::Node, context: &Context) -> String {
+    if let Some(value) = try_evaluate_expression(xot, expr, invocation, context) {
+        return value;
+    }
+
+    if let Some(attr_name) = expr.strip_prefix("self.") {
+        context.undefined(&format!("reference to missing attribute \"{}\"", attr_name));
+    } else {
+        context.undefined(&format!("unrecognized expression: \"{}\"", expr));
+    }
+    "".to_string()
+}
+
+// How deeply attribute values referring to other attributes are expanded
+const MAX_EXPANSION_DEPTH: usize = 16;
+
+// Evaluate an expression, or return None if it refers to something that
+// doesn't exist
+fn try_evaluate_expression(
+    xot: &Xot,
+    expr: &str,
+    invocation: xot::Node,
+    context: &Context,
+) -> Option<String> {
+    // bound expressions take precedence over everything else
+    if let Some(value) = context.bindings.get(expr) {
+        return Some(value.clone());
+    }
+
+    // 'x.[y]' evaluates the expression 'y' first and then uses its value in
+    // place of the brackets, e.g. 'self.[self.key]' evaluates to the attribute
+    // named by the 'key' attribute. Innermost brackets are resolved first.
+    if let Some(open) = expr.rfind('[') {
+        if let Some(len) = expr[open..].find(']') {
+            let inner = evaluate_expression(xot, &expr[open + 1..open + len], invocation, context);
+            let resolved = format!("{}{}{}", &expr[..open], inner, &expr[open + len + 1..]);
+            return try_evaluate_expression(xot, &resolved, invocation, context);
+        }
+    }
+
+    // 'self.filepath' evaluates to context's filepath
+    if expr == "self.filepath" {
+        return Some(context.file_path.to_string());
+    }
+
+    // 'self.pagedir' evaluates to the directory of the page's source, with a
+    // trailing '/', e.g. '/blog/' for '/blog/post.html'
+    if expr == "self.pagedir" {
+        let end = context.file_path.rfind('/').map_or(0, |i| i + 1);
+        return Some(context.file_path[..end].to_string());
+    }
+
+    // 'self.url' evaluates to the url the page is served at
+    if expr == "self.url" {
+        return Some(context.url.clone());
+    }
+
+    // 'build.env' evaluates to the name of the environment being built for
+    if expr == "build.env" {
+        return Some(context.build.options.env.clone().unwrap_or_default());
+    }
+
+    // 'build.version' evaluates to the version of the site being built, such
+    // as for busting caches, or to nothing if there is none
+    if expr == "build.version" {
+        return Some(context.build.options.version.clone().unwrap_or_default());
+    }
+
+    // 'build.date' evaluates to the day the build started, e.g. 2024-03-09
+    if expr == "build.date" {
+        return Some(context.build.date.format("%Y-%m-%d").to_string());
+    }
+
+    // "A||B" evaluates expression A and returns it if defined and non-empty,
+    // otherwise evaluates and returns expression B
+    // TODO: if more general context-free expressions are needed,
+    // implement a proper parser
+    if let Some(captures) = context.regex_or_expr.captures(expr) {
+        let a = &captures[1];
+        let b = &captures[2];
+        let a_val = try_evaluate_expression(xot, a, invocation, context).unwrap_or_default();
+        if !a_val.is_empty() {
+            return Some(a_val);
+        }
+        return Some(evaluate_expression(xot, b, invocation, context));
+    }
+
+    // 'self.xyz' evaluates to contents of 'xyz' attribute of invocation element
+    if let Some(attr_name) = expr.strip_prefix("self.") {
+        let attr_value = attribute_name(xot, attr_name, invocation)
+            .and_then(|id| xot.attributes(invocation).get(id))?;
+
+        // The value may itself refer to other attributes, e.g. with
+        // a="${self.b}", which are expanded in turn
+        if attr_value.contains("${") {
+            if context.expansion_depth >= MAX_EXPANSION_DEPTH {
+                context.undefined(&format!(
+                    "the expansion of \"{}\" is nested too deeply, which usually means that attributes refer to each other in a circle",
+                    expr
+                ));
+                return Some(String::new());
+            }
+            let mut deeper = context.clone();
+            deeper.expansion_depth += 1;
+            return Some(expand_string(xot, attr_value, invocation, &deeper));
+        }
+        return Some(attr_value.to_string());
+    }
+
+    // 'xyz.a.b' evaluates to 'a.b' inside the data file 'xyz' or the
+    // current item of an <each> element named 'xyz'
+    context.lookup_data(expr).map(data_to_string)
+}
+
+// Look up the name of an attribute as written on the given element. A name
+// such as 'og:title' is resolved using the namespace declarations in scope
+// at that element.
+fn attribute_name(xot: &Xot, name: &str, node: xot::Node) -> Option<xot::NameId> {
+    let Some((prefix, local_name)) = name.split_once(':') else {
+        return xot.name(name);
+    };
+    let prefix = xot.prefix(prefix)?;
+    let namespace = xot.namespace_for_prefix(node, prefix)?;
+    xot.name_ns(local_name, namespace)
+}
+
+// Evaluate an expression with a format suffix, as in ${build.date:%Y}. Only
+// dates can be formatted, using chrono's strftime-like syntax.
+fn evaluate_formatted(
+    xot: &Xot,
+    expr: &str,
+    format: &str,
+    invocation: xot::Node,
+    context: &Context,
+) -> String {
+    if expr != "build.date" {
+        context.warn(&format!(
+            "ignoring the format in ${{{}:{}}} because only build.date can be formatted",
+            expr, format
+        ));
+        return evaluate_expression(xot, expr, invocation, context);
+    }
+    // Formatting with an invalid format panics, so check it beforehand
+    let items: Vec<chrono::format::Item> = chrono::format::StrftimeItems::new(format).collect();
+    if items.contains(&chrono::format::Item::Error) {
+        context.undefined(&format!("invalid date format \"{}\"", format));
+        return String::new();
+    }
+    context
+        .build
+        .date
+        .format_with_items(items.into_iter())
+        .to_string()
+}
+
+// Stands in for the '$' of an escaped `$${`, so that what is left after
+// expanding isn't expanded again, such as when an attribute's value is used in
+// another attribute. `restore_escaped_dollars` turns it back into a '$'.
+// U+FFFF is a noncharacter that XML forbids, so no document can contain it.
+const ESCAPED_DOLLAR: char = '\u{FFFF}';
+
+fn expand_string(xot: &Xot, expr_string: &str, invocation: xot::Node, context: &Context) -> String {
+    context
+        .regex_dollar_expansion
+        .replace_all(expr_string, |captures: &Captures| -> String {
+            // `$${` is written out as `${`, along with whatever follows
+            let Some(expr) = captures.get(1) else {
+                return format!("{}{{", ESCAPED_DOLLAR);
+            };
+            if let Some(format) = captures.get(2) {
+                return evaluate_formatted(
+                    xot,
+                    expr.as_str(),
+                    format.as_str(),
+                    invocation,
+                    context,
+                );
+            }
+            let s = evaluate_expression(xot, expr.as_str(), invocation, context);
+            // println!("Expanding \"{}\" into \"{}\"", &captures[0], s);
+            s
+        })
+        .to_string()
+}
+
+fn expression_matches_pattern(
+    xot: &Xot,
+    expr_string: &str,
+    pattern_string: &str,
+    literal: bool,
+    invocation: xot::Node,
+    context: &Context,
+) -> bool {
+    // println!(
+    //     "Testing whether expression \"{}\" == \"{}\"",
+    //     expr_string, pattern_string
+    // );
+
+    // Expand any expressions
+    let expr_value = evaluate_expression(xot, expr_string, invocation, context);
+    let pattern_value = expand_string(xot, pattern_string, invocation, context);
+
+    // println!(" -> \"{}\" == \"{}\"", expr_value, pattern_value);
+
+    if literal {
+        return expr_value == pattern_value;
+    }
+
+    // Wrap pattern in '^' and '$' to force matching the entire string
+    let pattern = format!("^{}$", pattern_value);
+    match Regex::new(&pattern) {
+        Ok(re) => re.is_match(&expr_value),
+        Err(err) => {
+            // The regex crate's messages quote the pattern over several
+            // lines, ending with what's actually wrong
+            let err = err.to_string();
+            let reason = err.lines().last().unwrap_or_default();
+            context.warn(&format!(
+                "treating the condition {}=\"{}\" as false because \"{}\" is not a valid regular expression ({}); add match=\"exact\" to compare it as it is",
+                expr_string,
+                pattern_string,
+                pattern_value,
+                reason.trim_start_matches("error: ")
+            ));
+            false
+        }
+    }
+}
+
+// Evaluate the conditions given as attributes of an element like <if>, named
+// `tag` for warnings, returning whether they all match along with the
+// conditions themselves
+fn evaluate_conditions(
+    xot: &Xot,
+    node: xot::Node,
+    tag: &str,
+    invocation: xot::Node,
+    context: &Context,
+) -> (bool, Vec<(String, String)>) {
+    // Attributes of the form `expression="value-pattern"` are conditions,
+    // which all have to match. Expressions always contain a '.', so other
+    // attributes such as `id` or `note` are left alone.
+    let conditions: Vec<(String, String)> = xot
+        .attributes(node)
+        .iter()
+        .map(|(name, pattern)| (xot.name_ns_str(name).0.to_string(), pattern.clone()))
+        .filter(|(expr, _)| expr.contains('.'))
+        .collect();
+    if conditions.is_empty() {
+        context.warn(&format!(
+            "<{}> element without a condition like self.x=\"...\"",
+            tag
+        ));
+    }
+    // Values are regular expressions, unless match="exact" says to compare
+    // them as they are, so that e.g. "1.0" doesn't also match "100"
+    let literal = match xot
+        .name("match")
+        .and_then(|id| xot.attributes(node).get(id))
+    {
+        None => false,
+        Some(mode) if mode == "regex" => false,
+        Some(mode) if mode == "exact" => true,
+        Some(mode) => {
+            context.warn(&format!(
+                "unknown <{} match=\"{}\">, expected \"regex\" or \"exact\"",
+                tag, mode
+            ));
+            false
+        }
+    };
+    let condition = !conditions.is_empty()
+        && conditions.iter().all(|(expr, pattern)| {
+            expression_matches_pattern(xot, expr, pattern, literal, invocation, context)
+        });
+    (condition, conditions)
+}
+
+/// Replace an `<if>` element with the contents of its `<then>` or `<else>`
+/// child, depending on whether its condition matches
+pub fn substitute_if(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let (condition, conditions) = evaluate_conditions(xot, node, "if", invocation, context);
+
+    // look for a 'then' child node
+    let node_then = xot
+        .name("then")
+        .map(|id| {
+            for child in xot.children(node) {
+                if xot.node_name(child) == Some(id) {
+                    return Some(child);
+                }
+            }
+            None
+        })
+        .flatten();
+
+    // look for an 'else' child node
+    let node_else = xot
+        .name("else")
+        .map(|id| {
+            for child in xot.children(node) {
+                if xot.node_name(child) == Some(id) {
+                    return Some(child);
+                }
+            }
+            None
+        })
+        .flatten();
+
+    // For simple cases, the branches can also be given as text in 'then' and
+    // 'else' attributes, as in <if self.x="y" then="A" else="B" />
+    let branch_attr = |name: &str| {
+        xot.name(name)
+            .and_then(|id| xot.attributes(node).get(id))
+            .cloned()
+    };
+    let text_then = branch_attr("then");
+    let text_else = branch_attr("else");
+
+    // This is most likely a misspelled <then>, which would otherwise go
+    // unnoticed as the <if> silently disappears
+    if node_then.is_none() && node_else.is_none() && text_then.is_none() && text_else.is_none() {
+        let conditions: Vec<String> = conditions
+            .iter()
+            .map(|(expr, pattern)| format!(" {}=\"{}\"", expr, pattern))
+            .collect();
+        context.undefined(&format!(
+            "<if{}> has neither a <then> nor an <else> element",
+            conditions.concat()
+        ));
+    }
+
+    // if match, replace with contents of 'then', otherwise with contents
+    // of 'else', preferring elements over attributes
+    let (branch_node, branch_text) = if condition {
+        (node_then, text_then)
+    } else {
+        (node_else, text_else)
+    };
+    if let Some(branch_node) = branch_node {
+        let children: Vec<xot::Node> = xot.children(branch_node).collect();
+        for ch in children {
+            let ch = xot.clone(ch);
+            xot.insert_before(node, ch)?;
+        }
+    } else if let Some(text) = branch_text.filter(|text| !text.is_empty()) {
+        let text = xot.new_text(&text);
+        xot.insert_before(node, text)?;
+    }
+    xot.remove(node)
+}
+
+/// Remove an `<addclass name="xyz">` element, adding 'xyz' to the classes of
+/// the element containing it if its conditions match, which are written
+/// like those of `<if>`
+pub fn substitute_addclass(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let (condition, _) = evaluate_conditions(xot, node, "addclass", invocation, context);
+    let name = xot
+        .name("name")
+        .and_then(|id| xot.attributes(node).get(id))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default();
+    if name.is_empty() {
+        context.warn("<addclass> element without a 'name' attribute");
+    }
+    let parent = xot.parent(node).filter(|parent| xot.is_element(*parent));
+    if parent.is_none() {
+        context.warn("<addclass> must be inside of the element to add the class to");
+    }
+
+    if let Some(parent) = parent.filter(|_| condition && !name.is_empty()) {
+        let class = xot.add_name("class");
+        let classes = match xot.attributes(parent).get(class) {
+            Some(classes) if !classes.trim().is_empty() => format!("{} {}", classes.trim(), name),
+            _ => name,
+        };
+        xot.attributes_mut(parent).insert(class, classes);
+    }
+    xot.remove(node)
+}
+
+/// Replace a `<self.xyz>` element with the invocation's 'xyz' attribute as
+/// text, or with the invocation's children in the case of `<self.inner>`
+pub fn substitute_attr(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let attr_name = xot
+        .name_ns_str(xot.node_name(node).unwrap())
+        .0
+        .strip_prefix("self.")
+        .unwrap();
+
+    if attr_name == "inner" {
+        // With <self.inner preserve-space="">, whitespace at either end of
+        // the children is kept as a single space rather than minified away,
+        // such as for text passed into an inline element
+        let preserve_space = xot
+            .name("preserve-space")
+            .is_some_and(|id| xot.attributes(node).get(id).is_some());
+
+        // replace tags <self.inner> with the node's children, other than
+        // the markup it passes as <prop>s
+        let children: Vec<xot::Node> = xot
+            .children(invocation)
+            .filter(|child| !is_prop(xot, *child))
+            .collect();
+        let last = children.len().saturating_sub(1);
+        for (index, ch) in children.into_iter().enumerate() {
+            let r = xot.clone(ch);
+            let mut document = context.document.borrow_mut();
+            document.keep_invocation_scope(xot, invocation, r);
+            document.copied_nodes.insert(r);
+            if preserve_space && (index == 0 || index == last) && xot.text(r).is_some() {
+                document.spaced_nodes.insert(r);
+            }
+            drop(document);
+            xot.insert_before(node, r)?;
+        }
+        xot.remove(node)?;
+
+        return Ok(());
+    }
+
+    if let Some(value) = context.bindings.get(&format!("self.{}", attr_name)) {
+        if !value.is_empty() {
+            let r = xot.new_text(value);
+            xot.insert_before(node, r)?;
+        }
+        xot.detach(node)?;
+        return Ok(());
+    }
+
+    // Markup passed as <prop name="icon">...</prop> takes the place of
+    // <self.icon />
+    if let Some(prop) = find_prop(xot, invocation, attr_name) {
+        let children: Vec<xot::Node> = xot.children(prop).collect();
+        for ch in children {
+            let r = xot.clone(ch);
+            let mut document = context.document.borrow_mut();
+            document.keep_invocation_scope(xot, invocation, r);
+            document.copied_nodes.insert(r);
+            drop(document);
+            xot.insert_before(node, r)?;
+        }
+        xot.remove(node)?;
+        return Ok(());
+    }
+
+    let attr_val = xot
+        .name(attr_name)
+        .and_then(|attr_id| xot.attributes(invocation).get(attr_id))
+        .cloned();
+    let Some(attr_val) = attr_val else {
+        context.undefined(&format!(
+            "reference to missing attribute \"{}\" in <self.{}>",
+            attr_name, attr_name
+        ));
+        return Ok(());
+    };
+    // Values referring to other attributes are expanded like in expressions
+    let attr_val = if attr_val.contains("${") {
+        evaluate_expression(xot, &format!("self.{}", attr_name), invocation, context)
+    } else {
+        attr_val
+    };
+
+    // replace tags <self.xyz> with attribute value xyz if defined
+    if !attr_val.is_empty() {
+        let r = xot.new_text(&attr_val);
+        xot.insert_before(node, r)?;
+    }
+    // xot.remove(node)?;
+    xot.detach(node)?;
+
+    Ok(())
+}
+
+// Recursively visit all string attributes of all descendants of a node
+// and expand expressions
+fn expand_all_attr_strings(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    // Comments marked with a '!', as in <!--! built ${build.date} -->, have
+    // their expressions expanded too. The marker stays until the page is
+    // written, for `expand_marked_comments` to find.
+    if let Some(comment) = xot.comment(node) {
+        let text = comment.get();
+        if text.starts_with('!') && text.contains("${") {
+            let expanded = expand_string(xot, text, invocation, context);
+            xot.comment_mut(node).unwrap().set(expanded);
+        }
+        return Ok(());
+    }
+
+    // Visit all attributes
+    let elem_name = xot
+        .element(node)
+        .map(|elem| xot.name_ns_str(elem.name()).0.to_string());
+    // The contents of <raw> are left exactly as they are
+    if elem_name.as_deref() == Some("raw") {
+        return Ok(());
+    }
+    {
+        let keys: Vec<xot::NameId> = xot.attributes(node).keys().collect();
+        for key in keys {
+            let Some(value) = xot.attributes(node).get(key) else {
+                continue;
+            };
+            if !value.contains("${") {
+                continue;
+            }
+            let new_value = expand_string(xot, value, invocation, context);
+            *xot.attributes_mut(node).get_mut(key).unwrap() = new_value;
+        }
+    }
+
+    // The contents of loops are expanded separately for every iteration
+    if elem_name.is_some_and(|name| {
+        name.starts_with("foreachvalue.")
+            || name.starts_with("foreachchild.")
+            || name == "foreachattr"
+            || name == "each"
+    }) {
+        return Ok(());
+    }
+
+    let children: Vec<xot::Node> = xot.children(node).collect();
+    for child in children {
+        expand_all_attr_strings(xot, child, invocation, context)?;
+    }
+
+    Ok(())
+}
+
+// Recursively look for elements carrying a `self.rest` attribute and replace
+// it with every attribute of the invocation that the definition doesn't
+// already refer to by name. Attributes written out explicitly on the element
+// take precedence over forwarded ones, except for those that
+// `Options::merge_attributes` says to combine.
+fn spread_remaining_attrs(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    marker: xot::NameId,
+    referenced_attrs: &HashSet<String>,
+    options: &Options,
+) -> Result<(), xot::Error> {
+    if xot.attributes(node).get(marker).is_some() {
+        xot.attributes_mut(node).remove(marker);
+        let remaining: Vec<(xot::NameId, String)> = xot
+            .attributes(invocation)
+            .iter()
+            .filter(|(key, _)| !referenced_attrs.contains(xot.name_ns_str(*key).0))
+            .map(|(key, value)| (key, value.clone()))
+            .collect();
+        for (key, value) in remaining {
+            // Attributes like class are combined instead
+            let name = xot.name_ns_str(key).0;
+            let value = match xot.attributes(node).get(key) {
+                None => value,
+                Some(own) if options.merges_attribute(name) => {
+                    merge_attribute_values(name, own, &value)
+                }
+                Some(_) => continue,
+            };
+            xot.attributes_mut(node).insert(key, value);
+        }
+    }
+
+    let children: Vec<xot::Node> = xot.children(node).collect();
+    for child in children {
+        spread_remaining_attrs(xot, child, invocation, marker, referenced_attrs, options)?;
+    }
+
+    Ok(())
+}
+
+// Combine two values of an attribute that is merged rather than replaced,
+// such as two sets of classes. Styles are separated by a ';', anything else
+// by a space.
+fn merge_attribute_values(name: &str, first: &str, second: &str) -> String {
+    let (first, second) = (first.trim(), second.trim());
+    if first.is_empty() || second.is_empty() {
+        return format!("{}{}", first, second);
+    }
+    if name == "style" {
+        format!("{}; {}", first.trim_end_matches(';'), second)
+    } else {
+        format!("{} {}", first, second)
+    }
+}
+
+// Whether an element name inside a definition refers to one of the
+// directives handled by `substitute_invocation`
+fn is_directive_name(name: &str) -> bool {
+    // besides <if>, <each>, <foreachattr>, and <addclass>, directives all have a '.' in
+    // their name, like <self.xyz>, <foreachchild.x>, or data references like
+    // <item.label>
+    name == "if"
+        || name == "each"
+        || name == "foreachattr"
+        || name == "addclass"
+        || name.contains('.')
+}
+
+// Process a node, recursively substituting and applying rules, and inserting
+// any resulting nodes in its place
+fn substitute_invocation(
+    xot: &mut Xot,
+    node: xot::Node,
+    invocation: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    debug_assert!(!xot.is_removed(node));
+    // comments and text get passed through unmodified
+    let elem_name: String = if let xot::Value::Element(elem) = xot.value(node) {
+        xot.name_ns_str(elem.name()).0.to_string()
+    } else {
+        return Ok(());
+    };
+
+    if elem_name == "raw" {
+        return Ok(());
+    }
+
+    // substitute loops before their contents, since these depend on
+    // the value of each iteration
+    if elem_name.starts_with("foreachvalue.") {
+        return substitute_foreachvalue(xot, node, invocation, context);
+    }
+    if elem_name.starts_with("foreachchild.") {
+        return substitute_foreach(xot, node, invocation, context);
+    }
+    if elem_name == "each" {
+        return substitute_each(xot, node, invocation, context);
+    }
+    if elem_name == "foreachattr" {
+        return substitute_foreachattr(xot, node, invocation, context);
+    }
+
+    // substitute innermost elements
+    {
+        let children: Vec<xot::Node> = xot.children(node).collect();
+        for child in children {
+            substitute_invocation(xot, child, invocation, context)?;
+        }
+    }
+
+    // substitute <if> tags
+    if elem_name == "if" {
+        return substitute_if(xot, node, invocation, context);
+    }
+
+    // <addclass> changes the element around it, which is still there
+    if elem_name == "addclass" {
+        return substitute_addclass(xot, node, invocation, context);
+    }
+
+    // Look for tags of the form <self.xyz>
+    if elem_name.starts_with("self.") {
+        return substitute_attr(xot, node, invocation, context);
+    }
+
+    // Look for references to data such as <item.label> or bound values such
+    // as <x.id> inside <foreachchild.x>. No html element has a '.' in its
+    // name, so these can't be confused with anything else.
+    if elem_name.contains('.') {
+        let value = match context.bindings.get(&elem_name) {
+            Some(value) => Some(value.clone()),
+            None => context.lookup_data(&elem_name).map(data_to_string),
+        };
+        if let Some(value) = value {
+            let text = xot.new_text(&value);
+            return xot.replace(node, text);
+        }
+    }
+
+    Ok(())
+}
+
+/// A single element loaded from an html file in the elements directory
+pub struct ElementDefinition {
+    name: String,
+    path: path::PathBuf,
+    tag_name: xot::NameId,
+    // the unwrapped contents of the definition, cloned for every instantiation
+    node: xot::Node,
+    // names of all invocation attributes that the definition refers to
+    // explicitly, i.e. that are not forwarded by `self.rest`
+    referenced_attrs: HashSet<String>,
+    // Which passes of `instantiate` have anything to do at all. These are
+    // worked out once when loading so that heavily used definitions without
+    // expressions or directives only cost a clone per instantiation.
+    has_expressions: bool,
+    has_spread: bool,
+    has_directives: bool,
+    // whether the definition refers to attributes whose names are only known
+    // when it is used, as in ${self.[self.key]} or inside of <foreachattr>
+    has_dynamic_refs: bool,
+    // whether the definition is a partial, which takes no attributes or
+    // content whatsoever and so comes out the same wherever it is used
+    partial: bool,
+    // whether the element is meant to be used inline, as set with
+    // <config trim="inline" />
+    inline: bool,
+    // other tag names the element can be used by, as set with
+    // <config alias="..." />
+    aliases: Vec<(String, xot::NameId)>,
+    // css from a top-level <style scoped=""> element, which is written to
+    // the build's component stylesheet once rather than with every use
+    style: Option<String>,
+    // attributes that every use of the element must have, as declared with
+    // <requires attrs="..." />
+    required_attrs: Vec<String>,
+    // restrictions on the values of attributes, as declared with
+    // <attr name="..." ... />
+    attr_checks: Vec<AttrCheck>,
+    // the only elements that the definition's own markup may use, as
+    // declared with <uses names="..." />, or None if it may use any
+    uses: Option<ElementScope>,
+    // conditions that a use of the element must meet for it to produce
+    // anything at all, as declared with <renderif self.x="..." />. These are
+    // kept apart from the definition's markup.
+    render_if: Vec<xot::Node>,
+    // values that the definition's expressions can refer to by name, as
+    // declared with <let name="..." value="..." />, in order
+    locals: Vec<(String, String)>,
+}
+
+// Names of the elements that are expanded within some markup, by the name
+// of their definition or any of their aliases
+type ElementScope = Arc<HashSet<String>>;
+
+// The values that an attribute of an element may have
+struct AttrCheck {
+    name: String,
+    kind: AttrType,
+    allowed: Option<Vec<String>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AttrType {
+    Text,
+    Number,
+    Integer,
+    Boolean,
+}
+
+impl AttrCheck {
+    // Read an <attr name="..." type="..." enum="..." /> element
+    fn parse(xot: &Xot, node: xot::Node) -> Result<AttrCheck, String> {
+        let mut name = None;
+        let mut kind = AttrType::Text;
+        let mut allowed = None;
+        for (key, value) in xot.attributes(node).iter() {
+            match xot.name_ns_str(key).0 {
+                "name" => name = Some(value.clone()),
+                "type" => {
+                    kind = match value.as_str() {
+                        "text" => AttrType::Text,
+                        "number" => AttrType::Number,
+                        "integer" => AttrType::Integer,
+                        "boolean" => AttrType::Boolean,
+                        _ => {
+                            return Err(format!(
+                                "unknown type \"{}\" in <attr>, expected \"text\", \"number\", \"integer\", or \"boolean\"",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "enum" => {
+                    allowed = Some(value.split(',').map(|v| v.trim().to_string()).collect())
+                }
+                other => return Err(format!("unknown setting {}=\"{}\" in <attr>", other, value)),
+            }
+        }
+        let Some(name) = name else {
+            return Err("<attr> is missing its \"name\" attribute".to_string());
+        };
+        Ok(AttrCheck {
+            name,
+            kind,
+            allowed,
+        })
+    }
+
+    // Describe what is wrong with the value, if anything
+    fn problem(&self, value: &str) -> Option<String> {
+        if let Some(allowed) = &self.allowed {
+            if !allowed.iter().any(|a| a == value) {
+                return Some(format!("one of \"{}\"", allowed.join("\", \"")));
+            }
+        }
+        let valid = match self.kind {
+            AttrType::Text => true,
+            AttrType::Number => value.trim().parse::<f64>().is_ok_and(|n| n.is_finite()),
+            AttrType::Integer => value.trim().parse::<i64>().is_ok(),
+            AttrType::Boolean => value == "true" || value == "false",
+        };
+        if valid {
+            return None;
+        }
+        Some(
+            match self.kind {
+                AttrType::Text => "text",
+                AttrType::Number => "a number",
+                AttrType::Integer => "an integer",
+                AttrType::Boolean => "\"true\" or \"false\"",
+            }
+            .to_string(),
+        )
+    }
+}
+
+// Collect the names of all attributes referred to via `self.xyz` or
+// `foreachvalue.xyz`, whether in element names, attribute names, or
+// expressions inside attribute values
+fn collect_referenced_attrs(xot: &Xot, node: xot::Node, referenced_attrs: &mut HashSet<String>) {
+    let regex_self_reference = Regex::new(r"(?:self|foreachvalue)\.([a-zA-Z0-9_\-:]+)").unwrap();
+    for descendant in xot.descendants(node) {
+        let Some(element) = xot.element(descendant) else {
+            continue;
+        };
+        let mut haystacks = vec![xot.name_ns_str(element.name()).0.to_string()];
+        for (key, value) in xot.attributes(descendant).iter() {
+            haystacks.push(xot.name_ns_str(key).0.to_string());
+            haystacks.push(value.clone());
+        }
+        for haystack in haystacks {
+            for captures in regex_self_reference.captures_iter(&haystack) {
+                referenced_attrs.insert(captures[1].to_string());
+            }
+        }
+    }
+}
+
+// Wrap the document root in a throwaway node because document roots
+// currently cannot be moved, which also allows for any number of top-level
+// nodes, including comments and text before the first element. An XML
+// declaration or doctype at the start, which can't be inside of an element,
+// is left out, while other processing instructions, such as
+// <?xml-stylesheet ...?>, are kept.
+// See https://github.com/faassen/xot/issues/22
+fn wrap_in_throwaway(source_text: &str) -> String {
+    let mut leading = "";
+    let mut rest = source_text;
+    loop {
+        let trimmed = rest.trim_start();
+        let declaration = trimmed
+            .strip_prefix("<?xml")
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_whitespace()));
+        let end = if declaration {
+            trimmed.find("?>").map(|end| end + 2)
+        } else if trimmed
+            .get(..9)
+            .is_some_and(|start| start.eq_ignore_ascii_case("<!doctype"))
+        {
+            trimmed.find('>').map(|end| end + 1)
+        } else {
+            None
+        };
+        let Some(end) = end else {
+            break;
+        };
+        leading = &rest[..rest.len() - trimmed.len()];
+        rest = &trimmed[end..];
+    }
+    format!("<throwaway>{}{}</throwaway>", leading, rest)
+}
+
+// The <throwaway> element of a document parsed from `wrap_in_throwaway`
+fn unwrap_throwaway(xot: &Xot, document: xot::Node) -> xot::Node {
+    xot.children(document)
+        .find(|child| xot.is_element(*child))
+        .unwrap()
+}
+
+impl ElementDefinition {
+    /// Load an element definition, named after the file
+    pub fn from_file(
+        xot: &mut Xot,
+        path: &std::path::Path,
+    ) -> Result<ElementDefinition, BuildError> {
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let source_text = read_source(path)?;
+        ElementDefinition::parse(xot, name, path, source_text)
+    }
+
+    /// Create an element definition from html held in memory
+    pub fn from_source(
+        xot: &mut Xot,
+        name: &str,
+        source_text: &str,
+    ) -> Result<ElementDefinition, BuildError> {
+        let path = path::PathBuf::from(format!("{}.html", name));
+        ElementDefinition::parse(xot, name.to_string(), &path, source_text.to_string())
+    }
+
+    /// Load every `<component name="...">` element in a single file, such as
+    /// a library of elements distributed as one, as an element definition
+    /// named by its `name` attribute
+    pub fn from_bundle(
+        xot: &mut Xot,
+        path: &path::Path,
+    ) -> Result<Vec<ElementDefinition>, BuildError> {
+        let parse_error = |message: String| BuildError::Parse {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        // A bundle holds any number of top-level <component> elements, which
+        // a document can't, so it is wrapped just like a definition
+        let source_text = wrap_in_throwaway(&read_source(path)?);
+        let document = xot
+            .parse(&source_text)
+            .map_err(|err| parse_error(err.to_string()))?;
+        let node = unwrap_throwaway(xot, document);
+
+        let component_name = xot.add_name("component");
+        let name_name = xot.add_name("name");
+        let mut definitions = Vec::new();
+        let children: Vec<xot::Node> = xot.children(node).collect();
+        for child in children {
+            let is_padding = xot.is_comment(child)
+                || xot
+                    .text(child)
+                    .is_some_and(|text| text.get().chars().all(is_collapsible_whitespace));
+            if is_padding {
+                continue;
+            }
+            if xot.node_name(child) != Some(component_name) {
+                return Err(parse_error(
+                    "a bundle may only contain <component name=\"...\"> elements".to_string(),
+                ));
+            }
+            let Some(name) = xot.attributes(child).get(name_name).cloned() else {
+                return Err(parse_error(
+                    "<component> is missing its \"name\" attribute".to_string(),
+                ));
+            };
+            let mut source_text = String::new();
+            let contents: Vec<xot::Node> = xot.children(child).collect();
+            for content in contents {
+                source_text += &xot.to_string(content)?;
+            }
+            definitions.push(ElementDefinition::parse(xot, name, path, source_text)?);
+        }
+        xot.remove(document)?;
+        Ok(definitions)
+    }
+
+    fn parse(
+        xot: &mut Xot,
+        name: String,
+        path: &path::Path,
+        source_text: String,
+    ) -> Result<ElementDefinition, BuildError> {
+        let source_text = wrap_in_throwaway(&source_text);
+
+        let document = xot.parse(&source_text).map_err(|err| BuildError::Parse {
+            path: path.to_path_buf(),
+            message: err.to_string(),
+        })?;
+
+        let node = unwrap_throwaway(xot, document);
+
+        // Top-level <config> elements hold settings for the element itself
+        // rather than content
+        let mut inline = false;
+        let mut aliases = Vec::new();
+        let config_name = xot.add_name("config");
+        let configs: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| xot.node_name(*child) == Some(config_name))
+            .collect();
+        for config in configs {
+            let settings: Vec<(String, String)> = xot
+                .attributes(config)
+                .iter()
+                .map(|(key, value)| (xot.name_ns_str(key).0.to_string(), value.clone()))
+                .collect();
+            for (key, value) in settings {
+                match (key.as_str(), value.as_str()) {
+                    ("trim", "inline") => inline = true,
+                    ("trim", "none") => inline = false,
+                    ("alias", names) => {
+                        for alias in names.split_whitespace() {
+                            aliases.push((alias.to_string(), xot.add_name(alias)));
+                        }
+                    }
+                    _ => {
+                        return Err(BuildError::Parse {
+                            path: path.to_path_buf(),
+                            message: format!("unknown setting {}=\"{}\" in <config>", key, value),
+                        })
+                    }
+                }
+            }
+            xot.remove(config)?;
+        }
+
+        // Top-level <requires attrs="a,b" /> elements list attributes that
+        // every use of the element must have
+        let mut required_attrs = Vec::new();
+        let requires_name = xot.add_name("requires");
+        let attrs_name = xot.add_name("attrs");
+        let requires: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| xot.node_name(*child) == Some(requires_name))
+            .collect();
+        for requirement in requires {
+            let Some(attrs) = xot.attributes(requirement).get(attrs_name) else {
+                return Err(BuildError::Parse {
+                    path: path.to_path_buf(),
+                    message: "<requires> is missing its \"attrs\" attribute".to_string(),
+                });
+            };
+            required_attrs.extend(
+                attrs
+                    .split(',')
+                    .map(|attr| attr.trim().to_string())
+                    .filter(|attr| !attr.is_empty()),
+            );
+            xot.remove(requirement)?;
+        }
+
+        // Top-level <attr name="x" type="number" /> or
+        // <attr name="x" enum="a,b" /> elements restrict the values that
+        // an attribute may have, if it is given at all
+        let mut attr_checks = Vec::new();
+        let attr_name = xot.add_name("attr");
+        let attr_nodes: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| xot.node_name(*child) == Some(attr_name))
+            .collect();
+        for attr_node in attr_nodes {
+            let check = AttrCheck::parse(xot, attr_node).map_err(|message| BuildError::Parse {
+                path: path.to_path_buf(),
+                message,
+            })?;
+            attr_checks.push(check);
+            xot.remove(attr_node)?;
+        }
+
+        // Top-level <uses names="a,b" /> elements limit the elements that
+        // the definition's markup can use to those listed and itself, so that
+        // other tags that happen to share a name with an element are left as
+        // they are
+        let mut uses: Option<HashSet<String>> = None;
+        let uses_name = xot.add_name("uses");
+        let names_name = xot.add_name("names");
+        let uses_nodes: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| xot.node_name(*child) == Some(uses_name))
+            .collect();
+        for uses_node in uses_nodes {
+            let Some(names) = xot.attributes(uses_node).get(names_name) else {
+                return Err(BuildError::Parse {
+                    path: path.to_path_buf(),
+                    message: "<uses> is missing its \"names\" attribute".to_string(),
+                });
+            };
+            uses.get_or_insert_with(HashSet::new).extend(
+                names
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string),
+            );
+            xot.remove(uses_node)?;
+        }
+        if let Some(uses) = &mut uses {
+            uses.insert(name.clone());
+            uses.extend(aliases.iter().map(|(alias, _)| alias.clone()));
+        }
+
+        // Top-level <renderif self.show="true" /> elements hold conditions
+        // like those of <if>, without which the element produces nothing
+        let renderif_name = xot.add_name("renderif");
+        let render_if: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| xot.node_name(*child) == Some(renderif_name))
+            .collect();
+        for condition in &render_if {
+            xot.detach(*condition)?;
+        }
+
+        // Top-level <let name="base" value="card ${self.variant}" /> elements
+        // define values that the rest of the definition can use as ${base}
+        let let_name = xot.add_name("let");
+        let local_name = xot.add_name("name");
+        let value_name = xot.add_name("value");
+        let let_nodes: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| xot.node_name(*child) == Some(let_name))
+            .collect();
+        let mut locals = Vec::new();
+        for let_node in &let_nodes {
+            let attributes = xot.attributes(*let_node);
+            let (Some(local), Some(value)) =
+                (attributes.get(local_name), attributes.get(value_name))
+            else {
+                return Err(BuildError::Parse {
+                    path: path.to_path_buf(),
+                    message: "<let> needs both a \"name\" and a \"value\" attribute".to_string(),
+                });
+            };
+            // Names with a '.' could be mistaken for attributes or data
+            if local.is_empty() || local.contains('.') {
+                return Err(BuildError::Parse {
+                    path: path.to_path_buf(),
+                    message: format!("<let name=\"{}\"> needs a name without a '.'", local),
+                });
+            }
+            locals.push((local.clone(), value.clone()));
+            xot.detach(*let_node)?;
+        }
+
+        let mut style: Option<String> = None;
+        let style_name = xot.add_name("style");
+        let scoped_name = xot.add_name("scoped");
+        let styles: Vec<xot::Node> = xot
+            .children(node)
+            .filter(|child| {
+                xot.node_name(*child) == Some(style_name)
+                    && xot.attributes(*child).get(scoped_name).is_some()
+            })
+            .collect();
+        for style_node in styles {
+            let css: String = xot
+                .children(style_node)
+                .filter_map(|child| xot.text(child).map(|t| t.get().to_string()))
+                .collect();
+            style = Some(match style {
+                Some(previous) => previous + "\n" + &css,
+                None => css,
+            });
+            xot.remove(style_node)?;
+        }
+
+        let mut referenced_attrs = HashSet::new();
+        collect_referenced_attrs(xot, node, &mut referenced_attrs);
+        for condition in &render_if {
+            collect_referenced_attrs(xot, *condition, &mut referenced_attrs);
+        }
+        for let_node in let_nodes {
+            collect_referenced_attrs(xot, let_node, &mut referenced_attrs);
+            xot.remove(let_node)?;
+        }
+
+        let mut has_expressions = false;
+        let mut has_spread = false;
+        let mut has_directives = false;
+        let mut has_dynamic_refs = false;
+        for descendant in xot.descendants(node) {
+            if let Some(comment) = xot.comment(descendant) {
+                has_expressions |= comment.get().starts_with('!') && comment.get().contains("${");
+            }
+            let Some(element) = xot.element(descendant) else {
+                continue;
+            };
+            has_directives |= is_directive_name(xot.name_ns_str(element.name()).0);
+            has_dynamic_refs |= xot.name_ns_str(element.name()).0 == "foreachattr";
+            for (key, value) in xot.attributes(descendant).iter() {
+                has_expressions |= value.contains("${");
+                has_spread |= xot.name_ns_str(key).0 == "self.rest";
+                has_dynamic_refs |= value.contains("self.[");
+            }
+        }
+
+        let partial = !has_expressions
+            && !has_spread
+            && !has_directives
+            && required_attrs.is_empty()
+            && attr_checks.is_empty()
+            && render_if.is_empty()
+            && locals.is_empty();
+        has_dynamic_refs |= locals.iter().any(|(_, value)| value.contains("self.["));
+
+        Ok(ElementDefinition {
+            tag_name: xot.add_name(&name),
+            name,
+            path: path.to_path_buf(),
+            node,
+            referenced_attrs,
+            has_expressions,
+            has_spread,
+            has_directives,
+            has_dynamic_refs,
+            partial,
+            inline,
+            aliases,
+            style,
+            required_attrs,
+            attr_checks,
+            uses: uses.map(Arc::new),
+            render_if,
+            locals,
+        })
+    }
+
+    // Report any required attributes that are missing from the invocation
+    // and any attributes whose values aren't allowed, failing the build, as
+    // well as any attributes that the definition never uses
+    fn check_invocation(&self, xot: &Xot, invocation: xot::Node, context: &Context) {
+        let value_of = |attr: &str| {
+            attribute_name(xot, attr, invocation)
+                .and_then(|name| xot.attributes(invocation).get(name).cloned())
+        };
+        let mut problems = Vec::new();
+        for attr in &self.required_attrs {
+            if value_of(attr).is_none() && find_prop(xot, invocation, attr).is_none() {
+                problems.push(format!("is missing the required attribute \"{}\"", attr));
+            }
+        }
+        for check in &self.attr_checks {
+            let Some(value) = value_of(&check.name) else {
+                continue;
+            };
+            if let Some(expected) = check.problem(&value) {
+                problems.push(format!(
+                    "has {}=\"{}\", but it must be {}",
+                    check.name, value, expected
+                ));
+            }
+        }
+        for problem in problems {
+            context.error(&format!(
+                "<{}> {} (see {})",
+                self.name,
+                problem,
+                self.path.display()
+            ));
+        }
+
+        // Attributes that the definition doesn't use anywhere are most likely
+        // misspelled, unless they might be forwarded or looked up by a name
+        // that is only known now. Namespaced attributes are left alone.
+        if self.has_spread || self.has_dynamic_refs {
+            return;
+        }
+        for (key, _) in xot.attributes(invocation).iter() {
+            let (name, namespace) = xot.name_ns_str(key);
+            if !namespace.is_empty()
+                || self.referenced_attrs.contains(name)
+                || self.required_attrs.iter().any(|attr| attr == name)
+                || self.attr_checks.iter().any(|check| check.name == name)
+            {
+                continue;
+            }
+            context.undefined(&format!(
+                "<{}> was given the attribute \"{}\", which it doesn't use (see {})",
+                self.name,
+                name,
+                self.path.display()
+            ));
+        }
+    }
+
+    /// The tag name the element is invoked by
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file the element was loaded from
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// Whether the element takes no attributes or content at all, such as a
+    /// footer, so that it can also be used as `<partial name="..." />`
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// Other tag names the element can be used by
+    pub fn aliases(&self) -> impl Iterator<Item = &str> {
+        self.aliases.iter().map(|(alias, _)| alias.as_str())
+    }
+
+    fn instantiate(
+        &self,
+        xot: &mut Xot,
+        invocation: xot::Node,
+        context: &Context,
+    ) -> Result<Vec<xot::Node>, xot::Error> {
+        self.check_invocation(xot, invocation, context);
+
+        for condition in &self.render_if {
+            let (matches, _) =
+                evaluate_conditions(xot, *condition, "renderif", invocation, context);
+            if !matches {
+                return Ok(Vec::new());
+            }
+        }
+
+        // Values declared with <let> are bound for the rest of the
+        // definition, each one able to use those before it
+        let local_context;
+        let context = if self.locals.is_empty() {
+            context
+        } else {
+            let mut local = context.clone();
+            for (name, value) in &self.locals {
+                let value = expand_string(xot, value, invocation, &local);
+                local = local.with_binding(name, value);
+            }
+            local_context = local;
+            &local_context
+        };
+
+        let node = xot.clone(self.node);
+
+        // Partials come out the same wherever they're used, so that there's
+        // nothing to do but to copy them
+        if self.partial && !self.inline {
+            return Ok(xot.children(node).collect());
+        }
+
+        if self.has_expressions {
+            expand_all_attr_strings(xot, node, invocation, context)?;
+        }
+        if self.has_spread {
+            let marker = xot.add_name("self.rest");
+            spread_remaining_attrs(
+                xot,
+                node,
+                invocation,
+                marker,
+                &self.referenced_attrs,
+                context.options,
+            )?;
+        }
+        if self.has_directives {
+            substitute_invocation(xot, node, invocation, context)?;
+        }
+
+        let mut children: Vec<xot::Node> = xot.children(node).collect();
+        if self.inline {
+            // Leave out whitespace and comments around the content, so that
+            // the element doesn't introduce any whitespace of its own
+            let is_padding = |n: &xot::Node| {
+                xot.is_comment(*n)
+                    || xot
+                        .text(*n)
+                        .is_some_and(|text| text.get().chars().all(is_collapsible_whitespace))
+            };
+            let start = children
+                .iter()
+                .position(|n| !is_padding(n))
+                .unwrap_or(children.len());
+            let end = children
+                .iter()
+                .rposition(|n| !is_padding(n))
+                .map_or(start, |i| i + 1);
+            children = children[start..end].to_vec();
+        }
+        Ok(children)
+    }
+}
+
+/// All elements available for expansion, keyed by tag name
+#[derive(Default)]
+pub struct ElementLibrary {
+    elements: HashMap<xot::NameId, ElementDefinition>,
+    // other names for elements, mapped to their actual tag name
+    aliases: HashMap<xot::NameId, xot::NameId>,
+}
+
+impl ElementLibrary {
+    /// Load every html file in a directory as an element definition
+    pub fn from_folder(
+        xot: &mut Xot,
+        path: &std::path::Path,
+    ) -> Result<ElementLibrary, BuildError> {
+        ElementLibrary::from_folders(xot, &[path.to_path_buf()], ElementOverrides::Error)
+    }
+
+    /// Load all elements of a bundle, a single file holding them as
+    /// `<component name="...">` elements
+    pub fn from_bundle(
+        xot: &mut Xot,
+        path: &std::path::Path,
+    ) -> Result<ElementLibrary, BuildError> {
+        let mut library = ElementLibrary::new();
+        for element_defn in ElementDefinition::from_bundle(xot, path)? {
+            library.insert(element_defn)?;
+        }
+        Ok(library)
+    }
+
+    /// Load every html file in several directories as element definitions,
+    /// in order, with `overrides` deciding what happens when a later
+    /// directory defines an element that an earlier one already did. Paths
+    /// of files rather than directories are loaded as bundles.
+    pub fn from_folders(
+        xot: &mut Xot,
+        paths: &[path::PathBuf],
+        overrides: ElementOverrides,
+    ) -> Result<ElementLibrary, BuildError> {
+        let mut library = ElementLibrary::new();
+        for path in paths {
+            // Elements within the same directory may never share a name
+            let mut folder = ElementLibrary::new();
+            if path.is_file() {
+                for element_defn in ElementDefinition::from_bundle(xot, path)? {
+                    folder.insert(element_defn)?;
+                }
+            } else {
+                for entry in fs::read_dir(path)? {
+                    let entry = entry?;
+                    let entry_path = entry.path();
+                    if let Some(ext) = entry_path.extension() {
+                        if ext == "html" {
+                            let element_defn = ElementDefinition::from_file(xot, &entry_path)?;
+                            folder.insert(element_defn)?;
+                        }
+                    }
+                }
+            }
+            for (_, element_defn) in folder.elements {
+                match overrides {
+                    ElementOverrides::Error => library.insert(element_defn)?,
+                    ElementOverrides::Replace => library.replace(element_defn)?,
+                }
+            }
+        }
+        Ok(library)
+    }
+
+    /// An empty library
+    pub fn new() -> ElementLibrary {
+        ElementLibrary::default()
+    }
+
+    /// Add an element definition. Fails if its name or any of its aliases
+    /// is already taken by another element.
+    pub fn insert(&mut self, element_defn: ElementDefinition) -> Result<(), BuildError> {
+        let names = std::iter::once((element_defn.name.as_str(), element_defn.tag_name)).chain(
+            element_defn
+                .aliases
+                .iter()
+                .map(|(alias, id)| (alias.as_str(), *id)),
+        );
+        for (name, id) in names {
+            if let Some(other) = self.get(id) {
+                return Err(BuildError::Parse {
+                    path: element_defn.path.clone(),
+                    message: format!(
+                        "the name \"{}\" is already taken by the element defined at {}",
+                        name,
+                        other.path.display()
+                    ),
+                });
+            }
+        }
+
+        for (_, alias_id) in &element_defn.aliases {
+            self.aliases.insert(*alias_id, element_defn.tag_name);
+        }
+        self.elements.insert(element_defn.tag_name, element_defn);
+        Ok(())
+    }
+
+    /// Add an element definition, first removing any other elements whose
+    /// name or aliases it takes
+    pub fn replace(&mut self, element_defn: ElementDefinition) -> Result<(), BuildError> {
+        let ids: Vec<xot::NameId> = std::iter::once(element_defn.tag_name)
+            .chain(element_defn.aliases.iter().map(|(_, id)| *id))
+            .collect();
+        for id in ids {
+            let Some(other) = self.get(id) else {
+                continue;
+            };
+            let other_name = other.tag_name;
+            if let Some(other) = self.elements.remove(&other_name) {
+                for (_, alias_id) in &other.aliases {
+                    self.aliases.remove(alias_id);
+                }
+            }
+        }
+        self.insert(element_defn)
+    }
+
+    /// The element with the given tag name or alias
+    pub fn get(&self, name: xot::NameId) -> Option<&ElementDefinition> {
+        let name = self.aliases.get(&name).unwrap_or(&name);
+        self.elements.get(name)
+    }
+
+    /// All elements, keyed by their actual tag name rather than any aliases
+    pub fn elements(&self) -> &HashMap<xot::NameId, ElementDefinition> {
+        &self.elements
+    }
+}
+
+/// Custom behaviour for elements of a given name, implemented in Rust rather
+/// than defined by a file in the element library. Register handlers with
+/// [`Generator::register_handler`].
+///
+/// ```no_run
+/// use html_generator::{Context, ElementHandler, Generator, Options};
+/// use std::path::{Path, PathBuf};
+///
+/// // Replaces <uppercase>text</uppercase> with TEXT
+/// struct Uppercase;
+///
+/// impl ElementHandler for Uppercase {
+///     fn handle(
+///         &self,
+///         xot: &mut xot::Xot,
+///         invocation: xot::Node,
+///         _context: &Context,
+///     ) -> Result<Option<Vec<xot::Node>>, xot::Error> {
+///         let text: String = xot
+///             .descendants(invocation)
+///             .filter_map(|node| xot.text(node).map(|text| text.get().to_string()))
+///             .collect();
+///         Ok(Some(vec![xot.new_text(&text.to_uppercase())]))
+///     }
+/// }
+///
+/// let mut generator = Generator::new(
+///     Path::new("pages/"),
+///     &[PathBuf::from("elements/")],
+///     Path::new("output/"),
+///     Options::default(),
+/// )?;
+/// generator.register_handler("uppercase", Uppercase);
+/// generator.run()?;
+/// # Ok::<(), html_generator::BuildError>(())
+/// ```
+pub trait ElementHandler: Send + Sync {
+    /// Produce the nodes that take the place of `invocation`, or None to
+    /// leave it to the element of the same name in the library, if there is
+    /// one, or else as it is. Like those of library elements, the nodes are
+    /// expanded in turn, so they must not contain an element that the
+    /// handler would replace again forever.
+    fn handle(
+        &self,
+        xot: &mut Xot,
+        invocation: xot::Node,
+        context: &Context,
+    ) -> Result<Option<Vec<xot::Node>>, xot::Error>;
+}
+
+/// Expand every element from the library found in or at `node`, including
+/// elements produced by other expansions. Returns whether `node` itself was
+/// expanded, in which case it has been detached and whatever it expanded to
+/// was inserted in its place, still to be expanded in turn.
+pub fn substitute(
+    xot: &mut Xot,
+    node: xot::Node,
+    library: &ElementLibrary,
+    context: &Context,
+) -> Result<bool, xot::Error> {
+    let Some(element) = xot.element(node) else {
+        return Ok(false);
+    };
+    let element_name = element.name();
+
+    // Nothing inside of <raw> is expanded
+    if xot.name_ns_str(element_name).0 == "raw" {
+        return Ok(false);
+    }
+
+    // Handlers registered by the embedding program take precedence over
+    // elements from the library, unless they leave the element alone
+    let handler = if context.build.handlers.is_empty() {
+        None
+    } else {
+        context.build.handlers.get(xot.name_ns_str(element_name).0)
+    };
+    if let Some(handler) = handler {
+        if let Some(replacement) = handler.handle(xot, node, context)? {
+            if !count_expansion(xot, node, context) {
+                return Ok(false);
+            }
+            context.build.stats.lock().unwrap().instantiations += 1;
+            for replacement_node in replacement {
+                xot.insert_before(node, replacement_node)?;
+            }
+            xot.detach(node)?;
+            return Ok(true);
+        }
+    }
+    if let Some(element_defn) = lookup_element(xot, node, library, context)
+        .filter(|element_defn| in_scope(xot, node, element_defn, context))
+    {
+        if !count_expansion(xot, node, context) {
+            return Ok(false);
+        }
+        // The name given to <partial> isn't an attribute of the element
+        if xot.node_name(node) != Some(element_defn.tag_name) {
+            if let Some(name_id) = xot.name("name") {
+                if xot.name_ns_str(element_name).0 == "partial" {
+                    xot.attributes_mut(node).remove(name_id);
+                }
+            }
+        }
+        context
+            .build
+            .used_elements
+            .lock()
+            .unwrap()
+            .insert(element_defn.name.clone());
+        context.build.stats.lock().unwrap().instantiations += 1;
+        context.document.borrow_mut().scoped |= element_defn.uses.is_some();
+        let instantiation = element_defn.instantiate(xot, node, context)?;
+        let mut document = context.document.borrow_mut();
+        // Whatever an inline element expands to is inline too
+        if element_defn.inline || document.inline_nodes.contains(&node) {
+            document
+                .inline_nodes
+                .extend(instantiation.iter().filter(|n| xot.is_element(**n)));
+        }
+        document.uses_component_styles |= element_defn.style.is_some();
+        if document.scoped {
+            for inst_node in &instantiation {
+                document
+                    .element_scopes
+                    .insert(*inst_node, element_defn.uses.clone());
+            }
+        }
+        if context.options.source_maps {
+            // Whatever the invocation came out of, its replacement did too
+            let mut origins = document.mapped_nodes.remove(&node).unwrap_or_default();
+            origins.push(document.mapped_elements.len());
+            document
+                .mapped_elements
+                .push((element_defn.name.clone(), element_defn.path.clone()));
+            for inst_node in &instantiation {
+                document.mapped_nodes.insert(*inst_node, origins.clone());
+            }
+        }
+        drop(document);
+        // Mark where the element's markup begins and ends. Comments are
+        // stripped by minification anyway, so don't bother then.
+        let trace = context.options.trace_components && !context.options.minify;
+        if trace {
+            let begin = xot.new_comment(&format!(" baumkuchen: {} ", element_defn.name));
+            xot.insert_before(node, begin)?;
+        }
+        for inst_node in instantiation {
+            debug_assert!(!xot.is_removed(node));
+            debug_assert!(!xot.is_removed(inst_node));
+            xot.insert_before(node, inst_node)?;
+        }
+        if trace {
+            let end = xot.new_comment(&format!(" /baumkuchen: {} ", element_defn.name));
+            xot.insert_before(node, end)?;
+        }
+        // xot.remove(node)?;
+        xot.detach(node)?;
+        return Ok(true);
+    }
+
+    // Whatever a child expands to takes its place and is expanded next,
+    // before moving on to its siblings, without going over the children
+    // before it again
+    let mut next = xot.first_child(node);
+    while let Some(child) = next {
+        let previous = xot.previous_sibling(child);
+        let following = xot.next_sibling(child);
+        next = if substitute(xot, child, library, context)? {
+            match previous {
+                Some(previous) => xot.next_sibling(previous),
+                None => xot.first_child(node),
+            }
+        } else {
+            following
+        };
+    }
+
+    Ok(false)
+}
+
+// The element that an element found on a page stands for, if any. Besides
+// using an element by its name or an alias, `<partial name="site-footer" />`
+// uses the element "site-footer", which is meant for partials.
+fn lookup_element<'l>(
+    xot: &Xot,
+    node: xot::Node,
+    library: &'l ElementLibrary,
+    context: &Context,
+) -> Option<&'l ElementDefinition> {
+    let element_name = xot.node_name(node)?;
+    if let Some(element_defn) = library.get(element_name) {
+        return Some(element_defn);
+    }
+    if xot.name_ns_str(element_name).0 != "partial" {
+        return None;
+    }
+    let Some(name) = xot.name("name").and_then(|id| xot.attributes(node).get(id)) else {
+        context.warn("<partial> element without a 'name' attribute");
+        return None;
+    };
+    let Some(element_defn) = xot.name(name).and_then(|id| library.get(id)) else {
+        context.warn(&format!(
+            "<partial name=\"{}\"> refers to an element that doesn't exist",
+            name
+        ));
+        return None;
+    };
+    if !element_defn.partial {
+        context.warn(&format!(
+            "<partial name=\"{}\"> refers to an element that takes attributes or content, which <partial> can't give it (see {})",
+            name,
+            element_defn.path.display()
+        ));
+    }
+    Some(element_defn)
+}
+
+// Count an element about to be expanded, or return false to leave it alone
+// because the page already had as many expansions as it may. Pages only
+// get that far when they keep growing, such as because an element expands
+// into itself alongside something else.
+fn count_expansion(xot: &Xot, node: xot::Node, context: &Context) -> bool {
+    let mut document = context.document.borrow_mut();
+    if document.expansions < context.options.max_expansions {
+        document.expansions += 1;
+        return true;
+    }
+    if !document.gave_up {
+        document.gave_up = true;
+        drop(document);
+        context.error(&format!(
+            "stopped expanding elements at <{}> after {} expansions, which usually means that an element expands into itself (the limit can be raised with --max-expansions)",
+            xot.name_ns_str(xot.node_name(node).unwrap()).0,
+            context.options.max_expansions
+        ));
+    }
+    false
+}
+
+// Whether an element may be expanded where it is used, rather than being left
+// alone because the markup around it comes from an element that doesn't list
+// it in its <uses>
+fn in_scope(
+    xot: &Xot,
+    node: xot::Node,
+    element_defn: &ElementDefinition,
+    context: &Context,
+) -> bool {
+    let Some(scope) = context.document.borrow().element_scope(xot, node) else {
+        return true;
+    };
+    let tag_name = xot.name_ns_str(xot.node_name(node).unwrap()).0;
+    scope.contains(&element_defn.name) || scope.contains(tag_name)
+}
+
+// Concatenate all text inside of a node, with runs of whitespace collapsed
+fn text_content(xot: &Xot, node: xot::Node) -> String {
+    let text: String = xot
+        .descendants(node)
+        .filter_map(|d| xot.text(d).map(|t| t.get().to_string()))
+        .collect();
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Turn arbitrary text into something usable as an id, e.g.
+// "Getting Started!" becomes "getting-started"
+fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+    if slug.is_empty() {
+        slug.push_str("section");
+    }
+    slug
+}
+
+// Append "-2", "-3", etc to a slug until it no longer collides with
+// any of the ids that are already taken, and take it
+fn take_unique_id(slug: String, taken_ids: &mut HashSet<String>) -> String {
+    let mut id = slug.clone();
+    let mut n = 2;
+    while taken_ids.contains(&id) {
+        id = format!("{}-{}", slug, n);
+        n += 1;
+    }
+    taken_ids.insert(id.clone());
+    id
+}
+
+// Give every heading with one of the given tag names an id based on its
+// text, unless it already has one. Returns the headings in document order
+// along with their ids.
+fn assign_heading_ids(
+    xot: &mut Xot,
+    document: xot::Node,
+    heading_names: &[&str],
+) -> Vec<(xot::Node, String)> {
+    let id_attr = xot.add_name("id");
+
+    let mut taken_ids: HashSet<String> = xot
+        .descendants(document)
+        .filter_map(|d| xot.attributes(d).get(id_attr).cloned())
+        .collect();
+
+    let headings: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|d| {
+            xot.element(*d)
+                .is_some_and(|e| heading_names.contains(&xot.name_ns_str(e.name()).0))
+        })
+        .collect();
+
+    let mut result = Vec::new();
+    for heading in headings {
+        let id = if let Some(id) = xot.attributes(heading).get(id_attr) {
+            id.clone()
+        } else {
+            let id = take_unique_id(slugify(&text_content(xot, heading)), &mut taken_ids);
+            xot.attributes_mut(heading).insert(id_attr, id.clone());
+            id
+        };
+        result.push((heading, id));
+    }
+    result
+}
+
+// Replace any <toc> elements with a nested list of links to all <h2>
+// and <h3> headings in the document
+fn substitute_toc(xot: &mut Xot, document: xot::Node) -> Result<(), xot::Error> {
+    let Some(toc_name) = xot.name("toc") else {
+        return Ok(());
+    };
+    let tocs: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|d| xot.node_name(*d) == Some(toc_name))
+        .collect();
+    if tocs.is_empty() {
+        return Ok(());
+    }
+
+    let headings = assign_heading_ids(xot, document, &["h2", "h3"]);
+
+    let ul = xot.add_name("ul");
+    let li = xot.add_name("li");
+    let a = xot.add_name("a");
+    let href = xot.add_name("href");
+    let class = xot.add_name("class");
+    let h3 = xot.add_name("h3");
+
+    for toc in tocs {
+        let list = xot.new_element(ul);
+        xot.attributes_mut(list).insert(class, "toc".to_string());
+
+        // <h3> headings are nested inside of the preceding <h2>'s item
+        let mut last_item: Option<xot::Node> = None;
+        let mut sublist: Option<xot::Node> = None;
+        for (heading, id) in &headings {
+            let item = xot.new_element(li);
+            let link = xot.new_element(a);
+            xot.attributes_mut(link).insert(href, format!("#{}", id));
+            let text = xot.new_text(&text_content(xot, *heading));
+            xot.append(link, text)?;
+            xot.append(item, link)?;
+
+            let is_subheading = xot.node_name(*heading) == Some(h3);
+            match (is_subheading, last_item) {
+                (true, Some(parent_item)) => {
+                    let sub = match sublist {
+                        Some(sub) => sub,
+                        None => {
+                            let sub = xot.new_element(ul);
+                            xot.append(parent_item, sub)?;
+                            sublist = Some(sub);
+                            sub
+                        }
+                    };
+                    xot.append(sub, item)?;
+                }
+                _ => {
+                    xot.append(list, item)?;
+                    last_item = Some(item);
+                    sublist = None;
+                }
+            }
+        }
+
+        xot.replace(toc, list)?;
+    }
+
+    Ok(())
+}
+
+// Remember all root-relative links in a generated page so that they can be
+// checked once all pages have been generated
+fn collect_links(xot: &Xot, document: xot::Node, context: &Context) {
+    let Some(href) = xot.name("href") else {
+        return;
+    };
+    let mut links = context.build.links.lock().unwrap();
+    for node in xot.descendants(document) {
+        let Some(link) = xot.attributes(node).get(href) else {
+            continue;
+        };
+        if link.starts_with('/') && !link.starts_with("//") {
+            links.push((context.file_path.clone(), link.clone()));
+        }
+    }
+}
+
+/// Turn a path relative to the root of the source or destination directory
+/// into a root-relative url path, which always uses forward slashes
+/// regardless of platform, e.g. `blog\post.html` on Windows becomes `/blog/post.html`
+pub fn url_path(relative_path: &path::Path) -> String {
+    let mut url = String::new();
+    for component in relative_path.components() {
+        if let path::Component::Normal(name) = component {
+            url.push('/');
+            url += &name.to_string_lossy();
+        }
+    }
+    if url.is_empty() {
+        url.push('/');
+    }
+    url
+}
+
+/// The url a generated page is served at, given its path relative to the
+/// root of the destination directory. With pretty urls, a page written
+/// to `about/index.html` (or whatever index documents are named) is served
+/// at `/about/`.
+pub fn public_url(relative_path: &path::Path, options: &Options) -> String {
+    let url = url_path(relative_path);
+    if options.pretty_urls {
+        if let Some(directory) = url.strip_suffix(&options.index_file_name()) {
+            return directory.to_string();
+        }
+    }
+    url
+}
+
+// The name a page or file of the source is written under, such as
+// "logo.png" for "Logo_src.PNG" with `lowercase_names` and a
+// `strip_name_suffix` of "_src", which is removed from the end of the name
+// before the extension
+fn output_file_name(name: &str, options: &Options) -> String {
+    let mut name = name.to_string();
+    if let Some(suffix) = options
+        .strip_name_suffix
+        .as_deref()
+        .filter(|s| !s.is_empty())
+    {
+        let dot = name.rfind('.').filter(|dot| *dot > 0).unwrap_or(name.len());
+        if name[..dot].ends_with(suffix) && dot > suffix.len() {
+            name.replace_range(dot - suffix.len()..dot, "");
+        }
+    }
+    if options.lowercase_names {
+        name = name.to_lowercase();
+    }
+    name
+}
+
+// Where to write a page named `stem`, either as `stem.html` or, with pretty
+// urls, as `stem/index.html`, so that it can be served at `stem/`. Either
+// uses the output extension in place of html if there is one, and a page
+// named `index` becomes the directory's index document, whatever its name.
+fn page_dst_path(dir: &path::Path, stem: &std::ffi::OsStr, options: &Options) -> path::PathBuf {
+    if stem == "index" {
+        dir.join(options.index_file_name())
+    } else if options.pretty_urls {
+        dir.join(stem).join(options.index_file_name())
+    } else {
+        plain_page_path(dir, stem, options)
+    }
+}
+
+// `stem.html` in `dir`, or `stem` with the output extension. Unlike
+// `Path::with_extension`, this keeps any dots in the stem, so that
+// `v1.2.html` doesn't turn into `v1.html`.
+fn plain_page_path(dir: &path::Path, stem: &std::ffi::OsStr, options: &Options) -> path::PathBuf {
+    let mut name = stem.to_os_string();
+    name.push(".");
+    name.push(options.page_extension());
+    dir.join(name)
+}
+
+// Find any collected links to html pages (or directories and extensionless
+// pretty URLs) that don't resolve to one of the generated pages
+fn check_links(build: &Build) -> Vec<(String, String)> {
+    let generated = build.outputs.lock().unwrap();
+    let extension = format!(".{}", build.options.page_extension());
+    let index = build.options.index_file_name();
+
+    let mut dead_links = Vec::new();
+    for (page_path, link) in build.links.lock().unwrap().iter() {
+        // ignore query strings and fragments
+        let path = link.split(['?', '#']).next().unwrap();
+        let last_segment = path.rsplit('/').next().unwrap();
+
+        let candidates = if path.ends_with('/') {
+            vec![format!("{}{}", path, index)]
+        } else if path.ends_with(&extension) {
+            vec![path.to_string()]
+        } else if !last_segment.contains('.') {
+            vec![
+                format!("{}{}", path, extension),
+                format!("{}/{}", path, index),
+            ]
+        } else {
+            // not a page
+            continue;
+        };
+
+        // Pages outside of the part of the site being generated are still
+        // there from before
+        let exists = |c: &String| {
+            generated.contains(c)
+                || (build.options.only.is_some()
+                    && build.dst_root.join(c.trim_start_matches('/')).is_file())
+        };
+        if !candidates.iter().any(exists) {
+            dead_links.push((page_path.clone(), link.clone()));
+        }
+    }
+    dead_links
+}
+
+// Make sure that a path about to be written to resolves to somewhere inside
+// the destination directory, even in the presence of symlinks
+fn ensure_within_destination(dst_root: &path::Path, path: &path::Path) -> Result<(), io::Error> {
+    let root = dst_root.canonicalize()?;
+
+    // The target itself may not exist yet, in which case its parent directory
+    // must. An existing target is resolved fully since writing through a
+    // symlinked file would also escape.
+    let resolved = if path.exists() {
+        path.canonicalize()?
+    } else {
+        let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid output path: {}", path.display()),
+            ));
+        };
+        parent.canonicalize()?.join(file_name)
+    };
+
+    if !resolved.starts_with(&root) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Refusing to write \"{}\" because it resolves to \"{}\", which is outside of the destination directory \"{}\"",
+                path.display(),
+                resolved.display(),
+                root.display()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+// Parse, minify, and write out an XML file such as an SVG image. Comments
+// and processing instructions are kept, since unlike in html, they can
+// matter to whatever reads the file.
+fn process_xml_file(
+    build: &Build,
+    source_path: &path::Path,
+    dst_path: &path::Path,
+    options: &Options,
+) -> Result<(), BuildError> {
+    let source_text = read_source(source_path)?;
+    let mut xot = new_xot();
+    let document = xot.parse(&source_text).map_err(|err| BuildError::Parse {
+        path: source_path.to_path_buf(),
+        message: err.to_string(),
+    })?;
+
+    if options.minify {
+        minify_around(
+            &mut xot,
+            document,
+            &DocumentState::default(),
+            true,
+            options.keep_whitespace_text,
+        )?;
+    }
+
+    // The XML declaration isn't part of the parsed document, so it is
+    // written out again just as it was. Processing instructions like
+    // <?xml-stylesheet ...?> are, and mustn't be mistaken for it.
+    let mut xml = String::new();
+    let declaration = source_text
+        .strip_prefix("<?xml")
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_whitespace()));
+    if declaration {
+        if let Some(end) = source_text.find("?>") {
+            xml += &source_text[..end + 2];
+            if !options.minify {
+                xml += "\n";
+            }
+        }
+    }
+    xml += &xot.to_string(document)?;
+
+    ensure_within_destination(&build.dst_root, dst_path)?;
+    ensure_not_overwriting(dst_path, options)?;
+    write_generated(dst_path, xml.as_bytes(), options)?;
+    build.wrote(dst_path);
+    Ok(())
+}
+
+// With `no_overwrite`, fail if something is already where a file is about to
+// be written
+fn ensure_not_overwriting(dst_path: &path::Path, options: &Options) -> io::Result<()> {
+    if options.no_overwrite && dst_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("Output file already exists: {}", dst_path.display()),
+        ));
+    }
+    Ok(())
+}
+
+// Write a generated file, unless an earlier build that wasn't cleaned away
+// already wrote the exact same contents there. Leaving it alone keeps its
+// modification time, which deployment tools like rsync go by.
+fn write_generated(dst_path: &path::Path, contents: &[u8], options: &Options) -> io::Result<()> {
+    if !options.cleans_first() && fs::read(dst_path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    fs::write(dst_path, contents)
+}
+
+fn generate_file(
+    xot: &mut Xot,
+    build: &Build,
+    page: &Page,
+    library: &ElementLibrary,
+) -> Result<(), BuildError> {
+    let source_path = &page.source_path;
+    let dst_path = &page.dst_path;
+
+    if !source_path.is_file() {
+        return Err(BuildError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Source path must be a file: {}", source_path.display()),
+        )));
+    }
+
+    let source_text = read_source(source_path)?;
+    let parse = |xot: &mut Xot| {
+        xot.parse(&source_text).map_err(|err| BuildError::Parse {
+            path: source_path.to_path_buf(),
+            message: err.to_string(),
+        })
+    };
+    let document = parse(xot)?;
+
+    let file_path = url_path(source_path.strip_prefix(&build.source_root).unwrap());
+    let url = public_url(
+        dst_path.strip_prefix(&build.dst_root).unwrap_or(dst_path),
+        &page.options,
+    );
+    let context = Context::new(file_path, build)
+        .with_options(&page.options)
+        .with_url(url);
+
+    let Some(paginate) = find_element(xot, document, "paginate") else {
+        return render_page(xot, build, page, library, document, &context, dst_path);
+    };
+
+    // A page containing <paginate in="xyz" size="n"> is generated once for
+    // every n items of the list 'xyz', as 1.html, 2.html, etc. in a directory
+    // named after the page (or 1/, 2/, etc. with pretty urls). Each one gets
+    // its items and position as 'page'.
+    let attribute = |name: &str| {
+        xot.name(name)
+            .and_then(|id| xot.attributes(paginate).get(id))
+            .cloned()
+    };
+    let items = match attribute("in").map(|path| (context.lookup_data(&path), path)) {
+        Some((Some(serde_json::Value::Array(items)), _)) => items.clone(),
+        Some((_, path)) => {
+            build.warn_in(
+                source_path,
+                &source_path.display().to_string(),
+                &format!("<paginate in=\"{}\"> doesn't refer to a list", path),
+            );
+            Vec::new()
+        }
+        None => {
+            build.warn_in(
+                source_path,
+                &source_path.display().to_string(),
+                "<paginate> element without an 'in' attribute",
+            );
+            Vec::new()
+        }
+    };
+    let size = match attribute("size").map(|size| size.parse::<usize>()) {
+        None => 10,
+        Some(Ok(size)) if size > 0 => size,
+        Some(_) => {
+            build.warn_in(
+                source_path,
+                &source_path.display().to_string(),
+                "<paginate> needs a positive 'size', using 10",
+            );
+            10
+        }
+    };
+    xot.remove(document)?;
+
+    let dst_dir = if page.options.pretty_urls {
+        dst_path.parent().unwrap().to_path_buf()
+    } else {
+        // The directory takes the name of the page without the extension
+        // `plain_page_path` gave it, such as `v1.2` for `v1.2.html`
+        let extension = format!(".{}", page.options.page_extension());
+        let file_name = dst_path.file_name().unwrap().to_string_lossy();
+        match file_name.strip_suffix(&extension) {
+            Some(stem) => dst_path.with_file_name(stem),
+            None => dst_path.with_extension(""),
+        }
+    };
+    if !dst_dir.exists() {
+        ensure_within_destination(&build.dst_root, &dst_dir)?;
+        fs::create_dir(&dst_dir)?;
+    }
+
+    let chunks: Vec<&[serde_json::Value]> = if items.is_empty() {
+        vec![&[]]
+    } else {
+        items.chunks(size).collect()
+    };
+    let chunk_dst_path =
+        |number: usize| page_dst_path(&dst_dir, number.to_string().as_ref(), &page.options);
+    let page_url = |number: usize| {
+        let path = chunk_dst_path(number);
+        public_url(
+            path.strip_prefix(&build.dst_root).unwrap_or(&path),
+            &page.options,
+        )
+    };
+    for (index, chunk) in chunks.iter().enumerate() {
+        let number = index + 1;
+        let document = parse(xot)?;
+        if let Some(paginate) = find_element(xot, document, "paginate") {
+            xot.remove(paginate)?;
+        }
+        let page_data = serde_json::json!({
+            "number": number,
+            "total": chunks.len(),
+            "items": chunk,
+            "prev": if number > 1 { page_url(number - 1) } else { String::new() },
+            "next": if number < chunks.len() { page_url(number + 1) } else { String::new() },
+        });
+        let mut page_context = context
+            .with_data("page", page_data)
+            .with_url(page_url(number));
+        // every file is a document of its own, so it starts over on the
+        // expansion count and on what the previous one used
+        page_context.document = Rc::new(RefCell::new(DocumentState::default()));
+        let chunk_dst_path = chunk_dst_path(number);
+        render_page(
+            xot,
+            build,
+            page,
+            library,
+            document,
+            &page_context,
+            &chunk_dst_path,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Find the first element with the given name at or below a node
+fn find_element(xot: &Xot, node: xot::Node, name: &str) -> Option<xot::Node> {
+    let name = xot.name(name)?;
+    xot.descendants(node)
+        .find(|descendant| xot.node_name(*descendant) == Some(name))
+}
+
+// Expand, post-process, and write out a parsed page
+fn render_page(
+    xot: &mut Xot,
+    build: &Build,
+    page: &Page,
+    library: &ElementLibrary,
+    document: xot::Node,
+    context: &Context,
+    dst_path: &path::Path,
+) -> Result<(), BuildError> {
+    // Fail before going to the trouble of expanding the page
+    ensure_not_overwriting(dst_path, &page.options)?;
+
+    let children: Vec<xot::Node> = xot.children(document).collect();
+    for node in children {
+        substitute(xot, node, library, context)?;
+    }
+
+    expand_marked_comments(xot, document, context);
+
+    restore_escaped_dollars(xot, document);
+
+    // Provided values can come from anywhere on the page, so this waits until
+    // every element is expanded
+    substitute_provides(xot, document, context)?;
+
+    substitute_assets(xot, document, context)?;
+
+    if let Some(env) = &page.options.env {
+        strip_env_regions(xot, document, env, context)?;
+    }
+
+    if page.options.highlight {
+        highlight_code(xot, document, context)?;
+    }
+
+    if page.options.dedupe_assets {
+        dedupe_assets(xot, document, page.options.hoist_assets, context)?;
+    }
+
+    if page.options.auto_ids {
+        assign_heading_ids(xot, document, &["h1", "h2", "h3", "h4", "h5", "h6"]);
+    }
+
+    // The table of contents depends on the fully expanded document
+    substitute_toc(xot, document)?;
+
+    unwrap_raw(xot, document, context)?;
+
+    if build.options.check_links {
+        collect_links(xot, document, context);
+    }
+
+    if context.document.borrow().uses_component_styles {
+        link_component_styles(xot, document, context);
+    }
+
+    consolidate_text(xot, document, &context.document.borrow())?;
+
+    if page.options.minify {
+        minify_around(
+            xot,
+            document,
+            &context.document.borrow(),
+            false,
+            page.options.keep_whitespace_text,
+        )?;
+    }
+
+    // Pretty urls put pages into directories of their own
+    if let Some(parent) = dst_path.parent() {
+        if !parent.exists() {
+            ensure_within_destination(&build.dst_root, parent)?;
+            fs::create_dir(parent)?;
+        }
+    }
+    ensure_within_destination(&build.dst_root, dst_path)?;
+
+    // Text inside these is written as CDATA sections, which only html's
+    // foreign content like SVG and MathML understands. They're matched by
+    // local name, whatever their namespace.
+    let mut cdata_section_elements: Vec<xot::NameId> = Vec::new();
+    if !page.options.cdata_elements.is_empty() {
+        for node in xot.descendants(document) {
+            let Some(element) = xot.element(node) else {
+                continue;
+            };
+            let name = element.name();
+            let local_name = xot.name_ns_str(name).0;
+            if page.options.cdata_elements.iter().any(|n| n == local_name)
+                && !cdata_section_elements.contains(&name)
+            {
+                cdata_section_elements.push(name);
+            }
+        }
+    }
+
+    if page.options.source_maps {
+        insert_source_map_markers(xot, document, &mut context.document.borrow_mut())?;
+    }
+
+    // Leaving attribute values unquoted is only possible in html
+    let requote = page.options.attribute_quotes != AttributeQuotes::Double
+        && page.options.serialization == Serialization::Html5;
+    let postprocess =
+        page.options.entities != Entities::Utf8 || requote || page.options.source_maps;
+    if !postprocess && (page.options.cleans_first() || !dst_path.exists()) {
+        // Serialize straight into the output file rather than building up
+        // the whole page as a string first, to keep memory usage down
+        let mut writer = io::BufWriter::new(fs::File::create(dst_path)?);
+        serialize_page(
+            xot,
+            document,
+            cdata_section_elements,
+            &page.options,
+            &mut writer,
+        )?;
+        writer.flush()?;
+    } else {
+        let mut html = Vec::new();
+        serialize_page(
+            xot,
+            document,
+            cdata_section_elements,
+            &page.options,
+            &mut html,
+        )?;
+        if postprocess {
+            // These options can only be applied to the serialized page
+            let mut text = String::from_utf8(html).map_err(io::Error::other)?;
+            if requote {
+                text = requote_attributes(&text, page.options.attribute_quotes);
+            }
+            if page.options.entities == Entities::Ascii {
+                text = escape_non_ascii(&text);
+            }
+            if page.options.source_maps {
+                text = write_source_map(build, &text, dst_path, context)?;
+            }
+            html = text.into_bytes();
+        }
+        write_generated(dst_path, &html, &page.options)?;
+    }
+    build.wrote(dst_path);
+
+    if let Ok(relative_path) = dst_path.strip_prefix(&build.dst_root) {
+        build
+            .outputs
+            .lock()
+            .unwrap()
+            .insert(url_path(relative_path));
+    }
+    build.stats.lock().unwrap().pages += 1;
+
+    // remove document node to free memory (hopefully?)
+    xot.remove(document)?;
+
+    Ok(())
+}
+
+// Start of the comments that mark where the markup of each element begins
+// and ends in a page being written with a source map
+const SOURCE_MAP_MARKER: &str = "<!--baumkuchen-map:";
+
+// Surround every node that came out of an element with comments marking
+// which element that was, to be found again in the serialized page by
+// `write_source_map`. This only happens right before writing the page, so that
+// the comments don't get in the way of anything else.
+fn insert_source_map_markers(
+    xot: &mut Xot,
+    document: xot::Node,
+    state: &mut DocumentState,
+) -> Result<(), xot::Error> {
+    let mapped_nodes = std::mem::take(&mut state.mapped_nodes);
+    for (node, origins) in mapped_nodes {
+        // Nodes may have been removed since, or merged into others
+        if xot.is_removed(node) || !xot.ancestors(node).any(|a| a == document) {
+            continue;
+        }
+        for origin in &origins {
+            let begin = xot.new_comment(&format!("baumkuchen-map:{}", origin));
+            xot.insert_before(node, begin)?;
+        }
+        // Each end goes right after the node, so the outermost goes first
+        for origin in &origins {
+            let end = xot.new_comment(&format!("baumkuchen-map:/{}", origin));
+            xot.insert_after(node, end)?;
+        }
+    }
+    Ok(())
+}
+
+// Take the comments left by `insert_source_map_markers` out of a serialized
+// page and write down where they were to `<page>.map` next to it, as JSON
+// like `{"source": "/blog/post.html", "elements": [{"element": "button",
+// "definition": "elements/button.html", "start": {"line": 1, "column": 120},
+// "end": {...}}]}`. Lines and columns count from 1, columns in characters.
+// Whatever isn't covered by an element comes from the page itself. Returns
+// the page without the comments.
+fn write_source_map(
+    build: &Build,
+    html: &str,
+    dst_path: &path::Path,
+    context: &Context,
+) -> Result<String, BuildError> {
+    let mut output = String::with_capacity(html.len());
+    // (element, start, end) as byte offsets into the output
+    let mut ranges: Vec<(usize, usize, usize)> = Vec::new();
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find(SOURCE_MAP_MARKER) {
+        output += &rest[..start];
+        rest = &rest[start + SOURCE_MAP_MARKER.len()..];
+        let end = rest.find("-->").unwrap_or(rest.len());
+        let marker = &rest[..end];
+        rest = rest.get(end + 3..).unwrap_or_default();
+        if let Some(origin) = marker
+            .strip_prefix('/')
+            .and_then(|o| o.parse::<usize>().ok())
+        {
+            if let Some(position) = open.iter().rposition(|(o, _)| *o == origin) {
+                let (_, begin) = open.remove(position);
+                // Neighbouring nodes from the same element make up one range
+                match ranges.iter_mut().rev().find(|range| range.0 == origin) {
+                    Some(range) if range.2 == begin => range.2 = output.len(),
+                    _ => ranges.push((origin, begin, output.len())),
+                }
+            }
+        } else if let Ok(origin) = marker.parse::<usize>() {
+            open.push((origin, output.len()));
+        }
+    }
+    output += rest;
+    ranges.sort_by_key(|(origin, start, end)| (*start, std::cmp::Reverse(*end), *origin));
+
+    // Where each line of the output starts, to turn offsets into positions
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(output.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let position = |offset: usize| {
+        let line = line_starts.partition_point(|start| *start <= offset);
+        let line_start = line_starts[line - 1];
+        let column = output[line_start..offset].chars().count() + 1;
+        serde_json::json!({ "line": line, "column": column })
+    };
+
+    let state = context.document.borrow();
+    let elements: Vec<serde_json::Value> = ranges
+        .iter()
+        .map(|(origin, start, end)| {
+            let (name, definition) = &state.mapped_elements[*origin];
+            serde_json::json!({
+                "element": name,
+                "definition": definition.display().to_string(),
+                "start": position(*start),
+                "end": position(*end),
+            })
+        })
+        .collect();
+    let map = serde_json::json!({
+        "source": context.file_path,
+        "elements": elements,
+    });
+
+    let mut map_path = dst_path.as_os_str().to_owned();
+    map_path.push(".map");
+    let map_path = path::PathBuf::from(map_path);
+    ensure_within_destination(&build.dst_root, &map_path)?;
+    ensure_not_overwriting(&map_path, context.options)?;
+    write_generated(&map_path, map.to_string().as_bytes(), context.options)?;
+    build.wrote(&map_path);
+    Ok(output)
+}
+
+// Elements that never have any contents in html, which XHTML writes as
+// `<br/>`
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// Write out a fully processed page as `options.serialization` says, starting
+// with the doctype that goes with it or the one given by `options.doctype`
+fn serialize_page(
+    xot: &mut Xot,
+    document: xot::Node,
+    mut cdata_section_elements: Vec<xot::NameId>,
+    options: &Options,
+    writer: &mut impl Write,
+) -> Result<(), BuildError> {
+    if options.serialization == Serialization::Html5 {
+        let parameters = xot::output::html5::Parameters {
+            indentation: None,
+            cdata_section_elements,
+        };
+        let Some(doctype) = &options.doctype else {
+            xot.html5().serialize_write(parameters, document, writer)?;
+            return Ok(());
+        };
+        // The html5 serializer always starts with a doctype of its own
+        let html = xot.html5().serialize_string(parameters, document)?;
+        let rest = html.strip_prefix("<!DOCTYPE html>").unwrap_or(&html);
+        write!(writer, "<!DOCTYPE {}>{}", doctype, rest)?;
+        return Ok(());
+    }
+
+    if options.serialization == Serialization::Xhtml {
+        // In XML, any empty element can close itself, but browsers only
+        // accept that for void elements, so the others are given an empty
+        // text node to be written with an end tag instead
+        let empty: Vec<xot::Node> = xot
+            .descendants(document)
+            .filter(|node| {
+                xot.element(*node).is_some_and(|element| {
+                    !VOID_ELEMENTS.contains(&xot.name_ns_str(element.name()).0)
+                }) && xot.first_child(*node).is_none()
+            })
+            .collect();
+        for node in empty {
+            let text = xot.new_text("");
+            xot.append(node, text)?;
+        }
+
+        // XHTML elements are those in its namespace, which is declared on
+        // the document element for all that are in none
+        let xhtml = xot.add_namespace("http://www.w3.org/1999/xhtml");
+        let no_namespace = xot.no_namespace();
+        let elements: Vec<xot::Node> = xot
+            .descendants(document)
+            .filter(|node| xot.is_element(*node))
+            .collect();
+        for node in elements {
+            let name = xot.node_name(node).unwrap();
+            if xot.namespace_for_name(name) != no_namespace {
+                continue;
+            }
+            let local_name = xot.local_name_str(name).to_string();
+            let xhtml_name = xot.add_name_ns(&local_name, xhtml);
+            xot.element_mut(node).unwrap().set_name(xhtml_name);
+            if let Some(cdata_name) = cdata_section_elements.iter_mut().find(|n| **n == name) {
+                *cdata_name = xhtml_name;
+            }
+        }
+        let root = xot.document_element(document)?;
+        let empty_prefix = xot.empty_prefix();
+        xot.namespaces_mut(root).insert(empty_prefix, xhtml);
+
+        // Scripts and stylesheets are text, where a '<' or '&' would
+        // otherwise be escaped as it is elsewhere
+        for name in ["script", "style"] {
+            let name = xot.add_name_ns(name, xhtml);
+            if !cdata_section_elements.contains(&name) {
+                cdata_section_elements.push(name);
+            }
+        }
+    }
+
+    let prolog = match (&options.doctype, options.serialization) {
+        (Some(doctype), Serialization::Xml) => {
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE {}>\n",
+                doctype
+            )
+        }
+        (None, Serialization::Xml) => "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string(),
+        (Some(doctype), _) => format!("<!DOCTYPE {}>\n", doctype),
+        (None, _) => "<!DOCTYPE html>\n".to_string(),
+    };
+    writer.write_all(prolog.as_bytes())?;
+    let parameters = xot::output::xml::Parameters {
+        cdata_section_elements,
+        ..Default::default()
+    };
+    if options.serialization == Serialization::Xhtml {
+        // Empty scripts and stylesheets, like `<script src="...">`, have no
+        // text to keep from being escaped
+        let xhtml = xot.serialize_xml_string(parameters, document)?;
+        writer.write_all(xhtml.replace("<![CDATA[]]>", "").as_bytes())?;
+        return Ok(());
+    }
+    xot.serialize_xml_write(parameters, document, writer)?;
+    Ok(())
+}
+
+// Expand what is left of the expressions in comments marked with a '!', as
+// in `<!--! built ${build.date} -->`, including those written on the page
+// itself, and remove the marker. Marked comments survive minification.
+fn expand_marked_comments(xot: &mut Xot, document: xot::Node, context: &Context) {
+    let raw_name = xot.name("raw");
+    let comments: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| xot.comment(*node).is_some_and(|c| c.get().starts_with('!')))
+        .filter(|node| {
+            raw_name.is_none() || !xot.ancestors(*node).any(|a| xot.node_name(a) == raw_name)
+        })
+        .collect();
+    if comments.is_empty() {
+        return;
+    }
+    // Outside of any element, 'self' is the page's root element
+    let root = xot.document_element(document).unwrap_or(document);
+    for comment in comments {
+        let text = xot.comment(comment).unwrap().get()[1..].to_string();
+        let expanded = expand_string(xot, &text, root, context);
+        xot.comment_mut(comment).unwrap().set(expanded);
+        context
+            .document
+            .borrow_mut()
+            .preformatted_nodes
+            .insert(comment);
+    }
+}
+
+// Move the contents of every `<provide key="...">` to wherever a
+// `<consume key="..."/>` with the same key is, such as a title given in the
+// body of a page into the <head> of its layout. A <consume> keeps its own
+// contents as a fallback when nothing is provided for it. Only the first
+// <provide> for a key counts.
+fn substitute_provides(
+    xot: &mut Xot,
+    document: xot::Node,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let provide_name = xot.name("provide");
+    let consume_name = xot.name("consume");
+    if provide_name.is_none() && consume_name.is_none() {
+        return Ok(());
+    }
+    let raw_name = xot.name("raw");
+    let key_name = xot.add_name("key");
+    let outside_raw = |xot: &Xot, node: xot::Node| {
+        raw_name.is_none() || !xot.ancestors(node).any(|a| xot.node_name(a) == raw_name)
+    };
+    let provides: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| provide_name.is_some() && xot.node_name(*node) == provide_name)
+        .filter(|node| outside_raw(xot, *node))
+        .collect();
+    let consumes: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| consume_name.is_some() && xot.node_name(*node) == consume_name)
+        .filter(|node| outside_raw(xot, *node))
+        .collect();
+
+    // Take out what's provided first, so that a <provide> inside of a
+    // <consume>'s fallback doesn't end up in two places
+    let mut provided: HashMap<String, xot::Node> = HashMap::new();
+    for provide in provides {
+        xot.detach(provide)?;
+        let Some(key) = xot.attributes(provide).get(key_name).cloned() else {
+            context.warn("<provide> element without a 'key' attribute");
+            xot.remove(provide)?;
+            continue;
+        };
+        if provided.contains_key(&key) {
+            context.warn(&format!(
+                "'{}' is provided more than once, using the first",
+                key
+            ));
+            xot.remove(provide)?;
+            continue;
+        }
+        provided.insert(key, provide);
+    }
+
+    for consume in consumes {
+        let Some(key) = xot.attributes(consume).get(key_name).cloned() else {
+            context.warn("<consume> element without a 'key' attribute");
+            xot.remove(consume)?;
+            continue;
+        };
+        let contents: Vec<xot::Node> = match provided.get(&key) {
+            Some(provide) => {
+                let children: Vec<xot::Node> = xot.children(*provide).collect();
+                children.into_iter().map(|child| xot.clone(child)).collect()
+            }
+            None => {
+                if xot.first_child(consume).is_none() {
+                    context.undefined(&format!("Nothing on the page provides '{}'", key));
+                }
+                xot.children(consume).collect()
+            }
+        };
+        for node in contents {
+            xot.insert_before(consume, node)?;
+        }
+        xot.remove(consume)?;
+    }
+    for provide in provided.into_values() {
+        xot.remove(provide)?;
+    }
+    Ok(())
+}
+
+// Write out escaped expressions as they are, as `${...}`. In attributes that
+// were never expanded, such as those of plain html elements on a page, `$${`
+// still needs to lose its escape. The contents of <raw> are left alone.
+fn restore_escaped_dollars(xot: &mut Xot, document: xot::Node) {
+    let raw_name = xot.name("raw");
+    let nodes: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| {
+            raw_name.is_none() || !xot.ancestors(*node).any(|a| xot.node_name(a) == raw_name)
+        })
+        .collect();
+    let restore = |text: &str| text.replace("$${", "${").replace(ESCAPED_DOLLAR, "$");
+    for node in nodes {
+        if let Some(text) = xot.text_mut(node) {
+            if text.get().contains(ESCAPED_DOLLAR) {
+                let restored = text.get().replace(ESCAPED_DOLLAR, "$");
+                text.set(restored);
+            }
+        } else if let Some(comment) = xot.comment_mut(node) {
+            if comment.get().contains(ESCAPED_DOLLAR) {
+                let restored = comment.get().replace(ESCAPED_DOLLAR, "$");
+                comment.set(restored);
+            }
+        } else if xot.is_element(node) {
+            let keys: Vec<xot::NameId> = xot.attributes(node).keys().collect();
+            for key in keys {
+                let value = xot.attributes(node).get(key).unwrap();
+                if value.contains("$${") || value.contains(ESCAPED_DOLLAR) {
+                    let restored = restore(value);
+                    *xot.attributes_mut(node).get_mut(key).unwrap() = restored;
+                }
+            }
+        }
+    }
+}
+
+// Merge every run of neighbouring text nodes into a single one. Expansion
+// leaves text from different places side by side, such as around the value of
+// a <self.xyz>, since `new_xot` turns off text consolidation, and minification
+// only collapses whitespace within each text node. Text nodes that the
+// document keeps track of for minification are left alone.
+fn consolidate_text(
+    xot: &mut Xot,
+    document: xot::Node,
+    state: &DocumentState,
+) -> Result<(), xot::Error> {
+    let is_tracked = |node: &xot::Node| {
+        state.preformatted_nodes.contains(node) || state.spaced_nodes.contains(node)
+    };
+    let texts: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| xot.text(*node).is_some() && !is_tracked(node))
+        .collect();
+    for node in texts {
+        let Some(previous) = xot.previous_sibling(node) else {
+            continue;
+        };
+        let Some(previous_text) = xot.text(previous) else {
+            continue;
+        };
+        if is_tracked(&previous) {
+            continue;
+        }
+        let merged = format!("{}{}", previous_text.get(), xot.text(node).unwrap().get());
+        xot.text_mut(previous).unwrap().set(merged);
+        xot.remove(node)?;
+    }
+    Ok(())
+}
+
+// Replace every `<asset src="...">` element with an `<img>` of the file,
+// keeping its other attributes. The src is resolved like a link, relative to
+// the page or to the root of the source directory, and written relative to the
+// page's url. With a `hash` attribute, the file is also copied next to where
+// it is copied anyway, with a hash of its contents in the name, as in
+// `logo.5d41402abc4b2a76.png`, so that browsers can cache it indefinitely.
+fn substitute_assets(
+    xot: &mut Xot,
+    document: xot::Node,
+    context: &Context,
+) -> Result<(), BuildError> {
+    let Some(asset_name) = xot.name("asset") else {
+        return Ok(());
+    };
+    let raw_name = xot.name("raw");
+    let assets: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| xot.node_name(*node) == Some(asset_name))
+        .filter(|node| {
+            raw_name.is_none() || !xot.ancestors(*node).any(|a| xot.node_name(a) == raw_name)
+        })
+        .collect();
+    if assets.is_empty() {
+        return Ok(());
+    }
+
+    let src_name = xot.add_name("src");
+    let hash_name = xot.add_name("hash");
+    let img_name = xot.add_name("img");
+    for asset in assets {
+        let Some(src) = xot.attributes(asset).get(src_name).cloned() else {
+            context.warn("<asset> element without a 'src' attribute");
+            xot.remove(asset)?;
+            continue;
+        };
+        let hashed = xot.attributes(asset).get(hash_name).is_some();
+        let img = xot.new_element(img_name);
+        xot.attributes_mut(img)
+            .insert(src_name, asset_url(&src, hashed, context)?);
+        let others: Vec<(xot::NameId, String)> = xot
+            .attributes(asset)
+            .iter()
+            .filter(|(key, _)| *key != src_name && *key != hash_name)
+            .map(|(key, value)| (key, value.clone()))
+            .collect();
+        for (key, value) in others {
+            xot.attributes_mut(img).insert(key, value);
+        }
+        xot.replace(asset, img)?;
+    }
+    Ok(())
+}
+
+// The url of the file an <asset src="..."> refers to, relative to the current
+// page, after copying it under a hashed name if asked to
+fn asset_url(src: &str, hashed: bool, context: &Context) -> Result<String, BuildError> {
+    let absolute = if src.starts_with('/') {
+        src.to_string()
+    } else {
+        let page_dir = &context.file_path[..context.file_path.rfind('/').unwrap_or(0) + 1];
+        format!("{}{}", page_dir, src)
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in absolute.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let source_path: path::PathBuf = std::iter::once(context.build.source_root.as_path())
+        .chain(segments.iter().map(path::Path::new))
+        .collect();
+    if segments.is_empty() || !source_path.is_file() {
+        context.warn(&format!(
+            "<asset src=\"{}\"> refers to a file that doesn't exist",
+            src
+        ));
+        return Ok(src.to_string());
+    }
+
+    // The file is copied under another name if the build renames files
+    let file_name = output_file_name(segments.last().unwrap(), &context.build.options);
+    *segments.last_mut().unwrap() = &file_name;
+
+    let mut target = format!("/{}", segments.join("/"));
+    if hashed {
+        target = copy_hashed_asset(&source_path, &segments, context)?;
+    }
+    Ok(relative_url(&context.url, &target))
+}
+
+// Copy a file from the source directory to the same place in the destination,
+// but with a hash of its contents added to the name, and return its
+// root-relative url. Every file is only copied once per build.
+fn copy_hashed_asset(
+    source_path: &path::Path,
+    segments: &[&str],
+    context: &Context,
+) -> Result<String, BuildError> {
+    let build = context.build;
+    let mut hashed_assets = build.hashed_assets.lock().unwrap();
+    if let Some(url) = hashed_assets.get(source_path) {
+        return Ok(url.clone());
+    }
+
+    let contents = fs::read(source_path)?;
+    let hash = content_hash(&contents);
+    let (dir, file_name) = segments.split_at(segments.len() - 1);
+    let file_name = match file_name[0].rfind('.') {
+        Some(dot) if dot > 0 => {
+            format!("{}.{}{}", &file_name[0][..dot], hash, &file_name[0][dot..])
+        }
+        _ => format!("{}.{}", file_name[0], hash),
+    };
+    let dst_path: path::PathBuf = std::iter::once(build.dst_root.as_path())
+        .chain(dir.iter().map(path::Path::new))
+        .chain(std::iter::once(path::Path::new(&file_name)))
+        .collect();
+    ensure_within_destination(&build.dst_root, &dst_path)?;
+    ensure_not_overwriting(&dst_path, context.options)?;
+    write_generated(&dst_path, &contents, context.options)?;
+    build.wrote(&dst_path);
+    build.stats.lock().unwrap().files_copied += 1;
+
+    let mut url = String::new();
+    for segment in dir {
+        url += "/";
+        url += segment;
+    }
+    url += "/";
+    url += &file_name;
+    hashed_assets.insert(source_path.to_path_buf(), url.clone());
+    Ok(url)
+}
+
+// A hash of a file's contents to put into its name. Unlike std's hashers,
+// FNV-1a gives the same hash in every build and on every platform.
+fn content_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+// A url leading from the page served at the root-relative url `from` to the
+// root-relative url `to`, such as "../images/logo.png" from "/blog/post.html"
+// to "/images/logo.png"
+fn relative_url(from: &str, to: &str) -> String {
+    let from_dir: Vec<&str> = from[..from.rfind('/').unwrap_or(0)]
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let to_segments: Vec<&str> = to
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let (to_dir, file_name) = to_segments.split_at(to_segments.len().saturating_sub(1));
+    let common = from_dir
+        .iter()
+        .zip(to_dir)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut url = "../".repeat(from_dir.len() - common);
+    for segment in &to_dir[common..] {
+        url += segment;
+        url.push('/');
+    }
+    url += file_name.first().copied().unwrap_or("");
+    url
+}
+
+// Replace every `<raw>` element with its contents, which were left alone by
+// expansion and are also left alone by minification
+fn unwrap_raw(xot: &mut Xot, document: xot::Node, context: &Context) -> Result<(), xot::Error> {
+    let Some(raw_name) = xot.name("raw") else {
+        return Ok(());
+    };
+    // A <raw> inside of another is part of the outer one's contents
+    let raws: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| xot.node_name(*node) == Some(raw_name))
+        .filter(|node| {
+            xot.ancestors(*node)
+                .skip(1)
+                .all(|ancestor| xot.node_name(ancestor) != Some(raw_name))
+        })
+        .collect();
+    let mut state = context.document.borrow_mut();
+    for raw in raws {
+        let children: Vec<xot::Node> = xot.children(raw).collect();
+        for child in children {
+            xot.insert_before(raw, child)?;
+            state.preformatted_nodes.insert(child);
+        }
+        xot.remove(raw)?;
+    }
+    Ok(())
+}
+
+// Remove everything between `<!-- #name -->` and `<!-- #endname -->` comments
+// with the same parent, unless building for the environment of that name, in
+// which case only the comments themselves are removed
+fn strip_env_regions(
+    xot: &mut Xot,
+    document: xot::Node,
+    env: &str,
+    context: &Context,
+) -> Result<(), xot::Error> {
+    let marker = |xot: &Xot, node: xot::Node| {
+        let text = xot.comment(node)?.get().trim();
+        let name = text.strip_prefix('#')?;
+        let is_name = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        (!name.is_empty() && name.chars().all(is_name)).then(|| name.to_string())
+    };
+    let starts: Vec<(xot::Node, String)> = xot
+        .descendants(document)
+        .filter_map(|node| Some((node, marker(xot, node)?)))
+        .filter(|(_, name)| !name.starts_with("end"))
+        .collect();
+
+    // Going backwards, regions inside of others are dealt with before the
+    // nodes containing them can be removed
+    for (start, name) in starts.into_iter().rev() {
+        let end_name = format!("end{}", name);
+        let mut region = vec![start];
+        let mut end = None;
+        let mut next = xot.next_sibling(start);
+        while let Some(node) = next {
+            region.push(node);
+            if marker(xot, node).as_deref() == Some(end_name.as_str()) {
+                end = Some(node);
+                break;
+            }
+            next = xot.next_sibling(node);
+        }
+        let Some(end) = end else {
+            context.warn(&format!(
+                "<!-- #{} --> has no matching <!-- #{} --> after it",
+                name, end_name
+            ));
+            continue;
+        };
+        if name == env {
+            xot.remove(start)?;
+            xot.remove(end)?;
+        } else {
+            for node in region {
+                xot.remove(node)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Color the text of every <code lang="..."> element according to the syntax
+// of that language, by wrapping its tokens in <span> elements with inline
+// styles, so that pages don't need any scripts or stylesheets for it
+fn highlight_code(xot: &mut Xot, document: xot::Node, context: &Context) -> Result<(), xot::Error> {
+    let code_name = xot.add_name("code");
+    let lang_name = xot.add_name("lang");
+    let blocks: Vec<(xot::Node, String)> = xot
+        .descendants(document)
+        .filter(|node| xot.node_name(*node) == Some(code_name))
+        .filter_map(|node| Some((node, xot.attributes(node).get(lang_name)?.clone())))
+        .collect();
+    if blocks.is_empty() {
+        return Ok(());
+    }
+
+    let (syntaxes, theme) = context.build.highlighter.get_or_init(|| {
+        let themes = syntect::highlighting::ThemeSet::load_defaults();
+        (
+            syntect::parsing::SyntaxSet::load_defaults_newlines(),
+            themes.themes[HIGHLIGHT_THEME].clone(),
+        )
+    });
+    let span_name = xot.add_name("span");
+    let style_name = xot.add_name("style");
+
+    for (code, lang) in blocks {
+        // Only plain text can be highlighted, not markup
+        if xot.children(code).any(|child| xot.text(child).is_none()) {
+            continue;
+        }
+        let Some(syntax) = syntaxes.find_syntax_by_token(&lang) else {
+            context.warn(&format!(
+                "not highlighting <code lang=\"{}\"> because the language is unknown",
+                lang
+            ));
+            continue;
+        };
+        let text: String = xot
+            .children(code)
+            .filter_map(|child| xot.text(child).map(|t| t.get().to_string()))
+            .collect();
+
+        let mut highlighter = syntect::easy::HighlightLines::new(syntax, theme);
+        let mut tokens = Vec::new();
+        for line in syntect::util::LinesWithEndings::from(&text) {
+            match highlighter.highlight_line(line, syntaxes) {
+                Ok(regions) => {
+                    for (style, token) in regions {
+                        // Neighbouring tokens of the same style share a span
+                        match tokens.last_mut() {
+                            Some((last_style, last_token)) if *last_style == style => {
+                                *last_token += token
+                            }
+                            _ => tokens.push((style, token.to_string())),
+                        }
+                    }
+                }
+                Err(err) => {
+                    context.warn(&format!(
+                        "failed to highlight <code lang=\"{}\">: {}",
+                        lang, err
+                    ));
+                    tokens.clear();
+                    break;
+                }
+            }
+        }
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let children: Vec<xot::Node> = xot.children(code).collect();
+        for child in children {
+            xot.remove(child)?;
+        }
+        for (style, token) in tokens {
+            let text = xot.new_text(&token);
+            if token.trim().is_empty() {
+                xot.append(code, text)?;
+                continue;
+            }
+            let color = style.foreground;
+            let mut css = format!("color:#{:02x}{:02x}{:02x}", color.r, color.g, color.b);
+            if style
+                .font_style
+                .contains(syntect::highlighting::FontStyle::BOLD)
+            {
+                css += ";font-weight:bold";
+            }
+            if style
+                .font_style
+                .contains(syntect::highlighting::FontStyle::ITALIC)
+            {
+                css += ";font-style:italic";
+            }
+            let span = xot.new_element(span_name);
+            xot.attributes_mut(span).insert(style_name, css);
+            xot.append(span, text)?;
+            xot.append(code, span)?;
+        }
+        context
+            .document
+            .borrow_mut()
+            .preformatted_nodes
+            .insert(code);
+    }
+    Ok(())
+}
+
+// Name of the color scheme used for highlighting code, one of those that
+// come with syntect
+const HIGHLIGHT_THEME: &str = "InspiredGitHub";
+
+// Remove every <script> and <style> element that is identical to an earlier
+// one, optionally moving the ones that are kept to the end of the <head>
+fn dedupe_assets(
+    xot: &mut Xot,
+    document: xot::Node,
+    hoist: bool,
+    context: &Context,
+) -> Result<(), BuildError> {
+    let script = xot.add_name("script");
+    let style = xot.add_name("style");
+    let assets: Vec<xot::Node> = xot
+        .descendants(document)
+        .filter(|node| {
+            let name = xot.node_name(*node);
+            name == Some(script) || name == Some(style)
+        })
+        .collect();
+
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut kept: Vec<xot::Node> = Vec::new();
+    for asset in assets {
+        if seen.insert(xot.to_string(asset)?) {
+            kept.push(asset);
+        } else {
+            xot.remove(asset)?;
+        }
+    }
+
+    if !hoist || kept.is_empty() {
+        return Ok(());
+    }
+    let Some(head) = find_element(xot, document, "head") else {
+        context.warn("can't move scripts and styles because the page has no <head>");
+        return Ok(());
+    };
+    for asset in kept {
+        if !xot.ancestors(asset).any(|ancestor| ancestor == head) {
+            xot.append(head, asset)?;
+        }
+    }
+    Ok(())
+}
+
+/// Name of the stylesheet in the root of the destination directory that
+/// holds the scoped styles of all elements used in the build
+pub const COMPONENT_STYLESHEET: &str = "components.css";
+
+// Link the stylesheet holding the elements' scoped styles from the <head>
+fn link_component_styles(xot: &mut Xot, document: xot::Node, context: &Context) {
+    let Some(head) = find_element(xot, document, "head") else {
+        context.warn(&format!(
+            "can't link {} because the page has no <head>",
+            COMPONENT_STYLESHEET
+        ));
+        return;
+    };
+    let link_name = xot.add_name("link");
+    let link = xot.new_element(link_name);
+    let rel = xot.add_name("rel");
+    let href = xot.add_name("href");
+    xot.attributes_mut(link)
+        .insert(rel, "stylesheet".to_string());
+    xot.attributes_mut(link)
+        .insert(href, format!("/{}", COMPONENT_STYLESHEET));
+    xot.append(head, link).unwrap();
+}
+
+// Replace every character outside of ASCII with a numeric character reference
+fn escape_non_ascii(html: &str) -> String {
+    let mut escaped = String::with_capacity(html.len());
+    for c in html.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            escaped += &format!("&#x{:X};", c as u32);
+        }
+    }
+    escaped
+}
+
+// Rewrite the double-quoted attribute values of all start tags in serialized
+// html to use the given style of quotes instead
+fn requote_attributes(html: &str, quotes: AttributeQuotes) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        out += &rest[..start];
+        rest = &rest[start..];
+
+        // Copy comments, doctypes, and end tags as they are
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map_or(rest.len(), |i| i + 3);
+            out += &rest[..end];
+            rest = &rest[end..];
+            continue;
+        }
+        if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            out.push('<');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let name_end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let tag_name = rest[1..name_end].to_ascii_lowercase();
+        out += &rest[..name_end];
+        rest = &rest[name_end..];
+
+        // Attributes, up to the end of the tag
+        while let Some(c) = rest.chars().next() {
+            if c == '>' {
+                out.push('>');
+                rest = &rest[1..];
+                break;
+            }
+            if c.is_ascii_whitespace() || c == '/' {
+                out.push(c);
+                rest = &rest[1..];
+                continue;
+            }
+
+            let attr_end = rest
+                .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '>')
+                .unwrap_or(rest.len());
+            out += &rest[..attr_end];
+            rest = &rest[attr_end..];
+            let Some(quoted) = rest.strip_prefix("=\"") else {
+                continue;
+            };
+            let value_end = quoted.find('"').unwrap_or(quoted.len());
+            let value = &quoted[..value_end];
+            rest = quoted.get(value_end + 1..).unwrap_or("");
+
+            out.push('=');
+            match quotes {
+                AttributeQuotes::Double => {
+                    out.push('"');
+                    out += value;
+                    out.push('"');
+                }
+                AttributeQuotes::Single => {
+                    out.push('\'');
+                    out += &value.replace('\'', "&#39;").replace("&quot;", "\"");
+                    out.push('\'');
+                }
+                AttributeQuotes::Minimal => {
+                    let needs_quotes = value.is_empty()
+                        || value
+                            .contains(|c: char| c.is_ascii_whitespace() || "\"'=<>`".contains(c));
+                    if needs_quotes {
+                        out.push('"');
+                        out += value;
+                        out.push('"');
+                    } else {
+                        out += value;
+                    }
+                }
+            }
+        }
+
+        // The contents of these may contain anything that looks like a tag
+        if tag_name == "script" || tag_name == "style" {
+            let end_tag = format!("</{}", tag_name);
+            let end = rest
+                .to_ascii_lowercase()
+                .find(&end_tag)
+                .unwrap_or(rest.len());
+            out += &rest[..end];
+            rest = &rest[end..];
+        }
+    }
+    out += rest;
+
+    out
+}
+
+fn clean_folder(path: &std::path::Path, build: &Build) -> Result<(), io::Error> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_name().to_str().unwrap().starts_with(".") {
+            build.info(&format!(
+                "Not deleting \"{}\" at \"{}\"",
+                entry.file_name().to_str().unwrap(),
+                path.display()
+            ));
+            continue;
+        }
+        let entry_type = entry.file_type()?;
+        if entry_type.is_file() {
+            fs::remove_file(entry.path())?;
+        } else if entry_type.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+// Delete everything in a directory of the destination that the build didn't
+// write, except for files and directories starting with '.', along with any
+// directories this leaves empty. Returns whether the directory is empty now.
+fn sync_folder(
+    path: &path::Path,
+    written: &HashSet<path::PathBuf>,
+    build: &Build,
+) -> Result<bool, io::Error> {
+    let mut empty = true;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            empty = false;
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            if sync_folder(&entry_path, written, build)? && !written.contains(&entry_path) {
+                build.info(&format!(
+                    "Deleting orphaned directory \"{}\"",
+                    entry_path.display()
+                ));
+                fs::remove_dir(&entry_path)?;
+            } else {
+                empty = false;
+            }
+        } else if written.contains(&entry_path) {
+            empty = false;
+        } else {
+            build.info(&format!(
+                "Deleting orphaned file \"{}\"",
+                entry_path.display()
+            ));
+            fs::remove_file(&entry_path)?;
+        }
+    }
+    Ok(empty)
+}
+
+/// Settings that control how a site is generated
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Follow symbolic links in the source directory instead of skipping them
+    pub follow_symlinks: bool,
+    /// Maximum number of pages generated in parallel, or None to use
+    /// one thread per logical CPU
+    pub jobs: Option<usize>,
+    /// Only print errors
+    pub quiet: bool,
+    /// Give all headings an id based on their text
+    pub auto_ids: bool,
+    /// Verify that internal links point at generated pages
+    pub check_links: bool,
+    /// Remove comments and collapse whitespace in generated pages
+    pub minify: bool,
+    /// When minifying, collapse text that is all whitespace into a single
+    /// space rather than removing it, for pages where that space matters
+    /// between elements that aren't known to be inline
+    pub keep_whitespace_text: bool,
+    /// Attributes whose values are combined rather than replaced when an
+    /// element forwards its attributes with `self.rest`, or when a child is
+    /// put in place of `<x>` inside of `<foreachchild.x>`. The values of
+    /// "style" are separated by a ';', any others by a space.
+    pub merge_attributes: Vec<String>,
+    /// Surround the markup produced by each element with comments naming
+    /// the element. Has no effect when minifying.
+    pub trace_components: bool,
+    /// How to write characters outside of ASCII
+    pub entities: Entities,
+    /// How to quote attribute values. Only applies to html, since XML
+    /// always needs quotes.
+    pub attribute_quotes: AttributeQuotes,
+    /// Whether pages are written as html, XHTML, or XML
+    pub serialization: Serialization,
+    /// What to write in the doctype at the start of every page instead of
+    /// the one that goes with `serialization`, such as
+    /// `html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "..."`
+    pub doctype: Option<String>,
+    /// Write a `.map` file of JSON next to every page, telling which parts
+    /// of it came out of which elements
+    pub source_maps: bool,
+    /// Name of the environment being built for, such as "production",
+    /// available to elements as 'build.env'
+    pub env: Option<String>,
+    /// Directory of .json and .toml data files, available to elements
+    /// by file name
+    pub data: Option<path::PathBuf>,
+    /// Local names of elements whose text is written as CDATA sections,
+    /// such as "script" for scripts inside of inline SVG. None by default,
+    /// since html only allows CDATA in SVG and MathML.
+    pub cdata_elements: Vec<String>,
+    /// Fail the build on references to missing attributes or unknown
+    /// expressions instead of warning about them
+    pub strict: bool,
+    /// Keep only the first of any identical `<script>` or `<style>`
+    /// elements on a page, such as those added by every use of an element
+    pub dedupe_assets: bool,
+    /// Move the `<script>` and `<style>` elements kept by `dedupe_assets`
+    /// into the page's `<head>`
+    pub hoist_assets: bool,
+    /// Delete everything in the destination directory before generating
+    /// into it, except for files and directories starting with '.'
+    pub clean: bool,
+    /// What happens when several element directories define an element
+    /// of the same name
+    pub element_overrides: ElementOverrides,
+    /// Extensions of XML files, such as "svg", which are parsed, minified
+    /// if `minify` is set, and serialized again instead of being copied
+    pub process_extensions: Vec<String>,
+    /// Write pages other than index.html to a directory of their own, as
+    /// `about/index.html` instead of `about.html`, to be served at `/about/`
+    pub pretty_urls: bool,
+    /// With `pretty_urls`, also write a page to where each page would be
+    /// without them, like `about.html`, which redirects to the actual page
+    pub redirect_stubs: bool,
+    /// Highlight the syntax of the text in `<code lang="...">` elements
+    pub highlight: bool,
+    /// Stop at the first page that fails to generate, rather than generating
+    /// all other pages and reporting every failure at the end
+    pub fail_fast: bool,
+    /// Extension to give generated pages instead of "html", such as "php"
+    /// for pages that are processed further by the server. Other files keep
+    /// their extensions.
+    pub output_extension: Option<String>,
+    /// Print how many pages have been generated so far, and which, to
+    /// standard error
+    pub progress: bool,
+    /// Instead of emptying the destination directory before generating into
+    /// it, delete whatever in it the build didn't write afterwards, such as
+    /// the outputs of pages whose sources were deleted. Files and directories
+    /// starting with '.' are kept either way.
+    pub sync: bool,
+    /// Most elements expanded on a single page, after which the build fails.
+    /// Only pages where an element keeps expanding into itself should ever
+    /// get there.
+    pub max_expansions: usize,
+    /// File name of the page served for a directory, such as "default.html"
+    /// for hosts that look for that, or None for "index" with the page
+    /// extension. Pages named index.html are written under this name, and so
+    /// are pages written to directories of their own with `pretty_urls`.
+    pub index_name: Option<String>,
+    /// Version of the site being built, such as "1.2.3", available to
+    /// elements as 'build.version'
+    pub version: Option<String>,
+    /// Directory or file relative to the source directory, such as "blog",
+    /// outside of which nothing is generated. The destination directory is
+    /// neither emptied nor synced, so that the rest of the site stays as
+    /// it is.
+    pub only: Option<path::PathBuf>,
+    /// Fail instead of writing any page or file where one already exists in
+    /// the destination, such as when generating several source directories
+    /// into the same destination, which must not collide. Implies not
+    /// cleaning the destination first.
+    pub no_overwrite: bool,
+    /// Directories relative to the source directory, such as "static",
+    /// which are copied as they are, html files and all
+    pub passthrough: Vec<path::PathBuf>,
+    /// Write pages and copied files under lowercase names, such as
+    /// "logo.png" for "Logo.PNG"
+    pub lowercase_names: bool,
+    /// Remove this from the end of the names of pages and copied files,
+    /// before the extension, such as "_src" to write "logo_src.png" as
+    /// "logo.png"
+    pub strip_name_suffix: Option<String>,
+}
+
+impl Options {
+    // Extension of generated pages, without a leading '.'
+    fn page_extension(&self) -> &str {
+        self.output_extension
+            .as_deref()
+            .map_or("html", |extension| extension.trim_start_matches('.'))
+    }
+
+    // File name of the page served for a directory
+    fn index_file_name(&self) -> String {
+        match &self.index_name {
+            Some(index_name) => index_name.clone(),
+            None => format!("index.{}", self.page_extension()),
+        }
+    }
+
+    // Whether two values of an attribute are combined rather than one
+    // replacing the other
+    fn merges_attribute(&self, name: &str) -> bool {
+        self.merge_attributes.iter().any(|merged| merged == name)
+    }
+
+    // Whether the destination directory is emptied before generating into it.
+    // Emptying it would defeat `no_overwrite`, so that implies not cleaning.
+    fn cleans_first(&self) -> bool {
+        self.clean && !self.sync && !self.no_overwrite && self.only.is_none()
+    }
+}
+
+/// What happens when an element directory defines an element that an
+/// earlier element directory already defined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ElementOverrides {
+    /// Fail to load the library
+    #[default]
+    Error,
+    /// Use the definition from the later directory
+    Replace,
+}
+
+impl std::str::FromStr for ElementOverrides {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ElementOverrides, String> {
+        match s {
+            "error" => Ok(ElementOverrides::Error),
+            "replace" => Ok(ElementOverrides::Replace),
+            _ => Err(format!(
+                "unknown element overrides \"{}\", expected \"error\" or \"replace\"",
+                s
+            )),
+        }
+    }
+}
+
+/// How attribute values are quoted in generated pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttributeQuotes {
+    /// `class="a"`
+    #[default]
+    Double,
+    /// `class='a'`
+    Single,
+    /// `class=a`, falling back to double quotes for values which
+    /// can't be written without them
+    Minimal,
+}
+
+impl std::str::FromStr for AttributeQuotes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AttributeQuotes, String> {
+        match s {
+            "double" => Ok(AttributeQuotes::Double),
+            "single" => Ok(AttributeQuotes::Single),
+            "minimal" => Ok(AttributeQuotes::Minimal),
+            _ => Err(format!(
+                "unknown attribute quotes \"{}\", expected \"double\", \"single\", or \"minimal\"",
+                s
+            )),
+        }
+    }
+}
+
+/// How generated pages are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Serialization {
+    /// As html, where elements like `<br>` have no end tag
+    #[default]
+    Html5,
+    /// As XML that browsers also accept as html, where elements like `<br/>`
+    /// close themselves and all others have an end tag, in the XHTML
+    /// namespace, with scripts and stylesheets as CDATA sections
+    Xhtml,
+    /// As XML, starting with an XML declaration, where every empty element
+    /// closes itself
+    Xml,
+}
+
+impl std::str::FromStr for Serialization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Serialization, String> {
+        match s {
+            "html5" | "html" => Ok(Serialization::Html5),
+            "xhtml" => Ok(Serialization::Xhtml),
+            "xml" => Ok(Serialization::Xml),
+            _ => Err(format!(
+                "unknown serialization \"{}\", expected \"html5\", \"xhtml\", or \"xml\"",
+                s
+            )),
+        }
+    }
+}
+
+/// How characters outside of ASCII are written to generated pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Entities {
+    /// Write them as they are, encoded as UTF-8. Characters which html
+    /// requires to be escaped, like non-breaking spaces, are still escaped.
+    #[default]
+    Utf8,
+    /// Write them as numeric character references like `&#x2014;`, so that
+    /// pages survive being served with the wrong encoding. Note that this
+    /// also affects the contents of `<script>` and `<style>` elements, where
+    /// character references have no meaning.
+    Ascii,
+}
+
+impl std::str::FromStr for Entities {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Entities, String> {
+        match s {
+            "utf8" | "utf-8" => Ok(Entities::Utf8),
+            "ascii" => Ok(Entities::Ascii),
+            _ => Err(format!(
+                "unknown entity handling \"{}\", expected \"utf8\" or \"ascii\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            follow_symlinks: false,
+            jobs: None,
+            quiet: false,
+            auto_ids: false,
+            check_links: false,
+            minify: true,
+            keep_whitespace_text: false,
+            merge_attributes: vec!["class".to_string(), "style".to_string()],
+            trace_components: false,
+            entities: Entities::Utf8,
+            attribute_quotes: AttributeQuotes::Double,
+            serialization: Serialization::Html5,
+            doctype: None,
+            source_maps: false,
+            env: None,
+            data: None,
+            cdata_elements: Vec::new(),
+            strict: false,
+            dedupe_assets: false,
+            hoist_assets: false,
+            clean: true,
+            element_overrides: ElementOverrides::Error,
+            process_extensions: Vec::new(),
+            pretty_urls: false,
+            redirect_stubs: false,
+            highlight: false,
+            fail_fast: true,
+            output_extension: None,
+            progress: false,
+            sync: false,
+            max_expansions: 100_000,
+            index_name: None,
+            version: None,
+            only: None,
+            no_overwrite: false,
+            passthrough: Vec::new(),
+            lowercase_names: false,
+            strip_name_suffix: None,
+        }
+    }
+}
+
+/// The contents of a `baumkuchen.toml` file. Anything left out is
+/// taken from the command line or the defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub source: Option<path::PathBuf>,
+    /// One directory, or a list of them
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub elements: Option<Vec<path::PathBuf>>,
+    pub destination: Option<path::PathBuf>,
+    pub follow_symlinks: Option<bool>,
+    pub jobs: Option<usize>,
+    pub quiet: Option<bool>,
+    pub auto_ids: Option<bool>,
+    pub check_links: Option<bool>,
+    pub minify: Option<bool>,
+    pub keep_whitespace_text: Option<bool>,
+    pub merge_attributes: Option<Vec<String>>,
+    pub trace_components: Option<bool>,
+    pub entities: Option<Entities>,
+    pub attribute_quotes: Option<AttributeQuotes>,
+    pub serialization: Option<Serialization>,
+    pub doctype: Option<String>,
+    pub source_maps: Option<bool>,
+    pub env: Option<String>,
+    pub data: Option<path::PathBuf>,
+    pub cdata_elements: Option<Vec<String>>,
+    pub strict: Option<bool>,
+    pub dedupe_assets: Option<bool>,
+    pub hoist_assets: Option<bool>,
+    pub clean: Option<bool>,
+    pub element_overrides: Option<ElementOverrides>,
+    pub process_extensions: Option<Vec<String>>,
+    pub pretty_urls: Option<bool>,
+    pub redirect_stubs: Option<bool>,
+    pub highlight: Option<bool>,
+    pub fail_fast: Option<bool>,
+    pub output_extension: Option<String>,
+    pub progress: Option<bool>,
+    pub sync: Option<bool>,
+    pub no_overwrite: Option<bool>,
+    /// Relative to the source directory rather than to the config file
+    pub passthrough: Option<Vec<path::PathBuf>>,
+    pub lowercase_names: Option<bool>,
+    pub strip_name_suffix: Option<String>,
+    pub max_expansions: Option<usize>,
+    pub index_name: Option<String>,
+    pub version: Option<String>,
+    pub report_unused: Option<bool>,
+    /// Shell command to run after a successful build
+    pub post_build: Option<String>,
+}
+
+impl Config {
+    /// Name of the config file looked for in the working directory
+    pub const FILE_NAME: &'static str = "baumkuchen.toml";
+
+    /// Read a config file. Relative paths in it are taken to be relative
+    /// to the directory containing the file.
+    pub fn from_file(path: &path::Path) -> Result<Config, BuildError> {
+        let text = read_source(path)?;
+        let mut config = Config::parse(&text).map_err(|message| BuildError::Parse {
+            path: path.to_path_buf(),
+            message,
+        })?;
+
+        let base = path.parent().unwrap_or(path::Path::new(""));
+        for dir in [
+            &mut config.source,
+            &mut config.destination,
+            &mut config.data,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *dir = base.join(&dir);
+        }
+        for dir in config.elements.iter_mut().flatten() {
+            *dir = base.join(&dir);
+        }
+
+        Ok(config)
+    }
+
+    /// Parse the contents of a config file
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|err| err.message().to_string())
+    }
+
+    /// Name of the config files that apply to a single directory of the
+    /// source tree and everything below it
+    pub const DIRECTORY_FILE_NAME: &'static str = ".baumkuchen.toml";
+
+    /// Names of any settings in the config that only make sense for a
+    /// whole build, and so are ignored in per-directory config files
+    pub fn build_wide_settings(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.source.is_some() {
+            names.push("source");
+        }
+        if self.elements.is_some() {
+            names.push("elements");
+        }
+        if self.destination.is_some() {
+            names.push("destination");
+        }
+        if self.jobs.is_some() {
+            names.push("jobs");
+        }
+        if self.quiet.is_some() {
+            names.push("quiet");
+        }
+        if self.check_links.is_some() {
+            names.push("check-links");
+        }
+        if self.clean.is_some() {
+            names.push("clean");
+        }
+        if self.element_overrides.is_some() {
+            names.push("element-overrides");
+        }
+        if self.fail_fast.is_some() {
+            names.push("fail-fast");
+        }
+        if self.output_extension.is_some() {
+            names.push("output-extension");
+        }
+        if self.progress.is_some() {
+            names.push("progress");
+        }
+        if self.index_name.is_some() {
+            names.push("index-name");
+        }
+        if self.sync.is_some() {
+            names.push("sync");
+        }
+        if self.no_overwrite.is_some() {
+            names.push("no-overwrite");
+        }
+        if self.passthrough.is_some() {
+            names.push("passthrough");
+        }
+        if self.lowercase_names.is_some() {
+            names.push("lowercase-names");
+        }
+        if self.strip_name_suffix.is_some() {
+            names.push("strip-name-suffix");
+        }
+        if self.report_unused.is_some() {
+            names.push("report-unused");
+        }
+        if self.post_build.is_some() {
+            names.push("post-build");
+        }
+        if self.env.is_some() {
+            names.push("env");
+        }
+        if self.version.is_some() {
+            names.push("version");
+        }
+        if self.data.is_some() {
+            names.push("data");
+        }
+        names
+    }
+
+    /// Overwrite any options that the config sets
+    pub fn apply(&self, options: &mut Options) {
+        if let Some(follow_symlinks) = self.follow_symlinks {
+            options.follow_symlinks = follow_symlinks;
+        }
+        if let Some(jobs) = self.jobs {
+            options.jobs = Some(jobs);
+        }
+        if let Some(quiet) = self.quiet {
+            options.quiet = quiet;
+        }
+        if let Some(auto_ids) = self.auto_ids {
+            options.auto_ids = auto_ids;
+        }
+        if let Some(check_links) = self.check_links {
+            options.check_links = check_links;
+        }
+        if let Some(minify) = self.minify {
+            options.minify = minify;
+        }
+        if let Some(keep_whitespace_text) = self.keep_whitespace_text {
+            options.keep_whitespace_text = keep_whitespace_text;
+        }
+        if let Some(merge_attributes) = &self.merge_attributes {
+            options.merge_attributes = merge_attributes.clone();
+        }
+        if let Some(trace_components) = self.trace_components {
+            options.trace_components = trace_components;
+        }
+        if let Some(entities) = self.entities {
+            options.entities = entities;
+        }
+        if let Some(attribute_quotes) = self.attribute_quotes {
+            options.attribute_quotes = attribute_quotes;
+        }
+        if let Some(serialization) = self.serialization {
+            options.serialization = serialization;
+        }
+        if self.doctype.is_some() {
+            options.doctype = self.doctype.clone();
+        }
+        if let Some(source_maps) = self.source_maps {
+            options.source_maps = source_maps;
+        }
+        if let Some(env) = &self.env {
+            options.env = Some(env.clone());
+        }
+        if let Some(data) = &self.data {
+            options.data = Some(data.clone());
+        }
+        if let Some(cdata_elements) = &self.cdata_elements {
+            options.cdata_elements = cdata_elements.clone();
+        }
+        if let Some(strict) = self.strict {
+            options.strict = strict;
+        }
+        if let Some(dedupe_assets) = self.dedupe_assets {
+            options.dedupe_assets = dedupe_assets;
+        }
+        if let Some(hoist_assets) = self.hoist_assets {
+            options.hoist_assets = hoist_assets;
+        }
+        if let Some(clean) = self.clean {
+            options.clean = clean;
+        }
+        if let Some(element_overrides) = self.element_overrides {
+            options.element_overrides = element_overrides;
+        }
+        if let Some(process_extensions) = &self.process_extensions {
+            options.process_extensions = process_extensions.clone();
+        }
+        if let Some(pretty_urls) = self.pretty_urls {
+            options.pretty_urls = pretty_urls;
+        }
+        if let Some(redirect_stubs) = self.redirect_stubs {
+            options.redirect_stubs = redirect_stubs;
+        }
+        if let Some(highlight) = self.highlight {
+            options.highlight = highlight;
+        }
+        if let Some(fail_fast) = self.fail_fast {
+            options.fail_fast = fail_fast;
+        }
+        if let Some(output_extension) = &self.output_extension {
+            options.output_extension = Some(output_extension.clone());
+        }
+        if let Some(progress) = self.progress {
+            options.progress = progress;
+        }
+        if let Some(sync) = self.sync {
+            options.sync = sync;
+        }
+        if let Some(no_overwrite) = self.no_overwrite {
+            options.no_overwrite = no_overwrite;
+        }
+        if let Some(passthrough) = &self.passthrough {
+            options.passthrough = passthrough.clone();
+        }
+        if let Some(lowercase_names) = self.lowercase_names {
+            options.lowercase_names = lowercase_names;
+        }
+        if self.strip_name_suffix.is_some() {
+            options.strip_name_suffix = self.strip_name_suffix.clone();
+        }
+        if let Some(max_expansions) = self.max_expansions {
+            options.max_expansions = max_expansions;
+        }
+        if let Some(index_name) = &self.index_name {
+            options.index_name = Some(index_name.clone());
+        }
+        if let Some(version) = &self.version {
+            options.version = Some(version.clone());
+        }
+    }
+}
+
+// Accept either a single value or a list of them in config files
+fn one_or_many<'de, D>(deserializer: D) -> Result<Option<Vec<path::PathBuf>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(path::PathBuf),
+        Many(Vec<path::PathBuf>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => Some(vec![one]),
+        OneOrMany::Many(many) => Some(many),
+    })
+}
+
+/// Everything about a build that is shared between all pages
+pub struct Build {
+    source_root: path::PathBuf,
+    elements_paths: Vec<path::PathBuf>,
+    dst_root: path::PathBuf,
+    options: Options,
+    // names of all elements that were instantiated at least once, across
+    // all pages and worker threads
+    used_elements: Mutex<HashSet<String>>,
+    // all root-relative links found in generated pages, as pairs of the
+    // linking page's path and the link, if checking links
+    links: Mutex<Vec<(String, String)>>,
+    // root-relative paths of all pages written so far
+    outputs: Mutex<HashSet<String>>,
+    // every file and directory in the destination that was written or
+    // created, if syncing
+    written: Mutex<HashSet<path::PathBuf>>,
+    // root-relative urls of the files copied under hashed names for
+    // <asset hash="">, by the path of their source file
+    hashed_assets: Mutex<HashMap<path::PathBuf, String>>,
+    // required attributes left out of invocations, disallowed attribute
+    // values, and pages that expanded too many elements
+    invalid: Mutex<Vec<String>>,
+    // references to missing attributes and unknown expressions, if strict
+    undefined: Mutex<Vec<String>>,
+    // every warning and error reported so far, including those in `invalid`
+    // and `undefined`
+    diagnostics: Mutex<Vec<Diagnostic>>,
+    // the contents of all data files, by file name without extension
+    data: HashMap<String, serde_json::Value>,
+    // counts of what has been done so far
+    stats: Mutex<Stats>,
+    // when the build started, in local time
+    date: chrono::DateTime<chrono::Local>,
+    // handlers for elements implemented by the embedding program, by tag name
+    handlers: HashMap<String, Box<dyn ElementHandler>>,
+    // syntax definitions and color scheme for highlighting code, loaded
+    // the first time they're needed
+    highlighter: OnceLock<(syntect::parsing::SyntaxSet, syntect::highlighting::Theme)>,
+}
+
+/// Numbers describing a finished build
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Pages generated, counting every page of a paginated one
+    pub pages: usize,
+    /// Other files copied from the source directory
+    pub files_copied: usize,
+    /// Elements expanded across all pages, counting every use
+    pub instantiations: usize,
+    /// Wall-clock time taken by the whole build
+    pub duration: time::Duration,
+}
+
+impl Build {
+    /// A build of the source directory into the destination directory using
+    /// the elements in the elements directories. Nothing is read or written
+    /// until pages are generated.
+    pub fn new(
+        source: &path::Path,
+        elements: &[path::PathBuf],
+        destination: &path::Path,
+        options: Options,
+    ) -> Build {
+        Build {
+            source_root: source.to_path_buf(),
+            elements_paths: elements.to_vec(),
+            dst_root: destination.to_path_buf(),
+            options,
+            used_elements: Mutex::new(HashSet::new()),
+            links: Mutex::new(Vec::new()),
+            outputs: Mutex::new(HashSet::new()),
+            written: Mutex::new(HashSet::new()),
+            hashed_assets: Mutex::new(HashMap::new()),
+            invalid: Mutex::new(Vec::new()),
+            undefined: Mutex::new(Vec::new()),
+            diagnostics: Mutex::new(Vec::new()),
+            stats: Mutex::new(Stats::default()),
+            date: chrono::Local::now(),
+            handlers: HashMap::new(),
+            highlighter: OnceLock::new(),
+            data: HashMap::new(),
+        }
+    }
+
+    /// Make data available to elements under the given name, as if it had
+    /// been loaded from a data file of that name
+    pub fn insert_data(&mut self, name: &str, value: serde_json::Value) {
+        self.data.insert(name.to_string(), value);
+    }
+
+    /// Load every .json and .toml file in a directory as data named after
+    /// the file. Two files of the same name, like site.json and site.toml,
+    /// are an error, as is a file named like data that is already there.
+    pub fn load_data(&mut self, path: &path::Path) -> Result<(), BuildError> {
+        let mut loaded: HashMap<String, path::PathBuf> = HashMap::new();
+        for entry in fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            let Some(ext) = entry_path.extension() else {
+                continue;
+            };
+            let text = if ext == "json" || ext == "toml" {
+                read_source(&entry_path)?
+            } else {
+                continue;
+            };
+            let parse_error = |message: String| BuildError::Parse {
+                path: entry_path.clone(),
+                message,
+            };
+            let value = if ext == "json" {
+                serde_json::from_str(&text).map_err(|err| parse_error(err.to_string()))?
+            } else {
+                toml::from_str(&text).map_err(|err| parse_error(err.message().to_string()))?
+            };
+            let name = entry_path
+                .file_stem()
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            if let Some(other) = loaded.get(&name) {
+                return Err(parse_error(format!(
+                    "\"{}\" is already loaded from {}",
+                    name,
+                    other.display()
+                )));
+            }
+            if self.data.contains_key(&name) {
+                return Err(parse_error(format!("\"{}\" is already defined", name)));
+            }
+            loaded.insert(name.clone(), entry_path.clone());
+            self.insert_data(&name, value);
+        }
+        Ok(())
+    }
+
+    // Remember that a file or directory in the destination belongs to the
+    // build, so that syncing doesn't delete it
+    fn wrote(&self, path: &path::Path) {
+        if self.options.sync {
+            self.written.lock().unwrap().insert(path.to_path_buf());
+        }
+    }
+
+    // Print an informational message to standard error unless running
+    // quietly, leaving standard output to whatever embeds the library
+    fn info(&self, message: &str) {
+        if !self.options.quiet {
+            eprintln!("{}", message);
+        }
+    }
+
+    // Print a warning unless running quietly, and record it
+    fn warn(&self, message: &str) {
+        if !self.options.quiet {
+            eprintln!("Warning: {}", message);
+        }
+        self.diagnostics
+            .lock()
+            .unwrap()
+            .push(Diagnostic::warning(None, message.to_string()));
+    }
+
+    // The same for a warning about a particular file, which is printed
+    // after `label`, such as the file's path relative to the source directory
+    fn warn_in(&self, file: &path::Path, label: &str, message: &str) {
+        if !self.options.quiet {
+            eprintln!("Warning: {}: {}", label, message);
+        }
+        self.diagnostics.lock().unwrap().push(Diagnostic::warning(
+            Some(file.display().to_string()),
+            message.to_string(),
+        ));
+    }
+
+    // Record something wrong with a file in `problems`, which fails the build
+    // once all pages are generated
+    fn error_in(
+        &self,
+        problems: &Mutex<Vec<String>>,
+        file: &path::Path,
+        label: &str,
+        message: &str,
+    ) {
+        problems
+            .lock()
+            .unwrap()
+            .push(format!("{}: {}", label, message));
+        self.diagnostics.lock().unwrap().push(Diagnostic::error(
+            Some(file.display().to_string()),
+            message.to_string(),
+        ));
+    }
+}
+
+// An html file from the source directory, to be generated after
+// the rest of the source tree has been copied
+struct Page {
+    source_path: path::PathBuf,
+    dst_path: path::PathBuf,
+    // options in effect for the directory containing the page
+    options: Options,
+}
+
+// Recreate the directory structure of the source directory, copying
+// all non-html files and collecting all html files to be generated.
+// `options` are the options in effect for the parent directory, which
+// a config file in this directory may override.
+fn generate_folder(
+    build: &Build,
+    source_path: &std::path::Path,
+    dst_path: &std::path::Path,
+    options: &Options,
+    ancestors: &mut Vec<path::PathBuf>,
+    pages: &mut Vec<Page>,
+) -> Result<(), BuildError> {
+    if !source_path.is_dir() {
+        return Err(BuildError::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Source path must be a directory: {}", source_path.display()),
+        )));
+    }
+
+    // Refuse to descend into a directory that is already being visited further
+    // up, which can only happen by following a symlink back into the tree
+    let canonical_source_path = source_path.canonicalize()?;
+    if ancestors.contains(&canonical_source_path) {
+        build.warn(&format!(
+            "not following \"{}\" because it links back to one of its parent directories",
+            source_path.display()
+        ));
+        return Ok(());
+    }
+
+    if !dst_path.exists() {
+        if dst_path != build.dst_root {
+            ensure_within_destination(&build.dst_root, dst_path)?;
+        }
+        fs::create_dir(dst_path)?;
+    }
+    build.wrote(dst_path);
+
+    let config_path = source_path.join(Config::DIRECTORY_FILE_NAME);
+    let options = if config_path.is_file() {
+        let config = Config::from_file(&config_path)?;
+        for name in config.build_wide_settings() {
+            build.warn(&format!(
+                "ignoring \"{}\" in \"{}\" because it can only be set for the whole build",
+                name,
+                config_path.display()
+            ));
+        }
+        let mut options = options.clone();
+        config.apply(&mut options);
+        options
+    } else {
+        options.clone()
+    };
+
+    ancestors.push(canonical_source_path);
+
+    for entry in fs::read_dir(source_path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let mut entry_type = entry.file_type()?;
+        let entry_name = entry_path.file_name().unwrap();
+        if entry_name == Config::DIRECTORY_FILE_NAME {
+            continue;
+        }
+        if entry_type.is_symlink() {
+            if !options.follow_symlinks {
+                build.warn(&format!(
+                    "skipping symbolic link \"{}\" (pass --follow-symlinks to follow it)",
+                    entry_path.display()
+                ));
+                continue;
+            }
+            // Look at whatever the link points to instead
+            match fs::metadata(&entry_path) {
+                Ok(metadata) => entry_type = metadata.file_type(),
+                Err(_) => {
+                    build.warn(&format!(
+                        "skipping broken symbolic link \"{}\"",
+                        entry_path.display()
+                    ));
+                    continue;
+                }
+            }
+        }
+        // Skip whatever is neither inside of the part of the source being
+        // generated nor on the way there
+        if let Some(only) = &options.only {
+            let relative = entry_path
+                .strip_prefix(&build.source_root)
+                .unwrap_or(&entry_path);
+            let on_the_way = entry_type.is_dir() && only.starts_with(relative);
+            if !relative.starts_with(only) && !on_the_way {
+                continue;
+            }
+        }
+        let passthrough = entry_type.is_dir()
+            && options.passthrough.iter().any(|dir| {
+                // "./static/" is the same directory as "static"
+                let dir = dir.components().filter(|c| *c != path::Component::CurDir);
+                entry_path
+                    .strip_prefix(&build.source_root)
+                    .is_ok_and(|relative| relative.components().eq(dir))
+            });
+        if passthrough {
+            copy_folder(build, &entry_path, &dst_path.join(entry_name), &options)?;
+        } else if entry_type.is_dir() {
+            generate_folder(
+                build,
+                &entry_path,
+                &dst_path.join(entry_name),
+                &options,
+                ancestors,
+                pages,
+            )?;
+        } else if entry_type.is_file() {
+            if let Some(ext) = entry_path.extension() {
+                if ext == "html" {
+                    let name = output_file_name(&entry_name.to_string_lossy(), &build.options);
+                    let stem = path::Path::new(&name).file_stem().unwrap();
+                    let page_dst_path = page_dst_path(dst_path, stem, &options);
+                    if options.pretty_urls && options.redirect_stubs && stem != "index" {
+                        let stub_path = plain_page_path(dst_path, stem, &options);
+                        write_redirect_stub(build, &stub_path, &page_dst_path, &options)?;
+                    }
+                    pages.push(Page {
+                        source_path: entry_path.clone(),
+                        dst_path: page_dst_path,
+                        options: options.clone(),
+                    });
+                    continue;
+                }
+            }
+
+            let entry_dst_path = dst_path.join(output_file_name(
+                &entry_name.to_string_lossy(),
+                &build.options,
+            ));
+            let process = entry_path.extension().is_some_and(|ext| {
+                options
+                    .process_extensions
+                    .iter()
+                    .any(|e| ext.eq_ignore_ascii_case(e.trim_start_matches('.')))
+            });
+            if process {
+                process_xml_file(build, &entry_path, &entry_dst_path, &options)?;
+                continue;
+            }
+            ensure_within_destination(&build.dst_root, &entry_dst_path)?;
+            ensure_not_overwriting(&entry_dst_path, &options)?;
+            fs::copy(&entry_path, &entry_dst_path)?;
+            build.wrote(&entry_dst_path);
+            build.stats.lock().unwrap().files_copied += 1;
+        }
+    }
+
+    ancestors.pop();
+
+    Ok(())
+}
+
+// Copy a directory of the source listed in `Options::passthrough` and
+// everything in it as it is, without generating any pages
+fn copy_folder(
+    build: &Build,
+    source_path: &path::Path,
+    dst_path: &path::Path,
+    options: &Options,
+) -> Result<(), BuildError> {
+    if !dst_path.exists() {
+        ensure_within_destination(&build.dst_root, dst_path)?;
+        fs::create_dir(dst_path)?;
+    }
+    build.wrote(dst_path);
+
+    for entry in fs::read_dir(source_path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let entry_dst_path = dst_path.join(entry.file_name());
+        let mut entry_type = entry.file_type()?;
+        if entry_type.is_symlink() {
+            if !options.follow_symlinks {
+                build.warn(&format!(
+                    "skipping symbolic link \"{}\" (pass --follow-symlinks to follow it)",
+                    entry_path.display()
+                ));
+                continue;
+            }
+            match fs::metadata(&entry_path) {
+                Ok(metadata) => entry_type = metadata.file_type(),
+                Err(_) => {
+                    build.warn(&format!(
+                        "skipping broken symbolic link \"{}\"",
+                        entry_path.display()
+                    ));
+                    continue;
+                }
+            }
+        }
+        if entry_type.is_dir() {
+            copy_folder(build, &entry_path, &entry_dst_path, options)?;
+        } else if entry_type.is_file() {
+            ensure_within_destination(&build.dst_root, &entry_dst_path)?;
+            ensure_not_overwriting(&entry_dst_path, options)?;
+            fs::copy(&entry_path, &entry_dst_path)?;
+            build.wrote(&entry_dst_path);
+            build.stats.lock().unwrap().files_copied += 1;
+        }
+    }
+    Ok(())
+}
+
+// Write a page at `stub_path` that only redirects to the page at `dst_path`,
+// so that links to where a page was before switching to pretty urls, like
+// `/about.html`, keep working
+fn write_redirect_stub(
+    build: &Build,
+    stub_path: &path::Path,
+    dst_path: &path::Path,
+    options: &Options,
+) -> Result<(), BuildError> {
+    let url = public_url(
+        dst_path.strip_prefix(&build.dst_root).unwrap_or(dst_path),
+        options,
+    );
+    let url = url.replace('&', "&amp;").replace('"', "&quot;");
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Redirecting to {url}</title><meta http-equiv=\"refresh\" content=\"0; url={url}\"><link rel=\"canonical\" href=\"{url}\"></head><body><a href=\"{url}\">{url}</a></body></html>",
+        url = url
+    );
+    ensure_within_destination(&build.dst_root, stub_path)?;
+    ensure_not_overwriting(stub_path, options)?;
+    write_generated(stub_path, html.as_bytes(), options)?;
+    build.wrote(stub_path);
+    if let Ok(relative_path) = stub_path.strip_prefix(&build.dst_root) {
+        build
+            .outputs
+            .lock()
+            .unwrap()
+            .insert(url_path(relative_path));
+    }
+    Ok(())
+}
+
+/// A `Xot` set up the way the substitution functions expect it
+pub fn new_xot() -> Xot {
+    let mut xot = Xot::new();
+
+    // Disable text consolidation (merging of text nodes while modifying)
+    // because it wreacks havoc when modifying nodes while iterating.
+    // See https://github.com/faassen/xot/issues/25
+    xot.set_text_consolidation(false);
+
+    xot
+}
+
+fn generate_pages(
+    xot: &mut Xot,
+    library: &ElementLibrary,
+    build: &Build,
+    pages: &[Page],
+) -> Result<(), BuildError> {
+    let fail_fast = build.options.fail_fast;
+    let progress = Progress::new(pages.len());
+    let generate_page = |xot: &mut Xot, page: &Page, library: &ElementLibrary| {
+        let result = generate_file(xot, build, page, library);
+        if build.options.progress {
+            progress.page_done(build, page);
+        }
+        result
+    };
+
+    if build.options.jobs == Some(1) {
+        let mut errors = Vec::new();
+        for page in pages {
+            if let Err(err) = generate_page(xot, page, library) {
+                if fail_fast {
+                    return Err(err);
+                }
+                errors.push(page_error(page, err));
+            }
+        }
+        return collect_errors(errors);
+    }
+
+    let mut pool_builder = rayon::ThreadPoolBuilder::new();
+    if let Some(jobs) = build.options.jobs {
+        pool_builder = pool_builder.num_threads(jobs);
+    }
+    let pool = pool_builder.build().map_err(io::Error::other)?;
+
+    // The library only fails to load again if its files changed since it was
+    // first loaded, in which case the build fails with the first such error
+    let library_error: Mutex<Option<BuildError>> = Mutex::new(None);
+    let init = || {
+        // Nodes can't be shared between different Xot instances, so
+        // every worker thread loads its own copy of the library
+        let mut xot = new_xot();
+        let library = ElementLibrary::from_folders(
+            &mut xot,
+            &build.elements_paths,
+            build.options.element_overrides,
+        )
+        .map_err(|err| {
+            library_error.lock().unwrap().get_or_insert(err);
+        })
+        .ok();
+        (xot, library)
+    };
+    let generate = |(xot, library): &mut (Xot, Option<ElementLibrary>), page: &Page| {
+        let Some(library) = library else {
+            // Stands in for the error in `library_error`, which replaces it
+            return Err(BuildError::Io(io::Error::other(
+                "failed to load the element library",
+            )));
+        };
+        generate_page(xot, page, library)
+    };
+
+    let result = if fail_fast {
+        pool.install(|| pages.par_iter().try_for_each_init(init, generate))
+    } else {
+        // Otherwise generate every page, whatever happens to the others
+        let results: Vec<Result<(), BuildError>> =
+            pool.install(|| pages.par_iter().map_init(init, generate).collect());
+        let errors = pages
+            .iter()
+            .zip(results)
+            .filter_map(|(page, result)| result.err().map(|err| page_error(page, err)))
+            .collect();
+        collect_errors(errors)
+    };
+    match library_error.into_inner().unwrap() {
+        Some(err) => Err(err),
+        None => result,
+    }
+}
+
+// Counts the pages generated so far, to show how far along a build is
+struct Progress {
+    total: usize,
+    done: AtomicUsize,
+    // whether progress is shown on a terminal, where each line replaces the
+    // previous one rather than filling up the screen
+    terminal: bool,
+}
+
+impl Progress {
+    fn new(total: usize) -> Progress {
+        Progress {
+            total,
+            done: AtomicUsize::new(0),
+            terminal: io::stderr().is_terminal(),
+        }
+    }
+
+    // Count a page as generated, successfully or not, and print the count
+    // along with the page's path
+    fn page_done(&self, build: &Build, page: &Page) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = url_path(
+            page.source_path
+                .strip_prefix(&build.source_root)
+                .unwrap_or(&page.source_path),
+        );
+        if self.terminal {
+            eprint!("\r\x1b[K[{}/{}] {}", done, self.total, path);
+            if done == self.total {
+                eprintln!();
+            }
+        } else {
+            eprintln!("[{}/{}] {}", done, self.total, path);
+        }
+    }
+}
+
+// Mention the page an error happened on, for errors that don't already
+fn page_error(page: &Page, err: BuildError) -> BuildError {
+    match err {
+        BuildError::Io(err) => BuildError::Io(io::Error::new(
+            err.kind(),
+            format!("{}: {}", page.source_path.display(), err),
+        )),
+        BuildError::Xot(err) => BuildError::Parse {
+            path: page.source_path.clone(),
+            message: err.to_string(),
+        },
+        err => err,
+    }
+}
+
+// Turn the errors of all pages into a single result
+fn collect_errors(mut errors: Vec<BuildError>) -> Result<(), BuildError> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(BuildError::Multiple(errors)),
+    }
+}
+
+/// Generates a site from a source directory and an element library
+pub struct Generator {
+    xot: Xot,
+    library: ElementLibrary,
+    build: Build,
+    pages: Vec<Page>,
+    dead_links: Vec<(String, String)>,
+    stats: Stats,
+}
+
+impl Generator {
+    /// Load the element library from the elements directories in order,
+    /// ready to generate the source directory into the destination directory
+    pub fn new(
+        source: &path::Path,
+        elements: &[path::PathBuf],
+        destination: &path::Path,
+        options: Options,
+    ) -> Result<Generator, BuildError> {
+        let mut xot = new_xot();
+        let library = ElementLibrary::from_folders(&mut xot, elements, options.element_overrides)?;
+
+        let data_path = options.data.clone();
+        let mut build = Build::new(source, elements, destination, options);
+        if let Some(data_path) = data_path {
+            build.load_data(&data_path)?;
+        }
+
+        Ok(Generator {
+            xot,
+            library,
+            build,
+            pages: Vec::new(),
+            dead_links: Vec::new(),
+            stats: Stats::default(),
+        })
+    }
+
+    /// The loaded element library
+    pub fn library(&self) -> &ElementLibrary {
+        &self.library
+    }
+
+    /// Handle every element with the given tag name using `handler` instead
+    /// of any element of that name in the library
+    pub fn register_handler(&mut self, name: &str, handler: impl ElementHandler + 'static) {
+        self.build
+            .handlers
+            .insert(name.to_string(), Box::new(handler));
+    }
+
+    /// Clean the destination directory unless the options say otherwise,
+    /// then copy and generate everything in the source directory into it
+    pub fn run(&mut self) -> Result<(), BuildError> {
+        let start = time::Instant::now();
+        *self.build.stats.lock().unwrap() = Stats::default();
+        self.build.diagnostics.lock().unwrap().clear();
+
+        if self.build.options.cleans_first() {
+            clean_folder(&self.build.dst_root, &self.build)?;
+        }
+        self.build.written.lock().unwrap().clear();
+        self.build.hashed_assets.lock().unwrap().clear();
+
+        if let Some(only) = &self.build.options.only {
+            let only = self.build.source_root.join(only);
+            if !only.exists() {
+                return Err(BuildError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Nothing to generate at {}", only.display()),
+                )));
+            }
+        }
+
+        self.pages.clear();
+        generate_folder(
+            &self.build,
+            &self.build.source_root,
+            &self.build.dst_root,
+            &self.build.options,
+            &mut Vec::new(),
+            &mut self.pages,
+        )?;
+
+        generate_pages(&mut self.xot, &self.library, &self.build, &self.pages)?;
+
+        // Only the elements used by every page are known to need styles
+        if self.build.options.only.is_none() {
+            self.write_component_styles()?;
+        }
+
+        self.stats = self.build.stats.lock().unwrap().clone();
+        self.stats.duration = start.elapsed();
+
+        // Problems found in strict mode are listed along with invalid uses of
+        // elements, but those decide how the build failed
+        let mut invalid = std::mem::take(&mut *self.build.invalid.lock().unwrap());
+        let mut undefined = std::mem::take(&mut *self.build.undefined.lock().unwrap());
+        if !invalid.is_empty() {
+            invalid.append(&mut undefined);
+            return Err(BuildError::Invalid(invalid));
+        }
+        if !undefined.is_empty() {
+            return Err(BuildError::Undefined(undefined));
+        }
+
+        // Only once everything has been generated successfully, so that a
+        // failed build doesn't delete anything
+        if self.build.options.sync && self.build.options.only.is_none() {
+            let written = std::mem::take(&mut *self.build.written.lock().unwrap());
+            sync_folder(&self.build.dst_root, &written, &self.build)?;
+        }
+
+        if self.build.options.check_links {
+            self.dead_links = check_links(&self.build);
+        }
+
+        Ok(())
+    }
+
+    // Write the scoped styles of all elements that were used to a single
+    // stylesheet, so that each appears only once however often it was used
+    fn write_component_styles(&self) -> Result<(), BuildError> {
+        let used_elements = self.build.used_elements.lock().unwrap();
+        let mut styled: Vec<&ElementDefinition> = self
+            .library
+            .elements()
+            .values()
+            .filter(|defn| defn.style.is_some() && used_elements.contains(&defn.name))
+            .collect();
+        if styled.is_empty() {
+            return Ok(());
+        }
+        styled.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut css = String::new();
+        for defn in styled {
+            css += &format!("/* {} */\n", defn.name);
+            css += defn.style.as_ref().unwrap().trim();
+            css += "\n";
+        }
+
+        let path = self.build.dst_root.join(COMPONENT_STYLESHEET);
+        ensure_not_overwriting(&path, &self.build.options)?;
+        // One left over from an earlier build is simply replaced
+        if self.build.source_root.join(COMPONENT_STYLESHEET).exists() {
+            self.build.warn(&format!(
+                "overwriting \"{}\" with the styles of the elements used",
+                path.display()
+            ));
+        }
+        write_generated(&path, css.as_bytes(), &self.build.options)?;
+        self.build.wrote(&path);
+        Ok(())
+    }
+
+    /// All elements in the library that weren't used by any page, sorted by name
+    pub fn unused_elements(&self) -> Vec<&ElementDefinition> {
+        let used_elements = self.build.used_elements.lock().unwrap();
+        let mut unused: Vec<&ElementDefinition> = self
+            .library
+            .elements()
+            .values()
+            .filter(|defn| !used_elements.contains(&defn.name))
+            .collect();
+        unused.sort_by(|a, b| a.name.cmp(&b.name));
+        unused
+    }
+
+    /// Internal links that don't lead to a generated page, as pairs of the
+    /// linking page's path and the link. Only collected with `check_links`.
+    pub fn dead_links(&self) -> &[(String, String)] {
+        &self.dead_links
+    }
+
+    /// Numbers describing the last build
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Every warning printed during the last build, and every problem
+    /// found in pages that made it fail, in the order they were found
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.build.diagnostics.lock().unwrap().clone()
+    }
+}
+
+/// Delete everything in the destination directory the way a build does
+/// before generating into it, without generating anything
+pub fn clean(destination: &path::Path, options: Options) -> Result<(), BuildError> {
+    let build = Build::new(path::Path::new(""), &[], destination, options);
+    clean_folder(destination, &build)?;
+    Ok(())
+}
+
+/// Generate the site in `source` into `destination` using the elements
+/// defined in the directories `elements`
+///
+/// ```no_run
+/// use html_generator::{generate, Options};
+/// use std::path::{Path, PathBuf};
+///
+/// generate(
+///     Path::new("pages"),
+///     &[PathBuf::from("elements")],
+///     Path::new("output"),
+///     Options::default(),
+/// )?;
+/// # Ok::<(), html_generator::BuildError>(())
+/// ```
+pub fn generate(
+    source: &path::Path,
+    elements: &[path::PathBuf],
+    destination: &path::Path,
+    options: Options,
+) -> Result<(), BuildError> {
+    Generator::new(source, elements, destination, options)?.run()
+}
+
+/// The outcome of a build along with everything reported along the way
+#[derive(Debug)]
+pub struct BuildReport {
+    /// Whether the build succeeded, or what failed it
+    pub result: Result<(), BuildError>,
+    /// Warnings and errors about the site, in the order they came up. Those
+    /// of a failed build end with the error that failed it.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Like `generate`, but also collect the warnings about the site, such as
+/// references to missing attributes, for a program embedding baumkuchen to
+/// show. Set `Options::quiet` to keep them from also being printed.
+pub fn generate_with_diagnostics(
+    source: &path::Path,
+    elements: &[path::PathBuf],
+    destination: &path::Path,
+    options: Options,
+) -> BuildReport {
+    let mut generator = match Generator::new(source, elements, destination, options) {
+        Ok(generator) => generator,
+        Err(err) => {
+            return BuildReport {
+                diagnostics: err.diagnostics(),
+                result: Err(err),
+            }
+        }
+    };
+    let result = generator.run();
+    let mut diagnostics = generator.diagnostics();
+    if let Err(err) = &result {
+        diagnostics.extend(err.diagnostics());
+    }
+    BuildReport {
+        result,
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A source, an elements, and a destination directory inside of a fresh
+    // temporary directory, which is deleted again when the test is done
+    struct Site {
+        root: path::PathBuf,
+    }
+
+    impl Site {
+        fn new() -> Site {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let root = std::env::temp_dir().join(format!(
+                "baumkuchen-test-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = fs::remove_dir_all(&root);
+            for dir in ["pages", "elements", "output"] {
+                fs::create_dir_all(root.join(dir)).unwrap();
+            }
+            Site { root }
+        }
+
+        // A path inside of the temporary directory, such as "pages/index.html"
+        fn path(&self, path: &str) -> path::PathBuf {
+            self.root.join(path)
+        }
+
+        // Write a file, creating the directories it is in
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.path(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+
+        fn generator(&self, options: Options) -> Result<Generator, BuildError> {
+            Generator::new(
+                &self.path("pages"),
+                &[self.path("elements")],
+                &self.path("output"),
+                options,
+            )
+        }
+
+        fn generate(&self, options: Options) -> Result<(), BuildError> {
+            self.generator(options)?.run()
+        }
+
+        // The contents of a file in the destination directory
+        fn output(&self, path: &str) -> String {
+            let path = self.path("output").join(path);
+            fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("can't read {}: {}", path.display(), err))
+        }
+    }
+
+    impl Drop for Site {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    // The default options, without printing anything
+    fn quiet() -> Options {
+        Options {
+            quiet: true,
+            ..Options::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_to_write_through_a_symlink_out_of_the_destination() {
+        let site = Site::new();
+        let outside = Site::new();
+        site.write("pages/docs/notes.txt", "hello");
+        std::os::unix::fs::symlink(outside.path("output"), site.path("output/docs")).unwrap();
+
+        match site.generate(quiet()) {
+            Err(BuildError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+            other => panic!("expected the write to be refused, got {:?}", other),
+        }
+        assert!(!outside.path("output/notes.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinked_directories_only_when_asked_and_not_in_circles() {
+        let site = Site::new();
+        let shared = Site::new();
+        shared.write("pages/notes.txt", "shared");
+        site.write("pages/index.html", "<html><body></body></html>");
+        std::os::unix::fs::symlink(shared.path("pages"), site.path("pages/shared")).unwrap();
+        std::os::unix::fs::symlink(site.path("pages"), site.path("pages/loop")).unwrap();
+
+        site.generate(quiet()).unwrap();
+        assert!(!site.path("output/shared").exists());
+        assert!(!site.path("output/loop").exists());
+
+        let options = Options {
+            follow_symlinks: true,
+            ..quiet()
+        };
+        site.generate(options).unwrap();
+        assert_eq!(site.output("shared/notes.txt"), "shared");
+        assert!(!site.path("output/loop").exists());
+    }
+
+    #[test]
+    fn spreads_the_remaining_attributes_onto_an_inner_element() {
+        let site = Site::new();
+        site.write(
+            "elements/textinput.html",
+            r#"<label><self.label /><input type="text" self.rest="" /></label>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><textinput label="Name" placeholder="Your name" required="required" /></body></html>"#,
+        );
+        site.generate(quiet()).unwrap();
+        assert!(site.output("index.html").contains(
+            r#"<label>Name<input type="text" placeholder="Your name" required></label>"#
+        ));
+    }
+
+    // Every file in a directory and below it, by its path relative to the
+    // directory
+    fn read_tree(dir: &path::Path) -> std::collections::BTreeMap<path::PathBuf, Vec<u8>> {
+        let mut files = std::collections::BTreeMap::new();
+        let mut dirs = vec![dir.to_path_buf()];
+        while let Some(current) = dirs.pop() {
+            for entry in fs::read_dir(&current).unwrap() {
+                let entry_path = entry.unwrap().path();
+                if entry_path.is_dir() {
+                    dirs.push(entry_path);
+                } else {
+                    let relative = entry_path.strip_prefix(dir).unwrap().to_path_buf();
+                    files.insert(relative, fs::read(&entry_path).unwrap());
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn generates_the_same_site_with_one_job_as_in_parallel() {
+        let site = Site::new();
+        site.write(
+            "elements/card.html",
+            r#"<div class="card ${self.kind}"><h2><self.title /></h2><self.inner /></div>"#,
+        );
+        for n in 0..20 {
+            site.write(
+                &format!("pages/section{}/page{}.html", n % 3, n),
+                &format!(
+                    r#"<html><body><card kind="k{n}" title="Page {n}"><p>Text {n}</p></card></body></html>"#
+                ),
+            );
+        }
+        site.write("pages/logo.svg", "<svg></svg>");
+
+        site.generate(quiet()).unwrap();
+        let parallel = read_tree(&site.path("output"));
+        site.generate(Options {
+            jobs: Some(1),
+            ..quiet()
+        })
+        .unwrap();
+        let sequential = read_tree(&site.path("output"));
+
+        assert_eq!(parallel.len(), 21);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn streams_a_large_page_the_same_as_serializing_it_to_a_string() {
+        let mut xot = new_xot();
+        let rows: String = (0..5000)
+            .map(|n| format!(r#"<tr><td class="n">{n}</td><td>Row &amp; {n}</td></tr>"#))
+            .collect();
+        let source = format!("<html><body><table>{}</table></body></html>", rows);
+        let document = xot.parse(&source).unwrap();
+
+        let expected = xot
+            .html5()
+            .serialize_string(
+                xot::output::html5::Parameters {
+                    indentation: None,
+                    cdata_section_elements: vec![],
+                },
+                document,
+            )
+            .unwrap();
+        let mut streamed = Vec::new();
+        serialize_page(
+            &mut xot,
+            document,
+            vec![],
+            &Options::default(),
+            &mut streamed,
+        )
+        .unwrap();
+
+        assert_eq!(String::from_utf8(streamed).unwrap(), expected);
+    }
+
+    #[test]
+    fn expands_an_element_whose_definition_uses_another_element() {
+        let site = Site::new();
+        site.write(
+            "elements/fancybutton.html",
+            r#"<button class="fancy ${self.class}"><self.inner /></button>"#,
+        );
+        site.write(
+            "elements/primarybutton.html",
+            r#"<fancybutton class="primary"><self.inner /></fancybutton>"#,
+        );
+        site.write(
+            "elements/cta.html",
+            r#"<primarybutton><b><self.inner /></b></primarybutton>"#,
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><cta>Buy</cta><primarybutton>Go</primarybutton></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <button class=\"fancy primary\"><b>Buy</b></button>\
+             <button class=\"fancy primary\">Go</button></body></html>"
+        );
+    }
+
+    #[test]
+    fn reports_the_elements_no_page_used() {
+        let site = Site::new();
+        site.write("elements/used.html", "<b><self.inner /></b>");
+        site.write("elements/unused.html", "<i><self.inner /></i>");
+        site.write(
+            "pages/index.html",
+            "<html><body><used>Hi</used></body></html>",
+        );
+
+        let mut generator = site.generator(quiet()).unwrap();
+        generator.run().unwrap();
+
+        let unused: Vec<&str> = generator
+            .unused_elements()
+            .iter()
+            .map(|defn| defn.name())
+            .collect();
+        assert_eq!(unused, ["unused"]);
+    }
+
+    #[test]
+    fn repeats_foreachvalue_for_every_value_of_a_split_attribute() {
+        let site = Site::new();
+        site.write(
+            "elements/tags.html",
+            r#"<div><foreachvalue.list><span class="tag-${self.value}"><self.value /></span></foreachvalue.list></div>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><tags list="a,b,c" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div>\
+             <span class=\"tag-a\">a</span>\
+             <span class=\"tag-b\">b</span>\
+             <span class=\"tag-c\">c</span></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn replaces_toc_with_links_to_the_headings() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            r#"<html><body><toc /><h2>Getting started</h2><p>Text</p><h2 id="usage">How to use it</h2></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><ul class=\"toc\">\
+             <li><a href=\"#getting-started\">Getting started</a></li>\
+             <li><a href=\"#usage\">How to use it</a></li></ul>\
+             <h2 id=\"getting-started\">Getting started</h2><p>Text</p>\
+             <h2 id=\"usage\">How to use it</h2></body></html>"
+        );
+    }
+
+    #[test]
+    fn gives_headings_with_the_same_text_different_ids() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            "<html><body><h1>Foo</h1><h2>Foo</h2><h3 id=\"bar\">Bar</h3></body></html>",
+        );
+
+        site.generate(Options {
+            auto_ids: true,
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><h1 id=\"foo\">Foo</h1><h2 id=\"foo-2\">Foo</h2>\
+             <h3 id=\"bar\">Bar</h3></body></html>"
+        );
+    }
+
+    #[test]
+    fn reports_internal_links_to_pages_that_were_not_generated() {
+        let site = Site::new();
+        site.write("pages/about.html", "<html><body>About</body></html>");
+        site.write(
+            "pages/index.html",
+            r#"<html><body><a href="/about.html">About</a><a href="/missing.html">Gone</a><a href="https://example.com/missing.html">Elsewhere</a></body></html>"#,
+        );
+
+        let mut generator = site
+            .generator(Options {
+                check_links: true,
+                ..quiet()
+            })
+            .unwrap();
+        generator.run().unwrap();
+
+        assert_eq!(
+            generator.dead_links(),
+            [("/index.html".to_string(), "/missing.html".to_string())]
+        );
+    }
+
+    // Parse an invocation and the markup of a definition to run a single
+    // substitution on, without any files involved
+    fn parse_fragments(xot: &mut Xot, invocation: &str, markup: &str) -> (xot::Node, xot::Node) {
+        let invocation = xot.parse(invocation).unwrap();
+        let invocation = xot.document_element(invocation).unwrap();
+        let markup = xot.parse(markup).unwrap();
+        let markup = xot.document_element(markup).unwrap();
+        (invocation, markup)
+    }
+
+    fn first_child_named(xot: &Xot, node: xot::Node, name: &str) -> xot::Node {
+        xot.descendants(node)
+            .find(|d| {
+                xot.node_name(*d)
+                    .is_some_and(|n| xot.name_ns_str(n).0 == name)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn substitute_if_keeps_the_matching_branch() {
+        let build = Build::new(path::Path::new(""), &[], path::Path::new(""), quiet());
+        let context = Context::new("index.html".to_string(), &build);
+        let mut xot = new_xot();
+
+        let (invocation, markup) = parse_fragments(
+            &mut xot,
+            r#"<note kind="warning" />"#,
+            r#"<div><if self.kind="warning"><then><b>Careful</b></then><else>Note</else></if></div>"#,
+        );
+        let node = first_child_named(&xot, markup, "if");
+        substitute_if(&mut xot, node, invocation, &context).unwrap();
+        assert_eq!(xot.to_string(markup).unwrap(), "<div><b>Careful</b></div>");
+
+        // Without an <else>, nothing is left when the condition doesn't match
+        let (invocation, markup) = parse_fragments(
+            &mut xot,
+            r#"<note kind="info" />"#,
+            r#"<div><if self.kind="warning"><then><b>Careful</b></then></if></div>"#,
+        );
+        let node = first_child_named(&xot, markup, "if");
+        substitute_if(&mut xot, node, invocation, &context).unwrap();
+        assert_eq!(xot.to_string(markup).unwrap(), "<div/>");
+    }
+
+    #[test]
+    fn substitute_foreach_repeats_for_every_child() {
+        let build = Build::new(path::Path::new(""), &[], path::Path::new(""), quiet());
+        let context = Context::new("index.html".to_string(), &build);
+        let mut xot = new_xot();
+
+        let (invocation, markup) = parse_fragments(
+            &mut xot,
+            "<list><a>1</a><b>2</b></list>",
+            "<ul><foreachchild.item><li><item /></li></foreachchild.item></ul>",
+        );
+        let node = first_child_named(&xot, markup, "foreachchild.item");
+        substitute_foreach(&mut xot, node, invocation, &context).unwrap();
+        assert_eq!(
+            xot.to_string(markup).unwrap(),
+            "<ul><li><a>1</a></li><li><b>2</b></li></ul>"
+        );
+
+        // An invocation without children repeats nothing
+        let (invocation, markup) = parse_fragments(
+            &mut xot,
+            "<list>only text</list>",
+            "<ul><foreachchild.item><li><item /></li></foreachchild.item></ul>",
+        );
+        let node = first_child_named(&xot, markup, "foreachchild.item");
+        substitute_foreach(&mut xot, node, invocation, &context).unwrap();
+        assert_eq!(xot.to_string(markup).unwrap(), "<ul/>");
+    }
+
+    #[test]
+    fn substitute_tag_replaces_every_occurrence() {
+        let build = Build::new(path::Path::new(""), &[], path::Path::new(""), quiet());
+        let context = Context::new("index.html".to_string(), &build);
+        let mut xot = new_xot();
+
+        let (invocation, markup) =
+            parse_fragments(&mut xot, "<card />", "<div><slot /><p><slot /></p></div>");
+        let replacement = xot.parse("<b>Hi</b>").unwrap();
+        let replacement = xot.document_element(replacement).unwrap();
+        let slot = xot.name("slot").unwrap();
+        substitute_tag(&mut xot, markup, slot, replacement, invocation, &context).unwrap();
+        assert_eq!(
+            xot.to_string(markup).unwrap(),
+            "<div><b>Hi</b><p><b>Hi</b></p></div>"
+        );
+
+        // Attributes of the tag are given to the replacement, with classes
+        // combined
+        let (invocation, markup) = parse_fragments(
+            &mut xot,
+            r#"<card tone="dark" />"#,
+            r#"<div><slot class="wide" title="${self.tone}" /></div>"#,
+        );
+        let replacement = xot.parse(r#"<b class="bold">Hi</b>"#).unwrap();
+        let replacement = xot.document_element(replacement).unwrap();
+        substitute_tag(&mut xot, markup, slot, replacement, invocation, &context).unwrap();
+        assert_eq!(
+            xot.to_string(markup).unwrap(),
+            r#"<div><b class="bold wide" title="dark">Hi</b></div>"#
+        );
+    }
+
+    #[test]
+    fn loads_paths_and_settings_from_a_config_file() {
+        let site = Site::new();
+        site.write(
+            "baumkuchen.toml",
+            "source = \"pages\"\nelements = \"elements\"\ndestination = \"output\"\nminify = false\n",
+        );
+
+        let config = Config::from_file(&site.path("baumkuchen.toml")).unwrap();
+        assert_eq!(config.source, Some(site.path("pages")));
+        assert_eq!(config.elements, Some(vec![site.path("elements")]));
+        assert_eq!(config.destination, Some(site.path("output")));
+
+        let mut options = Options::default();
+        config.apply(&mut options);
+        assert!(!options.minify);
+    }
+
+    #[test]
+    fn applies_a_directory_config_to_that_directory_only() {
+        let site = Site::new();
+        let page = "<html>\n  <body>\n    <p>Hi</p>\n  </body>\n</html>";
+        site.write("pages/index.html", page);
+        site.write("pages/blog/post.html", page);
+        site.write("pages/blog/.baumkuchen.toml", "minify = false\n");
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>Hi</p></body></html>"
+        );
+        assert_eq!(
+            site.output("blog/post.html"),
+            "<!DOCTYPE html><html>\n  <body>\n    <p>Hi</p>\n  </body>\n</html>"
+        );
+        assert!(!site.path("output/blog/.baumkuchen.toml").exists());
+    }
+
+    #[test]
+    fn surrounds_expanded_elements_with_trace_comments() {
+        let site = Site::new();
+        site.write(
+            "elements/fancybutton.html",
+            "<button><self.inner /></button>",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><fancybutton>Go</fancybutton></body></html>",
+        );
+
+        site.generate(Options {
+            trace_components: true,
+            minify: false,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><!-- baumkuchen: fancybutton -->\
+             <button>Go</button><!-- /baumkuchen: fancybutton --></body></html>"
+        );
+
+        // Minification removes them again
+        site.generate(Options {
+            trace_components: true,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><button>Go</button></body></html>"
+        );
+    }
+
+    #[test]
+    fn refers_to_namespaced_attributes_by_their_prefixed_name() {
+        let site = Site::new();
+        site.write(
+            "elements/ogtitle.html",
+            r#"<meta property="og:title" content="${self.og:title}" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html xmlns:og="https://ogp.me/ns#"><head><ogtitle og:title="Test page" /></head></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert!(site
+            .output("index.html")
+            .contains(r#"<meta property="og:title" content="Test page">"#));
+    }
+
+    #[test]
+    fn refers_to_hyphenated_attributes() {
+        let site = Site::new();
+        site.write(
+            "elements/row.html",
+            r#"<tr id="row-${self.data-id}"><td><self.data-id /></td></tr>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><table><row data-id="42" /></table></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><table><tr id=\"row-42\"><td>42</td></tr></table></body></html>"
+        );
+    }
+
+    #[test]
+    fn does_not_collapse_non_breaking_spaces_when_minifying() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            "<html><body><p><b>a</b>&#160;&#160;<b>b</b>  \u{a0} c</p></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p><b>a</b>&nbsp;&nbsp;<b>b</b> &nbsp; c</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn minify_keeps_non_breaking_spaces_between_words() {
+        let mut xot = new_xot();
+        let document = xot
+            .parse("<p>\n  one\u{a0}two   three \u{a0}\u{2009}four\n</p>")
+            .unwrap();
+
+        minify(&mut xot, document).unwrap();
+
+        let p = xot.document_element(document).unwrap();
+        assert_eq!(
+            xot.text_content_str(p),
+            Some("one\u{a0}two three \u{a0}\u{2009}four")
+        );
+    }
+
+    #[test]
+    fn quotes_attributes_with_double_quotes_by_default() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            r#"<html><body><a href='/about.html' title='Say "hi"' class=''>About</a></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <a href=\"/about.html\" title=\"Say &quot;hi&quot;\" class=\"\">About</a></body></html>"
+        );
+
+        site.generate(Options {
+            attribute_quotes: AttributeQuotes::Single,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <a href='/about.html' title='Say \"hi\"' class=''>About</a></body></html>"
+        );
+    }
+
+    #[test]
+    fn includes_blocks_for_the_environment_being_built_for() {
+        let site = Site::new();
+        site.write(
+            "elements/analytics.html",
+            r#"<if build.env="production"><then><script src="/analytics.js"></script></then></if>"#,
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><analytics /><p>Hi</p></body></html>",
+        );
+
+        site.generate(Options {
+            env: Some("production".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><script src=\"/analytics.js\"></script><p>Hi</p></body></html>"
+        );
+
+        site.generate(Options {
+            env: Some("staging".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>Hi</p></body></html>"
+        );
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>Hi</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn url_paths_use_forward_slashes() {
+        let nested: path::PathBuf = ["blog", "2024", "post.html"].iter().collect();
+        assert_eq!(url_path(&nested), "/blog/2024/post.html");
+        assert_eq!(url_path(path::Path::new("")), "/");
+
+        let site = Site::new();
+        site.write("elements/here.html", r#"<p title="${self.filepath}" />"#);
+        site.write("pages/blog/post.html", "<html><body><here /></body></html>");
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("blog/post.html"),
+            "<!DOCTYPE html><html><body><p title=\"/blog/post.html\"></p></body></html>"
+        );
+    }
+
+    #[test]
+    fn strips_a_byte_order_mark_from_sources() {
+        let site = Site::new();
+        site.write("elements/greeting.html", "\u{feff}<b>Hello</b>");
+        site.write(
+            "pages/index.html",
+            "\u{feff}<html><body><greeting /></body></html>",
+        );
+        fs::write(
+            site.path("pages/latin1.html"),
+            b"<html><body>\xe9</body></html>",
+        )
+        .unwrap();
+
+        let err = site.generate(quiet()).unwrap_err();
+        assert!(err.to_string().contains("latin1.html"), "{}", err);
+
+        fs::remove_file(site.path("pages/latin1.html")).unwrap();
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><b>Hello</b></body></html>"
+        );
+    }
+
+    #[test]
+    fn repeats_each_for_every_item_of_a_data_list() {
+        let site = Site::new();
+        site.write(
+            "data/site.json",
+            r#"{ "nav": [{ "label": "Home", "url": "/" }, { "label": "Blog", "url": "/blog/" }] }"#,
+        );
+        site.write(
+            "elements/sitenav.html",
+            r#"<nav><each in="site.nav"><a href="${item.url}"><item.label /></a></each></nav>"#,
+        );
+        site.write("pages/index.html", "<html><body><sitenav /></body></html>");
+
+        site.generate(Options {
+            data: Some(site.path("data")),
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><nav>\
+             <a href=\"/\">Home</a><a href=\"/blog/\">Blog</a></nav></body></html>"
+        );
+    }
+
+    #[test]
+    fn paginates_five_items_two_to_a_page_into_three_files() {
+        let site = Site::new();
+        site.write(
+            "data/blog.json",
+            r#"{ "posts": [{ "title": "A" }, { "title": "B" }, { "title": "C" }, { "title": "D" }, { "title": "E" }] }"#,
+        );
+        site.write(
+            "elements/postlist.html",
+            r#"<div data-page="${page.number}/${page.total}" data-prev="${page.prev}" data-next="${page.next}"><each in="page.items" as="post"><p><post.title /></p></each></div>"#,
+        );
+        site.write(
+            "pages/blog.html",
+            r#"<html><body><paginate in="blog.posts" size="2" /><postlist /></body></html>"#,
+        );
+
+        site.generate(Options {
+            data: Some(site.path("data")),
+            ..quiet()
+        })
+        .unwrap();
+
+        let mut files: Vec<String> = fs::read_dir(site.path("output/blog"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["1.html", "2.html", "3.html"]);
+        assert_eq!(
+            site.output("blog/2.html"),
+            "<!DOCTYPE html><html><body>\
+             <div data-page=\"2/3\" data-prev=\"/blog/1.html\" data-next=\"/blog/3.html\">\
+             <p>C</p><p>D</p></div></body></html>"
+        );
+        assert!(site.output("blog/3.html").contains("<p>E</p></div>"));
+    }
+
+    #[test]
+    fn counts_expansions_for_each_paginated_file_on_its_own() {
+        let site = Site::new();
+        site.write(
+            "data/blog.json",
+            r#"{ "posts": [{ "title": "A" }, { "title": "B" }, { "title": "C" }, { "title": "D" }, { "title": "E" }, { "title": "F" }] }"#,
+        );
+        site.write("elements/post.html", r#"<p><self.title /></p>"#);
+        site.write(
+            "elements/postlist.html",
+            r#"<div><each in="page.items" as="item"><post title="${item.title}" /></each></div>"#,
+        );
+        site.write(
+            "pages/blog.html",
+            r#"<html><body><paginate in="blog.posts" size="2" /><postlist /></body></html>"#,
+        );
+
+        site.generate(Options {
+            data: Some(site.path("data")),
+            max_expansions: 3,
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("blog/3.html"),
+            "<!DOCTYPE html><html><body><div><p>E</p><p>F</p></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn looks_up_the_attribute_another_attribute_names() {
+        let site = Site::new();
+        site.write(
+            "elements/pick.html",
+            r#"<span title="${self.[self.key]}" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><pick key="title" title="Hi" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><span title=\"Hi\"></span></body></html>"
+        );
+    }
+
+    #[test]
+    fn writes_scripts_in_svg_as_cdata_when_asked() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            r#"<html><body><svg xmlns="http://www.w3.org/2000/svg"><script><![CDATA[if (a < b && c) {}]]></script></svg></body></html>"#,
+        );
+
+        site.generate(Options {
+            cdata_elements: vec!["script".to_string()],
+            ..quiet()
+        })
+        .unwrap();
+
+        assert!(
+            site.output("index.html")
+                .contains("<script><![CDATA[if (a < b && c) {}]]></script>"),
+            "{}",
+            site.output("index.html")
+        );
+    }
+
+    #[test]
+    fn expands_every_top_level_node_of_a_definition() {
+        let site = Site::new();
+        site.write(
+            "elements/field.html",
+            r#"<label for="${self.name}"><self.label /></label><input id="${self.name}" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><form><field name="email" label="Email" /></form></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><form>\
+             <label for=\"email\">Email</label><input id=\"email\"></form></body></html>"
+        );
+    }
+
+    #[test]
+    fn fails_on_missing_attributes_only_in_strict_mode() {
+        let site = Site::new();
+        site.write("elements/card.html", r#"<div title="${self.missing}" />"#);
+        site.write("pages/index.html", "<html><body><card /></body></html>");
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div title=\"\"></div></body></html>"
+        );
+
+        let err = site
+            .generate(Options {
+                strict: true,
+                ..quiet()
+            })
+            .unwrap_err();
+        let BuildError::Undefined(messages) = &err else {
+            panic!("expected an undefined reference, got {:?}", err);
+        };
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("missing"), "{}", messages[0]);
+        assert!(messages[0].contains("index.html"), "{}", messages[0]);
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    #[test]
+    fn keeps_the_space_next_to_an_inline_element() {
+        let site = Site::new();
+        site.write(
+            "elements/icon.html",
+            "<config trim=\"inline\" />\n<svg class=\"icon\"><use href=\"#${self.name}\" /></svg>\n",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><div>\n  <icon name=\"star\" /> <span>Starred</span>\n</div></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div>\
+             <svg class=\"icon\"><use href=\"#star\"></use></svg> <span>Starred</span></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn compares_loop_items_with_the_invocation_inside_of_if() {
+        let site = Site::new();
+        site.write(
+            "elements/menu.html",
+            r#"<ul><foreachchild.item><li><if item.href="${self.active}"><then><b><item /></b></then><else><item /></else></if></li></foreachchild.item></ul>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><menu active="/b/"><a href="/a/">A</a><a href="/b/">B</a><a href="/c/">C</a></menu></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><ul>\
+             <li><a href=\"/a/\">A</a></li>\
+             <li><b><a href=\"/b/\">B</a></b></li>\
+             <li><a href=\"/c/\">C</a></li></ul></body></html>"
+        );
+    }
+
+    #[test]
+    fn reports_malformed_pages_with_exit_code_2() {
+        let site = Site::new();
+        site.write("pages/index.html", "<html><body></html>");
+
+        let err = site.generate(quiet()).unwrap_err();
+
+        assert!(
+            matches!(&err, BuildError::Parse { path, .. } if path.ends_with("index.html")),
+            "{:?}",
+            err
+        );
+        assert_eq!(err.exit_code(), 2);
+    }
+
+    #[test]
+    fn expands_an_alias_like_the_element_itself() {
+        let site = Site::new();
+        site.write(
+            "elements/primarybutton.html",
+            "<config alias=\"btn\" />\n<button class=\"primary ${self.size}\"><self.inner /></button>",
+        );
+        site.write(
+            "pages/a.html",
+            r#"<html><body><primarybutton size="big">Go</primarybutton></body></html>"#,
+        );
+        site.write(
+            "pages/b.html",
+            r#"<html><body><btn size="big">Go</btn></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(site.output("a.html"), site.output("b.html"));
+        assert!(site
+            .output("b.html")
+            .contains("<button class=\"primary big\">Go</button>"));
+
+        // An alias can't take the name of another element
+        site.write("elements/btn.html", "<button />");
+        assert!(site.generate(quiet()).is_err());
+    }
+
+    #[test]
+    fn collects_the_scoped_styles_of_an_element_once() {
+        let site = Site::new();
+        site.write(
+            "elements/card.html",
+            "<style scoped=\"\">.card { padding: 1em; }</style>\n<div class=\"card\"><self.inner /></div>",
+        );
+        site.write(
+            "elements/unused.html",
+            "<style scoped=\"\">.unused {}</style>\n<p />",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><head></head><body><card>A</card><card>B</card></body></html>",
+        );
+        site.write(
+            "pages/other.html",
+            "<html><head></head><body><card>C</card></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output(COMPONENT_STYLESHEET),
+            "/* card */\n.card { padding: 1em; }\n"
+        );
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><head><link rel=\"stylesheet\" href=\"/components.css\"></head>\
+             <body><div class=\"card\">A</div><div class=\"card\">B</div></body></html>"
+        );
+    }
+
+    #[test]
+    fn keeps_only_the_first_of_identical_scripts() {
+        let site = Site::new();
+        site.write(
+            "elements/counter.html",
+            "<button class=\"counter\">0</button><script>setupCounters();</script>",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><head></head><body><counter /><counter /><counter /></body></html>",
+        );
+
+        site.generate(Options {
+            dedupe_assets: true,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><head></head><body>\
+             <button class=\"counter\">0</button><script>setupCounters();</script>\
+             <button class=\"counter\">0</button><button class=\"counter\">0</button></body></html>"
+        );
+
+        site.generate(Options {
+            dedupe_assets: true,
+            hoist_assets: true,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><head><script>setupCounters();</script></head><body>\
+             <button class=\"counter\">0</button><button class=\"counter\">0</button>\
+             <button class=\"counter\">0</button></body></html>"
+        );
+    }
+
+    #[test]
+    fn cleans_the_destination_unless_told_not_to() {
+        let site = Site::new();
+        site.write("pages/index.html", "<html><body>Hi</body></html>");
+        site.write("output/old.html", "old");
+        site.write("output/.git/HEAD", "ref");
+
+        site.generate(Options {
+            clean: false,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(site.output("old.html"), "old");
+        assert!(site.path("output/index.html").exists());
+
+        clean(&site.path("output"), quiet()).unwrap();
+        assert!(!site.path("output/old.html").exists());
+        assert!(!site.path("output/index.html").exists());
+        assert_eq!(site.output(".git/HEAD"), "ref");
+
+        site.write("output/old.html", "old");
+        site.generate(quiet()).unwrap();
+        assert!(!site.path("output/old.html").exists());
+        assert!(site.path("output/index.html").exists());
+    }
+
+    #[test]
+    fn loads_several_element_directories_with_later_ones_overriding() {
+        let site = Site::new();
+        site.write(
+            "shared/card.html",
+            "<div class=\"shared\"><self.inner /></div>",
+        );
+        site.write(
+            "shared/badge.html",
+            "<span class=\"badge\"><self.inner /></span>",
+        );
+        site.write(
+            "elements/card.html",
+            "<div class=\"site\"><self.inner /></div>",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><card><badge>1</badge></card></body></html>",
+        );
+        let elements = [site.path("shared"), site.path("elements")];
+
+        let err = generate(
+            &site.path("pages"),
+            &elements,
+            &site.path("output"),
+            quiet(),
+        );
+        assert!(err.is_err());
+
+        generate(
+            &site.path("pages"),
+            &elements,
+            &site.path("output"),
+            Options {
+                element_overrides: ElementOverrides::Replace,
+                ..quiet()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <div class=\"site\"><span class=\"badge\">1</span></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn counts_pages_files_and_expansions() {
+        let site = Site::new();
+        site.write("elements/card.html", "<div><self.inner /></div>");
+        site.write(
+            "pages/index.html",
+            "<html><body><card>A</card><card>B</card></body></html>",
+        );
+        site.write(
+            "pages/blog/post.html",
+            "<html><body><card>C</card></body></html>",
+        );
+        site.write("pages/style.css", "body {}");
+
+        let mut generator = site.generator(quiet()).unwrap();
+        generator.run().unwrap();
+
+        let stats = generator.stats();
+        assert_eq!(stats.pages, 2);
+        assert_eq!(stats.files_copied, 1);
+        assert_eq!(stats.instantiations, 3);
+    }
+
+    #[test]
+    fn fails_when_a_required_attribute_is_left_out() {
+        let site = Site::new();
+        site.write(
+            "elements/link.html",
+            "<requires attrs=\"href,label\" />\n<a href=\"${self.href}\"><self.label /></a>",
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><link label="Home" /></body></html>"#,
+        );
+
+        let err = site.generate(quiet()).unwrap_err();
+        let BuildError::Invalid(messages) = &err else {
+            panic!("expected a missing attribute, got {:?}", err);
+        };
+        assert!(messages[0].contains("href"), "{}", messages[0]);
+        assert!(messages[0].contains("link"), "{}", messages[0]);
+        assert!(messages[0].contains("index.html"), "{}", messages[0]);
+        assert_eq!(err.exit_code(), 3);
+
+        site.write(
+            "pages/index.html",
+            r#"<html><body><link href="/" label="Home" /></body></html>"#,
+        );
+        site.generate(quiet()).unwrap();
+    }
+
+    #[test]
+    fn checks_attribute_values_against_their_declared_types() {
+        let site = Site::new();
+        site.write(
+            "elements/badge.html",
+            "<attr name=\"count\" type=\"number\" />\n\
+             <attr name=\"variant\" enum=\"primary,secondary\" />\n\
+             <span class=\"${self.variant}\"><self.count /></span>",
+        );
+
+        site.write(
+            "pages/index.html",
+            r#"<html><body><badge count="2.5" variant="primary" /></body></html>"#,
+        );
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><span class=\"primary\">2.5</span></body></html>"
+        );
+
+        site.write(
+            "pages/index.html",
+            r#"<html><body><badge count="2" variant="tertiary" /></body></html>"#,
+        );
+        let err = site.generate(quiet()).unwrap_err();
+        let BuildError::Invalid(messages) = &err else {
+            panic!("expected a disallowed value, got {:?}", err);
+        };
+        assert!(messages[0].contains("tertiary"), "{}", messages[0]);
+    }
+
+    #[test]
+    fn minifies_processed_svg_files_keeping_processing_instructions() {
+        let site = Site::new();
+        site.write(
+            "pages/logo.svg",
+            "<?xml-stylesheet href=\"logo.css\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">\n  <!-- the dot -->\n  <circle r=\"1\" />\n</svg>\n",
+        );
+        site.write(
+            "pages/icon.svg",
+            "<?xml version=\"1.0\"?>\n<?xml-stylesheet href=\"icon.css\"?>\n<svg />\n",
+        );
+
+        site.generate(Options {
+            process_extensions: vec!["svg".to_string()],
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("logo.svg"),
+            "<?xml-stylesheet href=\"logo.css\"?><svg xmlns=\"http://www.w3.org/2000/svg\">\
+             <!-- the dot --><circle r=\"1\"/></svg>"
+        );
+        assert_eq!(
+            site.output("icon.svg"),
+            "<?xml version=\"1.0\"?><?xml-stylesheet href=\"icon.css\"?><svg/>"
+        );
+    }
+
+    #[test]
+    fn gives_pages_their_public_url() {
+        let site = Site::new();
+        site.write(
+            "elements/canonical.html",
+            r#"<link rel="canonical" href="https://example.com${self.url}" />"#,
+        );
+        let page = "<html><head><canonical /></head></html>";
+        site.write("pages/about.html", page);
+        site.write("pages/index.html", page);
+        site.write("pages/v1.2.html", page);
+        site.write("pages/a.b.html", page);
+        site.write("pages/a.c.html", page);
+
+        site.generate(Options {
+            pretty_urls: true,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("about/index.html"),
+            "<!DOCTYPE html><html><head>\
+             <link rel=\"canonical\" href=\"https://example.com/about/\"></head></html>"
+        );
+        assert!(site
+            .output("index.html")
+            .contains("href=\"https://example.com/\""));
+        assert!(site.output("v1.2/index.html").contains("/v1.2/"));
+
+        // Dots in the name of a page stay where they are
+        site.generate(quiet()).unwrap();
+        assert!(site
+            .output("about.html")
+            .contains("https://example.com/about.html"));
+        assert!(site
+            .output("v1.2.html")
+            .contains("https://example.com/v1.2.html"));
+        assert!(site
+            .output("a.b.html")
+            .contains("https://example.com/a.b.html"));
+        assert!(site
+            .output("a.c.html")
+            .contains("https://example.com/a.c.html"));
+    }
+
+    #[test]
+    fn formats_the_build_date_per_expression() {
+        let site = Site::new();
+        site.write(
+            "elements/sitefooter.html",
+            r#"<footer title="${build.date:%Y}" data-day="${build.date:%Y-%m-%d}" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><sitefooter /></body></html>",
+        );
+
+        let mut generator = site.generator(quiet()).unwrap();
+        let today = generator.build.date;
+        generator.run().unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            format!(
+                "<!DOCTYPE html><html><body><footer title=\"{}\" data-day=\"{}\"></footer></body></html>",
+                today.format("%Y"),
+                today.format("%Y-%m-%d")
+            )
+        );
+    }
+
+    #[test]
+    fn highlights_code_with_a_language() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            "<html><body><pre><code lang=\"rust\">fn main() {\n    let x = \"&lt;b&gt;\";\n}</code></pre><code>fn plain()</code></body></html>",
+        );
+
+        site.generate(Options {
+            highlight: true,
+            ..quiet()
+        })
+        .unwrap();
+
+        let page = site.output("index.html");
+        assert!(page.contains("<span style=\""), "{}", page);
+        // The text is the same once the spans are taken away, line breaks
+        // and escaping included
+        let text = Regex::new("<[^>]*>").unwrap().replace_all(&page, "");
+        assert_eq!(text, "fn main() {\n    let x = \"&lt;b>\";\n}fn plain()");
+        assert!(page.contains("<code>fn plain()</code>"));
+    }
+
+    #[test]
+    fn reports_every_broken_page_when_not_failing_fast() {
+        let site = Site::new();
+        site.write("pages/a.html", "<html><body></html>");
+        site.write("pages/b.html", "<html><body><p></body></html>");
+        site.write("pages/ok.html", "<html><body>Fine</body></html>");
+
+        let err = site
+            .generate(Options {
+                fail_fast: false,
+                ..quiet()
+            })
+            .unwrap_err();
+
+        let BuildError::Multiple(errors) = &err else {
+            panic!("expected several errors, got {:?}", err);
+        };
+        let mut paths: Vec<String> = errors
+            .iter()
+            .map(|err| match err {
+                BuildError::Parse { path, .. } => {
+                    path.file_name().unwrap().to_string_lossy().into_owned()
+                }
+                err => panic!("expected a parse error, got {:?}", err),
+            })
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["a.html", "b.html"]);
+        assert_eq!(err.exit_code(), 2);
+        assert!(site.path("output/ok.html").exists());
+    }
+
+    #[test]
+    fn expands_expressions_in_attribute_values_transitively() {
+        let site = Site::new();
+        site.write(
+            "elements/greeting.html",
+            r#"<p title="${self.text}"><self.text /></p>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><greeting name="World" other="Hello, ${self.name}" text="${self.other}!" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p title=\"Hello, World!\">Hello, World!</p></body></html>"
+        );
+
+        // An attribute referring to itself stops rather than going on forever
+        site.write(
+            "pages/index.html",
+            r#"<html><body><greeting text="a${self.text}" /></body></html>"#,
+        );
+        site.generate(quiet()).unwrap();
+    }
+
+    #[test]
+    fn requires_every_condition_of_an_if_to_match() {
+        let site = Site::new();
+        site.write(
+            "elements/badge.html",
+            r#"<if self.kind="new" self.size="big"><then><b>New!</b></then><else><i>Old</i></else></if>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><badge kind="new" size="big" /><badge kind="new" size="small" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><b>New!</b><i>Old</i></body></html>"
+        );
+    }
+
+    #[test]
+    fn fails_on_an_if_without_branches_in_strict_mode() {
+        let site = Site::new();
+        site.write(
+            "elements/badge.html",
+            r#"<if self.kind="new"><than>New!</than></if>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><badge kind="new" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        let err = site
+            .generate(Options {
+                strict: true,
+                ..quiet()
+            })
+            .unwrap_err();
+        let BuildError::Undefined(messages) = &err else {
+            panic!("expected an <if> without branches, got {:?}", err);
+        };
+        assert!(
+            messages[0].contains(r#"<if self.kind="new">"#),
+            "{}",
+            messages[0]
+        );
+        assert!(messages[0].contains("index.html"), "{}", messages[0]);
+    }
+
+    #[test]
+    fn takes_the_branches_of_an_if_from_its_attributes() {
+        let site = Site::new();
+        site.write(
+            "elements/status.html",
+            r#"<span><if self.ok="true" then="Yes" else="No" /></span>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><status ok="true" /><status ok="false" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><span>Yes</span><span>No</span></body></html>"
+        );
+    }
+
+    #[test]
+    fn writes_pages_with_the_output_extension() {
+        let site = Site::new();
+        site.write(
+            "elements/here.html",
+            r#"<a href="${self.url}" title="${self.filepath}" />"#,
+        );
+        site.write("pages/page.html", "<html><body><here /></body></html>");
+        site.write("pages/style.css", "body {}");
+
+        site.generate(Options {
+            output_extension: Some("php".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+
+        assert!(!site.path("output/page.html").exists());
+        assert_eq!(
+            site.output("page.php"),
+            "<!DOCTYPE html><html><body>\
+             <a href=\"/page.php\" title=\"/page.html\"></a></body></html>"
+        );
+        assert_eq!(site.output("style.css"), "body {}");
+    }
+
+    #[test]
+    fn leaves_unchanged_outputs_alone_when_not_cleaning() {
+        let site = Site::new();
+        site.write(
+            "elements/card.html",
+            "<style scoped=\"\">.card {}</style>\n<div class=\"card\"><self.inner /></div>",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><head></head><body><card>A</card></body></html>",
+        );
+        site.write("pages/about.html", "<html><body>About</body></html>");
+        let options = || Options {
+            clean: false,
+            ..quiet()
+        };
+        site.generate(options()).unwrap();
+
+        // Pretend that the outputs were written long ago
+        let long_ago = time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(1_000_000_000);
+        for path in ["index.html", "about.html", COMPONENT_STYLESHEET] {
+            fs::File::options()
+                .write(true)
+                .open(site.path("output").join(path))
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+        site.write("pages/about.html", "<html><body>About us</body></html>");
+        site.generate(options()).unwrap();
+
+        let modified = |path: &str| {
+            fs::metadata(site.path("output").join(path))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        assert_eq!(modified("index.html"), long_ago);
+        assert_eq!(modified(COMPONENT_STYLESHEET), long_ago);
+        assert_ne!(modified("about.html"), long_ago);
+
+        // The stylesheet left over from the last build is replaced without
+        // a warning
+        let mut generator = site.generator(options()).unwrap();
+        generator.run().unwrap();
+        assert!(generator.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn removes_regions_for_other_environments() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            "<html><body><p>A</p><!-- #dev --><script src=\"/reload.js\"></script><!-- #enddev --><p>B</p></body></html>",
+        );
+
+        site.generate(Options {
+            env: Some("production".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>A</p><p>B</p></body></html>"
+        );
+
+        site.generate(Options {
+            env: Some("dev".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>A</p><script src=\"/reload.js\"></script><p>B</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn matches_if_values_literally_with_match_exact() {
+        let site = Site::new();
+        site.write(
+            "elements/version.html",
+            r#"<span><if self.v="1.0" match="exact" then="exact" else="no" /><if self.v="1.0" then="regex" /></span>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><version v="1.0" /><version v="100" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <span>exactregex</span><span>noregex</span></body></html>"
+        );
+    }
+
+    #[test]
+    fn treats_an_invalid_pattern_as_not_matching() {
+        let site = Site::new();
+        site.write(
+            "elements/check.html",
+            r#"<span><if self.v="(unclosed" then="yes" else="no" /></span>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><check v="(unclosed" /></body></html>"#,
+        );
+
+        let mut generator = site.generator(quiet()).unwrap();
+        generator.run().unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><span>no</span></body></html>"
+        );
+        let diagnostics = generator.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(
+            diagnostics[0].message.contains("(unclosed"),
+            "{:?}",
+            diagnostics
+        );
+    }
+
+    #[test]
+    fn only_expands_the_elements_a_definition_uses() {
+        let site = Site::new();
+        site.write("elements/icon.html", "<i class=\"icon\" />");
+        site.write(
+            "elements/label.html",
+            "<span class=\"label\"><self.inner /></span>",
+        );
+        site.write(
+            "elements/field.html",
+            "<uses names=\"icon\" />\n<div><icon /><label><self.inner /></label></div>",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><field><label>Name</label></field></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        // The <label> written in the definition is left as it is, while the
+        // one passed in is still expanded
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div><i class=\"icon\"></i>\
+             <label><span class=\"label\">Name</span></label></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn writes_the_contents_of_raw_verbatim() {
+        let site = Site::new();
+        site.write("elements/fancybutton.html", "<button class=\"fancy\" />");
+        site.write(
+            "pages/index.html",
+            "<html><body><fancybutton /><raw><fancybutton title=\"${self.x}\" />\n  <b> spaced </b></raw></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><button class=\"fancy\"></button>\
+             <fancybutton title=\"${self.x}\"></fancybutton>\n  <b> spaced </b></body></html>"
+        );
+    }
+
+    #[test]
+    fn repeats_several_elements_for_every_child() {
+        let site = Site::new();
+        site.write(
+            "elements/glossary.html",
+            "<dl>\n  <foreachchild.term>\n    <dt><term.name /></dt>\n    <dd><term /></dd>\n  </foreachchild.term>\n</dl>",
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><glossary><p name="Tag">An element</p><p name="Attribute">Part of a tag</p></glossary></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><dl>\
+             <dt>Tag</dt><dd><p name=\"Tag\">An element</p></dd>\
+             <dt>Attribute</dt><dd><p name=\"Attribute\">Part of a tag</p></dd></dl></body></html>"
+        );
+    }
+
+    #[test]
+    fn sync_removes_the_outputs_of_deleted_pages() {
+        let site = Site::new();
+        site.write("pages/index.html", "<html><body>Home</body></html>");
+        site.write("pages/blog/old.html", "<html><body>Old</body></html>");
+        site.write("pages/blog/img/photo.png", "png");
+        site.write("output/.git/HEAD", "ref");
+        site.write("output/.well-known/security.txt", "contact");
+        let options = || Options {
+            sync: true,
+            ..quiet()
+        };
+        site.generate(options()).unwrap();
+        assert!(site.path("output/blog/old.html").exists());
+
+        fs::remove_file(site.path("pages/blog/old.html")).unwrap();
+        fs::remove_dir_all(site.path("pages/blog/img")).unwrap();
+        site.generate(options()).unwrap();
+
+        assert!(!site.path("output/blog/old.html").exists());
+        assert!(!site.path("output/blog/img").exists());
+        assert!(site.path("output/index.html").exists());
+        assert_eq!(site.output(".git/HEAD"), "ref");
+        assert_eq!(site.output(".well-known/security.txt"), "contact");
+    }
+
+    #[test]
+    fn expands_and_keeps_marked_comments() {
+        let site = Site::new();
+        site.write(
+            "elements/stamp.html",
+            "<!--! stamped ${build.env} --><span />",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><!-- gone --><!--! built for ${build.env} --><stamp /></body></html>",
+        );
+
+        site.generate(Options {
+            env: Some("production".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><!-- built for production -->\
+             <!-- stamped production --><span></span></body></html>"
+        );
+    }
+
+    #[test]
+    fn points_assets_at_the_copied_file_from_the_page() {
+        let site = Site::new();
+        site.write("pages/images/logo.png", "logo");
+        site.write("pages/blog/photo.png", "photo");
+        site.write(
+            "pages/blog/post.html",
+            r#"<html><body><asset src="/images/logo.png" alt="Logo" /><asset src="photo.png" hash="" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        let page = site.output("blog/post.html");
+        assert!(
+            page.contains(r#"<img src="../images/logo.png" alt="Logo">"#),
+            "{}",
+            page
+        );
+        assert_eq!(site.output("images/logo.png"), "logo");
+
+        let hashed = Regex::new(r#"<img src="(photo\.[0-9a-f]+\.png)">"#)
+            .unwrap()
+            .captures(&page)
+            .unwrap_or_else(|| panic!("{}", page))[1]
+            .to_string();
+        assert_eq!(site.output(&format!("blog/{}", hashed)), "photo");
+    }
+
+    #[test]
+    fn stops_an_element_that_keeps_expanding_into_itself() {
+        let site = Site::new();
+        site.write("elements/item.html", "<li><self.inner /></li>");
+        let items = "<item>x</item>".repeat(5000);
+        site.write(
+            "pages/index.html",
+            &format!("<html><body><ul>{}</ul></body></html>", items),
+        );
+        site.write("elements/grow.html", "<grow /><grow />");
+        site.write("pages/grow.html", "<html><body><grow /></body></html>");
+
+        let start = time::Instant::now();
+        let err = site
+            .generate(Options {
+                max_expansions: 10_000,
+                ..quiet()
+            })
+            .unwrap_err();
+        assert!(start.elapsed() < time::Duration::from_secs(30));
+
+        let BuildError::Invalid(messages) = &err else {
+            panic!("expected too many expansions, got {:?}", err);
+        };
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("grow.html"), "{}", messages[0]);
+        assert!(messages[0].contains("<grow>"), "{}", messages[0]);
+        assert_eq!(
+            site.output("index.html").matches("<li>x</li>").count(),
+            5000
+        );
+    }
+
+    #[test]
+    fn names_index_documents_as_asked() {
+        let site = Site::new();
+        site.write("pages/index.html", "<html><body>Home</body></html>");
+        site.write("pages/about.html", "<html><body>About</body></html>");
+
+        site.generate(Options {
+            pretty_urls: true,
+            index_name: Some("default.html".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+
+        assert!(site.path("output/default.html").exists());
+        assert!(site.path("output/about/default.html").exists());
+        assert!(!site.path("output/index.html").exists());
+        assert!(!site.path("output/about/index.html").exists());
+    }
+
+    #[test]
+    fn renders_the_version_into_urls() {
+        let site = Site::new();
+        site.write(
+            "elements/stylesheet.html",
+            r#"<link rel="stylesheet" href="/style.css?v=${build.version}" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            "<html><head><stylesheet /></head></html>",
+        );
+
+        site.generate(Options {
+            version: Some("1.2.3".to_string()),
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><head>\
+             <link rel=\"stylesheet\" href=\"/style.css?v=1.2.3\"></head></html>"
+        );
+
+        site.generate(quiet()).unwrap();
+        assert!(site.output("index.html").contains("href=\"/style.css?v=\""));
+    }
+
+    #[test]
+    fn keeps_the_space_at_the_ends_of_preserved_contents() {
+        let site = Site::new();
+        site.write(
+            "elements/em-b.html",
+            r#"<b><self.inner preserve-space="" /></b>"#,
+        );
+        site.write("elements/plain-b.html", "<b><self.inner /></b>");
+        site.write(
+            "pages/index.html",
+            "<html><body><p>Very<em-b>  bold  </em-b>text</p><p>Very<plain-b> bold </plain-b>text</p></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <p>Very<b> bold </b>text</p><p>Very<b>bold</b>text</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn warns_about_attributes_the_definition_never_uses() {
+        let site = Site::new();
+        site.write("elements/card.html", r#"<div title="${self.title}" />"#);
+        site.write(
+            "elements/field.html",
+            r#"<input class="field" self.rest="" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><card titel="Hi" /><field name="email" /></body></html>"#,
+        );
+
+        let mut generator = site.generator(quiet()).unwrap();
+        generator.run().unwrap();
+        // Along with the missing "title", but nothing about the attribute
+        // forwarded by self.rest
+        let messages: Vec<String> = generator
+            .diagnostics()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert!(
+            messages[0].starts_with("<card> was given the attribute \"titel\""),
+            "{:?}",
+            messages
+        );
+        assert!(messages[1].contains("\"title\""), "{:?}", messages);
+
+        let err = site
+            .generate(Options {
+                strict: true,
+                ..quiet()
+            })
+            .unwrap_err();
+        assert!(matches!(err, BuildError::Undefined(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn copies_partials_wherever_they_are_used() {
+        let site = Site::new();
+        site.write(
+            "elements/site-footer.html",
+            "<footer><p>Made with care</p></footer>",
+        );
+        site.write("elements/card.html", "<div><self.inner /></div>");
+        site.write(
+            "pages/index.html",
+            r#"<html><body><partial name="site-footer" /><site-footer /></body></html>"#,
+        );
+
+        let mut generator = site.generator(quiet()).unwrap();
+        generator.run().unwrap();
+
+        let is_partial = |name: &str| {
+            generator
+                .library()
+                .elements()
+                .values()
+                .find(|defn| defn.name() == name)
+                .unwrap()
+                .is_partial()
+        };
+        assert!(is_partial("site-footer"));
+        assert!(!is_partial("card"));
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <footer><p>Made with care</p></footer><footer><p>Made with care</p></footer></body></html>"
+        );
+    }
+
+    #[test]
+    fn redirect_stubs_point_to_the_pretty_url() {
+        let site = Site::new();
+        site.write("pages/about.html", "<html><body><p>About</p></body></html>");
+
+        site.generate(Options {
+            pretty_urls: true,
+            redirect_stubs: true,
+            ..quiet()
+        })
+        .unwrap();
+
+        assert!(site.path("output/about/index.html").exists());
+        let stub = site.output("about.html");
+        assert!(stub.contains(r#"<meta http-equiv="refresh" content="0; url=/about/">"#));
+        assert!(stub.contains(r#"<link rel="canonical" href="/about/">"#));
+    }
+
+    #[test]
+    fn loads_elements_from_a_bundle() {
+        let site = Site::new();
+        site.write(
+            "components.html",
+            r#"<component name="myicon"><img class="icon" src="${self.src}" /></component>
+<component name="myicongrid"><div class="icon-grid"><self.inner /></div></component>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><myicongrid><myicon src="a.png" /></myicongrid></body></html>"#,
+        );
+
+        let mut xot = new_xot();
+        let library = ElementLibrary::from_bundle(&mut xot, &site.path("components.html")).unwrap();
+        assert_eq!(library.elements().len(), 2);
+        // a directory is no bundle
+        assert!(ElementLibrary::from_bundle(&mut xot, &site.path("pages")).is_err());
+
+        generate(
+            &site.path("pages"),
+            &[site.path("components.html")],
+            &site.path("output"),
+            quiet(),
+        )
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <div class=\"icon-grid\"><img class=\"icon\" src=\"a.png\"></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn repeats_a_row_for_every_attribute() {
+        let site = Site::new();
+        site.write(
+            "elements/specs.html",
+            "<table><caption><self.title /></caption><foreachattr exclude=\"title\">\
+             <tr><th><attr.name /></th><td><attr.value /></td></tr></foreachattr></table>",
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><specs title="Laptop" cpu="M3" ram="16 GB" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><table><caption>Laptop</caption>\
+             <tr><th>cpu</th><td>M3</td></tr><tr><th>ram</th><td>16 GB</td></tr>\
+             </table></body></html>"
+        );
+    }
+
+    #[test]
+    fn merges_neighbouring_text_nodes() {
+        let mut xot = new_xot();
+        let document = xot.parse("<p>Hello<br/>world</p>").unwrap();
+        let p = xot.document_element(document).unwrap();
+        let br = xot.children(p).nth(1).unwrap();
+        let comma = xot.new_text(", ");
+        xot.replace(br, comma).unwrap();
+        assert_eq!(xot.children(p).count(), 3);
+
+        consolidate_text(&mut xot, document, &DocumentState::default()).unwrap();
+
+        assert_eq!(xot.children(p).count(), 1);
+        assert_eq!(xot.text_content_str(p), Some("Hello, world"));
+    }
+
+    #[test]
+    fn adds_a_class_when_its_condition_holds() {
+        let site = Site::new();
+        site.write(
+            "elements/navlink.html",
+            r#"<a class="btn" href="${self.href}"><addclass name="active" self.current="true" /><self.inner /></a>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><navlink href="/" current="true">Home</navlink><navlink href="/about.html" current="false">About</navlink></body></html>"#,
+        );
+        site.write("pages/about.html", "<html><body></body></html>");
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <a class=\"btn active\" href=\"/\">Home</a>\
+             <a class=\"btn\" href=\"/about.html\">About</a></body></html>"
+        );
+    }
+
+    #[test]
+    fn definitions_can_start_with_a_comment() {
+        let site = Site::new();
+        site.write(
+            "elements/notice.html",
+            "<!-- A box for important notes -->\n<aside class=\"notice\"><self.inner /></aside>",
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body><notice>Mind the gap</notice></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <aside class=\"notice\">Mind the gap</aside></body></html>"
+        );
+    }
+
+    #[test]
+    fn keeps_processing_instructions_at_the_start_of_a_definition() {
+        let site = Site::new();
+        site.write(
+            "elements/icon.html",
+            "<?xml version=\"1.0\"?>\n<?xml-stylesheet href=\"icon.css\"?>\n<svg class=\"icon\" />",
+        );
+        site.write("pages/index.html", "<html><body><icon /></body></html>");
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <?xml-stylesheet href=\"icon.css\"><svg class=\"icon\"></svg></body></html>"
+        );
+    }
+
+    #[test]
+    fn separates_iterations_without_a_trailing_separator() {
+        let site = Site::new();
+        site.write(
+            "elements/taglist.html",
+            r#"<p><foreachvalue.tags sep="," separator=", "><self.value /></foreachvalue.tags></p>"#,
+        );
+        site.write(
+            "elements/namelist.html",
+            r#"<p><foreachchild.name separator=", "><name /></foreachchild.name></p>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><taglist tags="a,b,c" /><namelist><i>a</i><i>b</i><i>c</i></namelist></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>a, b, c</p>\
+             <p><i>a</i>, <i>b</i>, <i>c</i></p></body></html>"
+        );
+    }
+
+    #[test]
+    fn provides_the_title_to_the_head() {
+        let site = Site::new();
+        site.write(
+            "elements/sitetitle.html",
+            r#"<title><consume key="title">My site</consume></title>"#,
+        );
+        site.write(
+            "elements/pageheading.html",
+            r#"<h1><provide key="title"><self.text /></provide><self.text /></h1>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><head><sitetitle /></head><body><pageheading text="About us" /></body></html>"#,
+        );
+        site.write(
+            "pages/plain.html",
+            "<html><head><sitetitle /></head><body><p>Hi</p></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><head><title>About us</title></head>\
+             <body><h1>About us</h1></body></html>"
+        );
+        assert_eq!(
+            site.output("plain.html"),
+            "<!DOCTYPE html><html><head><title>My site</title></head>\
+             <body><p>Hi</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn only_leaves_other_outputs_untouched() {
+        let site = Site::new();
+        site.write(
+            "elements/pagepath.html",
+            r#"<p data-path="${self.filepath}"></p>"#,
+        );
+        site.write("pages/index.html", "<html><body>Home</body></html>");
+        site.write(
+            "pages/blog/post.html",
+            "<html><body><pagepath /></body></html>",
+        );
+        site.write("output/index.html", "from an earlier build");
+        site.write("output/old.html", "stale");
+
+        site.generate(Options {
+            only: Some(path::PathBuf::from("blog")),
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("blog/post.html"),
+            "<!DOCTYPE html><html><body><p data-path=\"/blog/post.html\"></p></body></html>"
+        );
+        assert_eq!(site.output("index.html"), "from an earlier build");
+        assert_eq!(site.output("old.html"), "stale");
+    }
+
+    #[test]
+    fn escaped_dollars_leave_other_text_alone() {
+        let site = Site::new();
+        site.write(
+            "elements/template-box.html",
+            r#"<div data-template="${self.template}"><self.inner /></div>"#,
+        );
+        site.write(
+            "pages/index.html",
+            "<html><body>\
+             <template-box template=\"Hello, $${name}!\">\u{E000} private</template-box>\
+             </body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <div data-template=\"Hello, ${name}!\">\u{E000} private</div></body></html>"
+        );
+    }
+
+    #[test]
+    fn resolves_values_from_several_data_files() {
+        let site = Site::new();
+        site.write("data/site.json", r#"{"title": "My site"}"#);
+        site.write("data/nav.toml", "home = \"/index.html\"\n");
+        site.write(
+            "elements/sitelink.html",
+            r#"<a href="${nav.home}" title="${site.title}"><site.title /></a>"#,
+        );
+        site.write("pages/index.html", "<html><body><sitelink /></body></html>");
+
+        site.generate(Options {
+            data: Some(site.path("data")),
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <a href=\"/index.html\" title=\"My site\">My site</a></body></html>"
+        );
+    }
+
+    #[test]
+    fn renders_nothing_unless_its_condition_holds() {
+        let site = Site::new();
+        site.write(
+            "elements/banner.html",
+            r#"<renderif self.show="true" /><div class="banner"><self.inner /></div>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><banner show="false">Hidden</banner><banner show="true">Shown</banner></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div class=\"banner\">Shown</div></body></html>"
+        );
+    }
+
+    #[test]
+    fn no_overwrite_merges_sources_but_refuses_to_replace_files() {
+        let site = Site::new();
+        site.write("pages/index.html", "<html><body><p>Home</p></body></html>");
+        site.write("blog/post.html", "<html><body><p>Post</p></body></html>");
+        let options = || Options {
+            no_overwrite: true,
+            ..quiet()
+        };
+
+        site.generate(options()).unwrap();
+        generate(
+            &site.path("blog"),
+            &[site.path("elements")],
+            &site.path("output"),
+            options(),
+        )
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><p>Home</p></body></html>"
+        );
+        assert_eq!(
+            site.output("post.html"),
+            "<!DOCTYPE html><html><body><p>Post</p></body></html>"
+        );
+
+        match site.generate(options()) {
+            Err(BuildError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::AlreadyExists),
+            result => panic!("expected the build to fail, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn writes_void_elements_as_xhtml() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            r#"<html><head><script src="a.js"></script><style>p > b { color: red }</style></head><body><p>a<br />b</p><div></div><script>if (1 &lt; 2 &amp;&amp; 3 > 2) go();</script></body></html>"#,
+        );
+
+        site.generate(Options {
+            serialization: Serialization::Xhtml,
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html>\n<html xmlns=\"http://www.w3.org/1999/xhtml\">\
+             <head><script src=\"a.js\"></script><style><![CDATA[p > b { color: red }]]></style></head>\
+             <body><p>a<br/>b</p><div></div><script><![CDATA[if (1 < 2 && 3 > 2) go();]]></script></body></html>"
+        );
+    }
+
+    #[test]
+    fn source_maps_name_the_element_behind_each_range() {
+        let site = Site::new();
+        site.write(
+            "elements/button.html",
+            r#"<a class="btn" href="${self.href}"><self.inner /></a>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><p>Hi</p><button href="/go.html">Go</button></body></html>"#,
+        );
+        site.write("pages/go.html", "<html><body></body></html>");
+
+        site.generate(Options {
+            source_maps: true,
+            ..quiet()
+        })
+        .unwrap();
+
+        let output = site.output("index.html");
+        let map: serde_json::Value = serde_json::from_str(&site.output("index.html.map")).unwrap();
+        assert_eq!(map["source"], "/index.html");
+        let elements = map["elements"].as_array().unwrap();
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0]["element"], "button");
+        assert_eq!(elements[0]["start"]["line"], 1);
+        assert_eq!(elements[0]["end"]["line"], 1);
+        let start = elements[0]["start"]["column"].as_u64().unwrap() as usize;
+        let end = elements[0]["end"]["column"].as_u64().unwrap() as usize;
+        assert_eq!(
+            &output[start - 1..end - 1],
+            "<a class=\"btn\" href=\"/go.html\">Go</a>"
+        );
+    }
+
+    #[test]
+    fn keeps_whitespace_text_as_a_single_space() {
+        let site = Site::new();
+        site.write(
+            "pages/index.html",
+            "<html><body><div>a</div>\n    <div>b</div></body></html>",
+        );
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div>a</div><div>b</div></body></html>"
+        );
+
+        site.generate(Options {
+            keep_whitespace_text: true,
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div>a</div> <div>b</div></body></html>"
+        );
+    }
+
+    #[test]
+    fn uses_a_let_value_in_several_places() {
+        let site = Site::new();
+        site.write(
+            "elements/card.html",
+            r#"<let name="base" value="card card-${self.variant}" /><div class="${base}"><h2 class="${base}-title"><self.title /></h2></div>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><card variant="wide" title="Hello" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div class=\"card card-wide\">\
+             <h2 class=\"card card-wide-title\">Hello</h2></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn merges_a_configured_attribute() {
+        let site = Site::new();
+        site.write(
+            "elements/textfield.html",
+            r#"<input class="field" data-variant="base" self.rest="" />"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><textfield class="wide" data-variant="large" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <input class=\"field wide\" data-variant=\"base\"></body></html>"
+        );
+
+        site.generate(Options {
+            merge_attributes: vec!["class".to_string(), "data-variant".to_string()],
+            ..quiet()
+        })
+        .unwrap();
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>\
+             <input class=\"field wide\" data-variant=\"base large\"></body></html>"
+        );
+    }
+
+    #[test]
+    fn copies_passthrough_directories_byte_for_byte() {
+        let site = Site::new();
+        let raw = "<!-- not templated -->\n<p>${self.x}<br>unclosed\r\n";
+        site.write("pages/static/demo.html", raw);
+        site.write("pages/index.html", "<html><body>Home</body></html>");
+
+        site.generate(Options {
+            passthrough: vec![path::PathBuf::from("static")],
+            ..quiet()
+        })
+        .unwrap();
+
+        assert_eq!(
+            fs::read(site.path("output/static/demo.html")).unwrap(),
+            raw.as_bytes()
+        );
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body>Home</body></html>"
+        );
+    }
+
+    #[test]
+    fn repeats_only_the_children_of_a_named_group() {
+        let site = Site::new();
+        site.write(
+            "elements/tabs.html",
+            r#"<div class="tabs"><ul><foreachchild.title from="titles"><li><title /></li></foreachchild.title></ul><foreachchild.panel from="panels"><section><panel /></section></foreachchild.panel></div>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><tabs><titles><b>One</b><b>Two</b></titles><panels><p>First</p><p>Second</p></panels></tabs></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div class=\"tabs\">\
+             <ul><li><b>One</b></li><li><b>Two</b></li></ul>\
+             <section><p>First</p></section><section><p>Second</p></section></div></body></html>"
+        );
+    }
+
+    #[test]
+    fn lowercases_output_names() {
+        let site = Site::new();
+        site.write("pages/Logo.PNG", "not really a png");
+        site.write("pages/About.html", "<html><body><p>About</p></body></html>");
+
+        site.generate(Options {
+            lowercase_names: true,
+            ..quiet()
+        })
+        .unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(site.path("output"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["about.html", "logo.png"]);
+        assert_eq!(site.output("logo.png"), "not really a png");
+    }
+
+    #[test]
+    fn self_pagedir_is_the_directory_of_the_page() {
+        let site = Site::new();
+        site.write(
+            "elements/up-link.html",
+            r#"<a href="${self.pagedir}../" dir="${self.dir}">Up</a>"#,
+        );
+        site.write(
+            "pages/blog/post.html",
+            r#"<html><body><up-link dir="rtl" /></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("blog/post.html"),
+            "<!DOCTYPE html><html><body>\
+             <a href=\"/blog/../\" dir=\"rtl\">Up</a></body></html>"
+        );
+    }
+
+    #[test]
+    fn nested_loops_render_every_item_of_every_section() {
+        let site = Site::new();
+        site.write(
+            "elements/section-list.html",
+            r#"<div><foreachchild.section><h2><section.title /></h2><ul><foreachchild.item in="section"><li class="${section.kind}"><item /></li></foreachchild.item></ul></foreachchild.section></div>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><section-list><group title="Fruit" kind="sweet"><b>Apple</b><b>Pear</b></group><group title="Vegetables" kind="green"><b>Kale</b><b>Leek</b><b>Pea</b></group></section-list></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><div>\
+             <h2>Fruit</h2><ul>\
+             <li class=\"sweet\"><b>Apple</b></li><li class=\"sweet\"><b>Pear</b></li></ul>\
+             <h2>Vegetables</h2><ul>\
+             <li class=\"green\"><b>Kale</b></li><li class=\"green\"><b>Leek</b></li>\
+             <li class=\"green\"><b>Pea</b></li></ul>\
+             </div></body></html>"
+        );
+    }
+
+    #[test]
+    fn passes_markup_as_a_prop() {
+        let site = Site::new();
+        site.write(
+            "elements/iconbutton.html",
+            r#"<button class="icon"><self.icon /><span><self.inner /></span></button>"#,
+        );
+        site.write(
+            "pages/index.html",
+            r#"<html><body><iconbutton><prop name="icon"><svg viewBox="0 0 16 16"><path d="M0 0h16v16z" /></svg></prop>Save</iconbutton></body></html>"#,
+        );
+
+        site.generate(quiet()).unwrap();
+
+        assert_eq!(
+            site.output("index.html"),
+            "<!DOCTYPE html><html><body><button class=\"icon\">\
+             <svg viewBox=\"0 0 16 16\"><path d=\"M0 0h16v16z\"></path></svg>\
+             <span>Save</span></button></body></html>"
+        );
+    }
+}
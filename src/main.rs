@@ -1,717 +1,608 @@
-use clap::Parser;
-use regex::{Captures, Regex};
-use std::{collections::HashMap, fs, io, path};
-use xot::Xot;
-
-struct Context {
-    // path of the document currently being generated, relative
-    // to the root of the source directory
-    file_path: String,
-    regex_dollar_expansion: Regex,
-    regex_or_expr: Regex,
-}
+use clap::{CommandFactory, Parser};
+use html_generator::{
+    clean, new_xot, AttributeQuotes, BuildError, Config, Diagnostic, ElementDefinition,
+    ElementLibrary, ElementOverrides, Entities, Generator, Options, Serialization,
+};
 
-impl Context {
-    fn new(file_path: String) -> Context {
-        let regex_dollar_expansion = Regex::new(r"\$\{([a-zA-Z0-9_\-\.\|]+)}").unwrap();
-        let regex_or_expr = Regex::new(r"^([a-zA-Z0-9_\-\.]+)\|\|([a-zA-Z0-9_\-\.]+)$").unwrap();
+#[derive(Parser, Debug)]
+#[command(about)]
+struct Args {
+    /// Directory of pages to generate [default: from the config file]
+    source: Option<std::path::PathBuf>,
+    /// Directory of element definitions or bundle file, or several separated by commas [default: from the config file]
+    elements: Option<String>,
+    /// Directory to write the site to [default: from the config file]
+    destination: Option<std::path::PathBuf>,
 
-        Context {
-            file_path,
-            regex_dollar_expansion,
-            regex_or_expr,
-        }
-    }
-}
+    /// Read settings from this file [default: baumkuchen.toml, if it exists]
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
 
-// Remove comments and outer whitespace from an existing node
-fn minify(xot: &mut Xot, node: xot::Node) -> Result<(), xot::Error> {
-    if xot.is_comment(node) {
-        return xot.remove(node);
-    }
+    /// Follow symbolic links in the source directory instead of skipping them
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    follow_symlinks: Option<bool>,
 
-    if let Some(text) = xot.text(node) {
-        let orig_text = text.get();
+    /// Number of pages to generate in parallel [default: number of logical CPUs]
+    #[arg(long, short)]
+    jobs: Option<std::num::NonZeroUsize>,
 
-        // Replace all runs of whitespace with just a single space
-        let mut trimmed = {
-            let mut s = String::new();
-            let mut words = orig_text.split_whitespace();
-            if let Some(w) = words.next() {
-                s = w.to_string();
-            }
-            while let Some(w) = words.next() {
-                s += " ";
-                s += w;
-            }
-            s
-        };
+    /// After building, list all elements that were never used
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    report_unused: Option<bool>,
 
-        // Add backing a leading space if it was removed and there is a previous node
-        {
-            if xot.previous_sibling(node).is_some() && orig_text.starts_with(char::is_whitespace) {
-                trimmed.insert(0, ' ');
-            }
-        }
+    /// Don't print anything but errors
+    #[arg(long, short, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    quiet: Option<bool>,
 
-        // Add backing a trailing space if it was removed and there is a next node
-        {
-            if xot.next_sibling(node).is_some() && orig_text.ends_with(char::is_whitespace) {
-                trimmed.push(' ');
-            }
-        }
+    /// Give all headings without an id one based on their text
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    auto_ids: Option<bool>,
 
-        // Remove the node outright if it is empty or all white space
-        // NOTE: this implicitly assumes that both adjacent siblings are not inline elements
-        if trimmed.chars().all(char::is_whitespace) {
-            return xot.remove(node);
-        }
+    /// After building, report root-relative links that don't lead to a generated page
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    check_links: Option<bool>,
 
-        if trimmed != orig_text {
-            xot.text_mut(node).unwrap().set(trimmed);
-        }
-    }
+    /// Keep comments and whitespace in generated pages
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    no_minify: Option<bool>,
 
-    let children: Vec<xot::Node> = xot.children(node).collect();
-    for child in &children {
-        minify(xot, *child)?;
-    }
+    /// When minifying, collapse whitespace between elements into a single space instead of removing it
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true, conflicts_with = "no_minify")]
+    keep_whitespace_text: Option<bool>,
 
-    Ok(())
-}
+    /// Attributes whose values are combined rather than replaced when forwarded to an element that has them already, e.g. "class,style,data-variant" [default: class,style]
+    #[arg(long = "merge-attr", value_name = "NAME", value_delimiter = ',')]
+    merge_attributes: Vec<String>,
 
-// Look for and replace single instances of a named tag with
-// the given replacement
-fn substitute_tag(
-    xot: &mut Xot,
-    node: xot::Node,
-    tag_name: xot::NameId,
-    replacement: xot::Node,
-    invocation: xot::Node,
-    context: &Context,
-) -> Result<(), xot::Error> {
-    debug_assert!(!xot.is_removed(node));
-    debug_assert!(!xot.is_removed(replacement));
-    let xot::Value::Element(elem) = xot.value(node) else {
-        return Ok(());
-    };
-    if elem.name() == tag_name {
-        let r = xot.clone(replacement);
-        // expand and propagate any attributes
-        let orig_attrs: Vec<(String, String)> = xot
-            .attributes(node)
-            .iter()
-            .map(|(key, value)| {
-                let key = xot.name_ns_str(key).0.to_string();
-                let value = expand_string(xot, value, invocation, context);
-                (key, value)
-            })
-            .collect();
-        xot.replace(node, r)?;
-        for (key, value) in orig_attrs {
-            let key_id = xot.add_name(&key);
-            xot.attributes_mut(r).insert(key_id, value);
-        }
-        return Ok(());
-    }
-    let children: Vec<xot::Node> = xot.children(node).collect();
-    for child in children {
-        substitute_tag(xot, child, tag_name, replacement, invocation, context)?;
-    }
-    Ok(())
-}
+    /// Surround the markup produced by each element with comments naming it (requires --no-minify)
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    trace_components: Option<bool>,
 
-fn substitute_foreach(
-    xot: &mut Xot,
-    node: xot::Node,
-    invocation: xot::Node,
-    context: &Context,
-) -> Result<(), xot::Error> {
-    let loop_var_str = xot
-        .name_ns_str(xot.node_name(node).unwrap())
-        .0
-        .strip_prefix("foreachchild.")
-        .unwrap();
-
-    debug_assert!(xot.children(node).filter(|c| xot.is_element(*c)).count() == 1);
-
-    let Some(loop_var) = xot.name(&loop_var_str) else {
-        println!(
-            "Warning: found tag \"<foreachchild.{}>\" but there is nothing named \"{}\"",
-            loop_var_str, loop_var_str
-        );
-        return Ok(());
-    };
+    /// How to write characters outside of ASCII: "utf8" or "ascii" (as numeric entities) [default: utf8]
+    #[arg(long)]
+    entities: Option<Entities>,
 
-    let node_child = xot
-        .children(node)
-        .filter(|c| xot.is_element(*c))
-        .next()
-        .unwrap();
-
-    let children: Vec<xot::Node> = xot.children(invocation).collect();
-    for inv_child in children {
-        // don't replace outer white space, text, or comments
-        if !xot.is_element(inv_child) {
-            continue;
-        }
-        let ch = xot.clone(node_child);
+    /// How to quote attribute values: "double", "single", or "minimal" [default: double]
+    #[arg(long)]
+    attribute_quotes: Option<AttributeQuotes>,
 
-        xot.insert_before(node, ch)?;
+    /// How to write pages: "html5", "xhtml" (e.g. <br/>), or "xml" [default: html5]
+    #[arg(long = "serialize", value_name = "FORMAT")]
+    serialization: Option<Serialization>,
 
-        substitute_tag(xot, ch, loop_var, inv_child, invocation, context)?;
-    }
-    // xot.remove(node)?;
-    xot.detach(node)?;
-    return Ok(());
-}
+    /// Doctype to start every page with instead of the one that goes with --serialize, e.g. 'html PUBLIC "-//W3C//DTD XHTML 1.0 Strict//EN" "http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd"'
+    #[arg(long)]
+    doctype: Option<String>,
 
-fn evaluate_expression(xot: &Xot, expr: &str, invocation: xot::Node, context: &Context) -> String {
-    // 'self.filepath' evaluates to context's filepath
-    if expr == "self.filepath" {
-        return context.file_path.to_string();
-    }
-
-    // "A||B" evaluates expression A and returns it if defined and non-empty,
-    // otherwise evaluates and returns expression B
-    // TODO: if more general context-free expressions are needed,
-    // implement a proper parser
-    if let Some(captures) = context.regex_or_expr.captures(expr) {
-        let a = &captures[1];
-        let b = &captures[2];
-        let a_val = evaluate_expression(xot, a, invocation, context);
-        if !a_val.is_empty() {
-            return a_val;
-        }
-        return evaluate_expression(xot, b, invocation, context);
-    }
+    /// Write a .map file of JSON next to every page, telling which parts of it came out of which elements
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    source_maps: Option<bool>,
 
-    // 'self.xyz' evaluates to contents of 'xyz' attribute of invocation element
-    if let Some(attr_name) = expr.strip_prefix("self.") {
-        let Some(attr_value) = xot
-            .name(attr_name)
-            .map(|id| xot.attributes(invocation).get(id))
-            .flatten()
-        else {
-            // println!("Warning: reference to missing attribute \"{}\"", attr_name);
-            return "".to_string();
-        };
+    /// Name of the environment being built for, available to elements as "build.env"
+    #[arg(long)]
+    env: Option<String>,
 
-        debug_assert!(!attr_value.contains('$'));
-        return attr_value.to_string();
-    }
+    /// Version of the site, available to elements as "build.version" [default: from a VERSION file, or else git describe]
+    #[arg(long)]
+    version: Option<String>,
 
-    println!("Warning: unrecognized expression: \"{}\"", expr);
-    "".to_string()
-}
+    /// Directory of .json and .toml data files to make available to elements, each named after its file
+    #[arg(long, visible_alias = "data-dir", value_name = "DIR")]
+    data: Option<std::path::PathBuf>,
 
-fn expand_string(xot: &Xot, expr_string: &str, invocation: xot::Node, context: &Context) -> String {
-    context
-        .regex_dollar_expansion
-        .replace_all(expr_string, |captures: &Captures| -> String {
-            let s = evaluate_expression(xot, &captures[1], invocation, context);
-            // println!("Expanding \"{}\" into \"{}\"", &captures[0], s);
-            s
-        })
-        .to_string()
-}
+    /// Write the text of elements with this local name as CDATA, e.g. "script" for scripts in inline SVG (can be repeated)
+    #[arg(long = "cdata-element", value_name = "NAME")]
+    cdata_elements: Vec<String>,
 
-fn expression_matches_pattern(
-    xot: &Xot,
-    expr_string: &str,
-    pattern_string: &str,
-    invocation: xot::Node,
-    context: &Context,
-) -> bool {
-    // println!(
-    //     "Testing whether expression \"{}\" == \"{}\"",
-    //     expr_string, pattern_string
-    // );
-
-    // Expand any expressions
-    let expr_value = evaluate_expression(xot, expr_string, invocation, context);
-    let pattern_value = expand_string(xot, pattern_string, invocation, context);
-
-    // println!(" -> \"{}\" == \"{}\"", expr_value, pattern_value);
-
-    // Wrap pattern in '^' and '$' to force matching the entire string
-    let pattern = format!("^{}$", pattern_value);
-    let re = Regex::new(&pattern).expect("Invalid regex");
-    re.is_match(&expr_value)
-}
+    /// Fail on references to missing attributes or unknown expressions instead of warning
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    strict: Option<bool>,
 
-fn substitute_if(
-    xot: &mut Xot,
-    node: xot::Node,
-    invocation: xot::Node,
-    context: &Context,
-) -> Result<(), xot::Error> {
-    // expect a single attribute of the form `expression="value-pattern"` and evaluate it
-    let condition = {
-        let attrs = xot.attributes(node);
-        let mut attrs_iter = attrs.iter();
-        let (attr_name_id, pattern) = attrs_iter.next().expect("msg");
-        assert!(attrs_iter.next().is_none());
-        let expr = xot.name_ns_str(attr_name_id).0;
-        expression_matches_pattern(xot, expr, pattern, invocation, context)
-    };
+    /// Keep only the first of any identical <script> or <style> elements on a page
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    dedupe_assets: Option<bool>,
+
+    /// Move the scripts and styles kept by --dedupe-assets into the <head>
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    hoist_assets: Option<bool>,
+
+    /// Empty the destination directory and exit without generating anything
+    #[arg(long, conflicts_with = "no_clean")]
+    clean_only: bool,
+
+    /// Generate into the destination directory without emptying it first
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    no_clean: Option<bool>,
 
-    // look for a 'then' child node
-    let node_then = xot
-        .name("then")
-        .map(|id| {
-            for child in xot.children(node) {
-                if xot.node_name(child) == Some(id) {
-                    return Some(child);
-                }
-            }
-            None
-        })
-        .flatten();
-
-    // look for an 'else' child node
-    let node_else = xot
-        .name("else")
-        .map(|id| {
-            for child in xot.children(node) {
-                if xot.node_name(child) == Some(id) {
-                    return Some(child);
-                }
-            }
-            None
-        })
-        .flatten();
+    /// Instead of emptying the destination directory first, delete whatever in it wasn't generated afterwards
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true, conflicts_with = "clean_only")]
+    sync: Option<bool>,
 
-    if node_then.is_none() && node_else.is_none() {
-        println!("Warning: <if> element without a nested <then> or <else> element");
-    }
+    /// Fail instead of overwriting any page or file already in the destination, e.g. when merging several sources into one destination. Implies --no-clean
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true, conflicts_with = "clean_only")]
+    no_overwrite: Option<bool>,
 
-    if condition {
-        // if match, replace with contents of 'then'
-        if let Some(node_then) = node_then {
-            let children: Vec<xot::Node> = xot.children(node_then).collect();
-            for ch in children {
-                let ch = xot.clone(ch);
-                xot.insert_before(node, ch)?;
-            }
-        }
-        xot.remove(node)
-    } else {
-        // otherwise, replace with contents of 'else'
-        if let Some(node_else) = node_else {
-            let children: Vec<xot::Node> = xot.children(node_else).collect();
-            for ch in children {
-                let ch = xot.clone(ch);
-                xot.insert_before(node, ch)?;
-            }
-        }
-        xot.remove(node)
-    }
-}
+    /// List every element loaded from the element directories, along with the file it came from, and exit without generating anything
+    #[arg(long, conflicts_with_all = ["clean_only", "sync"])]
+    print_library: bool,
 
-fn substitute_attr(
-    xot: &mut Xot,
-    node: xot::Node,
-    invocation: xot::Node,
-) -> Result<(), xot::Error> {
-    let attr_name = xot
-        .name_ns_str(xot.node_name(node).unwrap())
-        .0
-        .strip_prefix("self.")
-        .unwrap();
-
-    if attr_name == "inner" {
-        // replace tags <self.inner> with the node's children
-        let children: Vec<xot::Node> = xot.children(invocation).collect();
-        for ch in children {
-            let r = xot.clone(ch);
-            xot.insert_before(node, r)?;
-        }
-        xot.remove(node)?;
+    /// Only generate the pages and files in this directory or file of the source, e.g. "blog", leaving the rest of the destination as it is (implies --no-clean)
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["clean_only", "sync"])]
+    only: Option<std::path::PathBuf>,
 
-        return Ok(());
-    }
+    /// Directory of the source, e.g. "static", to copy as it is without generating any pages in it (can be repeated)
+    #[arg(long, value_name = "DIR")]
+    passthrough: Vec<std::path::PathBuf>,
 
-    let Some(attr_id) = xot.name(attr_name) else {
-        println!(
-            "Warning: undefined attribute \"{}\" referenced in node <self.{}>",
-            attr_name, attr_name
-        );
-        return Ok(());
-    };
+    /// Write pages and copied files under lowercase names, e.g. Logo.PNG as logo.png
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    lowercase_names: Option<bool>,
 
-    if let Some(attr_val) = xot.attributes(invocation).get(attr_id).cloned() {
-        // replace tags <self.xyz> with attribute value xyz if defined
-        if !attr_val.is_empty() {
-            let r = xot.new_text(&attr_val);
-            xot.insert_before(node, r)?;
-        }
-        // xot.remove(node)?;
-        xot.detach(node)?;
-    }
+    /// Remove this from the end of the names of pages and copied files, before the extension, e.g. "_src" to write logo_src.png as logo.png
+    #[arg(long, value_name = "SUFFIX")]
+    strip_name_suffix: Option<String>,
 
-    Ok(())
-}
+    /// Another directory of element definitions or bundle file, loaded after ELEMENTS (can be repeated)
+    #[arg(long = "elements", value_name = "DIR", value_delimiter = ',')]
+    more_elements: Vec<std::path::PathBuf>,
 
-// Recursively visit all string attributes of all descendants of a node
-// and expand expressions
-fn expand_all_attr_strings(
-    xot: &mut Xot,
-    node: xot::Node,
-    invocation: xot::Node,
-    context: &Context,
-) -> Result<(), xot::Error> {
-    // Visit all attributes
-    {
-        let keys: Vec<xot::NameId> = xot.attributes(node).keys().collect();
-        for key in keys {
-            let Some(value) = xot.attributes(node).get(key) else {
-                continue;
-            };
-            let new_value = expand_string(xot, &value, invocation, context);
-            *xot.attributes_mut(node).get_mut(key).unwrap() = new_value;
-        }
-    }
+    /// What to do when an element is defined in more than one directory: "error" or "replace" (later directories win) [default: error]
+    #[arg(long)]
+    element_overrides: Option<ElementOverrides>,
 
-    let children: Vec<xot::Node> = xot.children(node).collect();
-    for child in children {
-        expand_all_attr_strings(xot, child, invocation, context)?;
-    }
+    /// After building, print how many pages and files were written, how many elements were expanded, and how long it took
+    #[arg(long)]
+    stats: bool,
 
-    Ok(())
-}
+    /// Parse and minify files with these extensions as XML instead of copying them, e.g. "xml,svg"
+    #[arg(long = "process-ext", value_name = "EXT", value_delimiter = ',')]
+    process_extensions: Vec<String>,
 
-// Process a node, recursively substituting and applying rules, and inserting
-// any resulting nodes in its place
-fn substitute_invocation(
-    xot: &mut Xot,
-    node: xot::Node,
-    invocation: xot::Node,
-    context: &Context,
-) -> Result<(), xot::Error> {
-    debug_assert!(!xot.is_removed(node));
-    // comments and text get passed through unmodified
-    let elem_name: String = if let xot::Value::Element(elem) = xot.value(node) {
-        xot.name_ns_str(elem.name()).0.to_string()
-    } else {
-        return Ok(());
-    };
+    /// Write pages as e.g. about/index.html instead of about.html, to be served at /about/
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    pretty_urls: Option<bool>,
 
-    // substitute innermost elements
-    {
-        let children: Vec<xot::Node> = xot.children(node).collect();
-        for child in children {
-            substitute_invocation(xot, child, invocation, context)?;
-        }
-    }
+    /// With --pretty-urls, also write e.g. about.html as a page redirecting to /about/, so that old links keep working
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    redirect_stubs: Option<bool>,
 
-    // substitute <foreachchild.*> tags
-    if elem_name.starts_with("foreachchild.") {
-        return substitute_foreach(xot, node, invocation, context);
-    }
+    /// Highlight the syntax of code in <code lang="..."> elements
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    highlight: Option<bool>,
 
-    // substitute <if> tags
-    if elem_name == "if" {
-        return substitute_if(xot, node, invocation, context);
-    }
+    /// Whether to stop at the first page that fails, or to generate all others and report every failure at the end [default: true]
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    fail_fast: Option<bool>,
 
-    // Look for tags of the form <self.xyz>
-    if elem_name.starts_with("self.") {
-        return substitute_attr(xot, node, invocation);
-    }
+    /// Give generated pages this extension instead of html, e.g. "php" [default: html]
+    #[arg(long = "output-ext", value_name = "EXT")]
+    output_extension: Option<String>,
 
-    Ok(())
-}
+    /// File name of the page served for a directory, e.g. "default.html" [default: index.html]
+    #[arg(long, value_name = "NAME")]
+    index_name: Option<String>,
 
-struct ElementDefinition {
-    tag_name: xot::NameId,
-    node: xot::Node,
-}
+    /// Print each page as it is generated, counting up to the total, to standard error
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true", require_equals = true)]
+    progress: Option<bool>,
 
-impl ElementDefinition {
-    fn from_file(xot: &mut Xot, path: &std::path::Path) -> Result<ElementDefinition, io::Error> {
-        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
-        let mut source_text = fs::read_to_string(path)?;
-
-        // Wrap the document root in a throwaway node because document roots
-        // currently cannot be moved.
-        // See https://github.com/faassen/xot/issues/22
-        source_text.insert_str(0, "<throwaway>");
-        source_text.push_str("</throwaway>");
-
-        let document = xot.parse(&source_text).unwrap_or_else(|err| {
-            panic!(
-                "Failed to parse element definition at {}: {}",
-                path.display(),
-                err
-            )
-        });
+    /// Fail on pages that expand more than this many elements, which usually means that an element expands into itself [default: 100000]
+    #[arg(long, value_name = "N")]
+    max_expansions: Option<usize>,
 
-        Ok(ElementDefinition {
-            tag_name: xot.add_name(&name),
-            node: document,
-        })
-    }
+    /// Run this shell command after a successful build, with BAUMKUCHEN_SOURCE and BAUMKUCHEN_DESTINATION set to the two directories
+    #[arg(long, value_name = "COMMAND")]
+    post_build: Option<String>,
 
-    fn tag_name(&self) -> xot::NameId {
-        self.tag_name
-    }
+    /// Report warnings and errors as "text" or, for editors and other programs, as a "json" array [default: text]
+    #[arg(long, value_name = "FORMAT", value_parser = ["text", "json"])]
+    diagnostics: Option<String>,
 
-    fn instantiate(
-        &self,
-        xot: &mut Xot,
-        invocation: xot::Node,
-        context: &Context,
-    ) -> Result<Vec<xot::Node>, xot::Error> {
-        // unwrap <throwaway> node
-        let node = xot.children(self.node).next().unwrap();
+    /// Write the JSON diagnostics to this file instead of standard output
+    #[arg(long, value_name = "PATH")]
+    diagnostics_output: Option<std::path::PathBuf>,
+}
 
-        let node = xot.clone(node);
+// Exit code for a successful build that found dead links, after those of
+// `BuildError::exit_code`
+const EXIT_DEAD_LINKS: i32 = 5;
+
+// How warnings and errors are reported
+enum Report {
+    // Printed as they happen, for people
+    Text,
+    // Collected and written as a single JSON array at the end, to the
+    // given file or else to standard output
+    Json(Option<std::path::PathBuf>),
+}
 
-        expand_all_attr_strings(xot, node, invocation, context)?;
-        substitute_invocation(xot, node, invocation, context)?;
+impl Report {
+    // Report an error along with the diagnostics collected before it, and exit
+    fn fail(&self, err: BuildError, mut diagnostics: Vec<Diagnostic>) -> ! {
+        match self {
+            Report::Text => eprintln!("Error: {}", err),
+            Report::Json(_) => {
+                diagnostics.extend(err.diagnostics());
+                self.write_json(&diagnostics);
+            }
+        }
+        std::process::exit(err.exit_code());
+    }
 
-        Ok(xot.children(node).collect())
+    fn write_json(&self, diagnostics: &[Diagnostic]) {
+        let json = serde_json::to_string(diagnostics).unwrap();
+        match self {
+            Report::Json(Some(path)) => {
+                if let Err(err) = std::fs::write(path, json + "\n") {
+                    eprintln!("Error: {}: {}", path.display(), err);
+                    std::process::exit(BuildError::from(err).exit_code());
+                }
+            }
+            _ => println!("{}", json),
+        }
     }
 }
 
-struct ElementLibrary {
-    elements: HashMap<xot::NameId, ElementDefinition>,
+// Take a directory from the command line, or else from the config file
+fn require<T>(arg: Option<T>, from_config: Option<T>, name: &str) -> T {
+    arg.or(from_config).unwrap_or_else(|| {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                format!(
+                    "no {} directory was given on the command line or in a config file",
+                    name
+                ),
+            )
+            .exit()
+    })
 }
 
-impl ElementLibrary {
-    fn from_folder(xot: &mut Xot, path: &std::path::Path) -> Result<ElementLibrary, io::Error> {
-        let mut elements = HashMap::new();
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let entry_path = entry.path();
-            if let Some(ext) = entry_path.extension() {
-                if ext == "html" {
-                    let element_defn = ElementDefinition::from_file(xot, &entry_path)?;
-                    let prev = elements.insert(element_defn.tag_name(), element_defn);
-                    assert!(prev.is_none());
-                }
+fn main() {
+    let args = Args::parse();
+    let report = match args.diagnostics.as_deref() {
+        Some("json") => Report::Json(args.diagnostics_output.clone()),
+        _ => Report::Text,
+    };
+
+    let config = match &args.config {
+        Some(path) => Config::from_file(path).unwrap_or_else(|err| report.fail(err, Vec::new())),
+        None => {
+            let path = std::path::Path::new(Config::FILE_NAME);
+            if path.exists() {
+                Config::from_file(path).unwrap_or_else(|err| report.fail(err, Vec::new()))
+            } else {
+                Config::default()
             }
         }
-        Ok(ElementLibrary { elements })
-    }
+    };
 
-    fn elements(&self) -> &HashMap<xot::NameId, ElementDefinition> {
-        &self.elements
+    // Anything given on the command line takes precedence over the config file
+    let mut options = Options::default();
+    config.apply(&mut options);
+    apply_args(&args, &mut options);
+    if options.version.is_none() {
+        options.version = detect_version();
+    }
+    // Warnings are only collected, since the JSON reports them already
+    if let Report::Json(_) = report {
+        options.quiet = true;
+    }
+    let quiet = options.quiet;
+    let check_links = options.check_links;
+    let report_unused = args.report_unused.or(config.report_unused).unwrap_or(false);
+    let post_build = args.post_build.or(config.post_build);
+
+    if args.clean_only {
+        let destination = require(args.destination, config.destination, "destination");
+        clean(&destination, options).unwrap_or_else(|err| report.fail(err, Vec::new()));
+        return;
+    }
+
+    let mut elements: Vec<std::path::PathBuf> = args
+        .elements
+        .iter()
+        .flat_map(|elements| elements.split(','))
+        .map(std::path::PathBuf::from)
+        .collect();
+    elements.extend(args.more_elements);
+    let elements = require(
+        Some(elements).filter(|elements| !elements.is_empty()),
+        config.elements,
+        "elements",
+    );
+
+    if args.print_library {
+        let mut xot = new_xot();
+        let library = ElementLibrary::from_folders(&mut xot, &elements, options.element_overrides)
+            .unwrap_or_else(|err| report.fail(err, Vec::new()));
+        print_library(&library);
+        return;
+    }
+
+    let source = require(args.source, config.source, "source");
+    let destination = require(args.destination, config.destination, "destination");
+
+    let mut generator = Generator::new(&source, &elements, &destination, options)
+        .unwrap_or_else(|err| report.fail(err, Vec::new()));
+    if let Err(err) = generator.run() {
+        report.fail(err, generator.diagnostics());
+    }
+
+    if args.stats {
+        let stats = generator.stats();
+        let summary = format!(
+            "Generated {} pages and copied {} other files in {:.2?}, expanding {} elements",
+            stats.pages, stats.files_copied, stats.duration, stats.instantiations
+        );
+        match report {
+            Report::Text => println!("{}", summary),
+            Report::Json(_) => eprintln!("{}", summary),
+        }
     }
-}
 
-fn substitute(
-    xot: &mut Xot,
-    node: xot::Node,
-    library: &ElementLibrary,
-    context: &Context,
-) -> Result<bool, xot::Error> {
-    let Some(element) = xot.element(node) else {
-        return Ok(false);
-    };
-    let element_name = element.name();
-
-    let mut did_anything = false;
-
-    if let Some(element_defn) = library.elements().get(&element_name) {
-        let instantiation = element_defn
-            .instantiate(xot, node, context)
-            .expect("Failed to instantiate node");
-        for inst_node in instantiation {
-            debug_assert!(!xot.is_removed(node));
-            debug_assert!(!xot.is_removed(inst_node));
-            xot.insert_before(node, inst_node)?;
+    if let Report::Json(_) = report {
+        let mut diagnostics = generator.diagnostics();
+        for (page_path, link) in generator.dead_links() {
+            let page_path = source.join(page_path.trim_start_matches('/'));
+            diagnostics.push(Diagnostic::warning(
+                Some(page_path.display().to_string()),
+                format!("dead link: {}", link),
+            ));
         }
-        // xot.remove(node)?;
-        xot.detach(node)?;
-        did_anything = true;
-    }
-
-    loop {
-        let mut did_anything_inner = false;
-        let children: Vec<xot::Node> = xot.children(node).collect();
-        for child in children {
-            if substitute(xot, child, library, context)? {
-                did_anything_inner = true;
-                did_anything = true;
-                break;
+        if report_unused {
+            for defn in generator.unused_elements() {
+                diagnostics.push(Diagnostic::warning(
+                    Some(defn.path().display().to_string()),
+                    format!("unused element \"{}\"", defn.name()),
+                ));
             }
         }
-        if !did_anything_inner {
-            break;
-        }
+        report.write_json(&diagnostics);
+    } else {
+        print_report(&generator, check_links, report_unused, quiet);
     }
 
-    Ok(did_anything)
-}
+    if let Some(command) = post_build {
+        run_post_build(&command, &source, &destination, &report);
+    }
 
-fn generate_file(
-    xot: &mut Xot,
-    source_root: &path::Path,
-    source_path: &path::Path,
-    dst_path: &path::Path,
-    library: &ElementLibrary,
-) -> Result<(), io::Error> {
-    if !source_path.is_file() {
-        panic!("Source path must be a file: {}", source_path.display());
-    }
-
-    // if dst_path.exists() {
-    //     panic!("Output file already exists: {}", dst_path.display());
-    // }
-
-    let source_text = fs::read_to_string(source_path)?;
-    let document = xot.parse(&source_text).unwrap_or_else(|err| {
-        panic!(
-            "Failed to parse html file at {}: {}",
-            source_path.display(),
-            err
-        )
-    });
-
-    let file_path = "/".to_string()
-        + &source_path
-            .strip_prefix(source_root)
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-
-    let context = Context::new(file_path);
-
-    let children: Vec<xot::Node> = xot.children(document).collect();
-    for node in children {
-        substitute(xot, node, library, &context).expect("Failed to substitute document");
-    }
-
-    minify(xot, document).expect("Failed to minify document");
-
-    let generated_html = xot
-        .html5()
-        .serialize_string(
-            xot::output::html5::Parameters {
-                indentation: None,
-                cdata_section_elements: vec![],
-            },
-            document,
-        )
-        .expect("Failed to serialize html");
-
-    fs::write(dst_path, generated_html)?;
-
-    // remove document node to free memory (hopefully?)
-    xot.remove(document).expect("Failed to remove document");
-
-    Ok(())
+    if check_links && !generator.dead_links().is_empty() {
+        std::process::exit(EXIT_DEAD_LINKS);
+    }
 }
 
-fn clean_folder(path: &std::path::Path) -> Result<(), io::Error> {
-    if !path.exists() {
-        return Ok(());
+// Override the options with those given on the command line
+fn apply_args(args: &Args, options: &mut Options) {
+    let flags = [
+        (args.follow_symlinks, &mut options.follow_symlinks),
+        (args.quiet, &mut options.quiet),
+        (args.auto_ids, &mut options.auto_ids),
+        (args.check_links, &mut options.check_links),
+        (args.trace_components, &mut options.trace_components),
+        (args.strict, &mut options.strict),
+        (args.dedupe_assets, &mut options.dedupe_assets),
+        (args.hoist_assets, &mut options.hoist_assets),
+        (args.pretty_urls, &mut options.pretty_urls),
+        (args.redirect_stubs, &mut options.redirect_stubs),
+        (args.highlight, &mut options.highlight),
+        (args.progress, &mut options.progress),
+        (args.sync, &mut options.sync),
+        (args.no_overwrite, &mut options.no_overwrite),
+        (args.source_maps, &mut options.source_maps),
+        (args.keep_whitespace_text, &mut options.keep_whitespace_text),
+        (args.lowercase_names, &mut options.lowercase_names),
+        (args.fail_fast, &mut options.fail_fast),
+    ];
+    for (arg, option) in flags {
+        if let Some(value) = arg {
+            *option = value;
+        }
+    }
+    if let Some(no_minify) = args.no_minify {
+        options.minify = !no_minify;
+    }
+    if let Some(no_clean) = args.no_clean {
+        options.clean = !no_clean;
+    }
+    if let Some(jobs) = args.jobs {
+        options.jobs = Some(jobs.get());
+    }
+    if let Some(entities) = args.entities {
+        options.entities = entities;
+    }
+    if let Some(attribute_quotes) = args.attribute_quotes {
+        options.attribute_quotes = attribute_quotes;
+    }
+    if let Some(serialization) = args.serialization {
+        options.serialization = serialization;
+    }
+    if args.doctype.is_some() {
+        options.doctype = args.doctype.clone();
+    }
+    if args.env.is_some() {
+        options.env = args.env.clone();
+    }
+    if args.data.is_some() {
+        options.data = args.data.clone();
+    }
+    if args.version.is_some() {
+        options.version = args.version.clone();
+    }
+    if !args.merge_attributes.is_empty() {
+        options.merge_attributes = args.merge_attributes.clone();
+    }
+    if !args.cdata_elements.is_empty() {
+        options.cdata_elements = args.cdata_elements.clone();
+    }
+    if !args.process_extensions.is_empty() {
+        options.process_extensions = args.process_extensions.clone();
     }
+    if let Some(max_expansions) = args.max_expansions {
+        options.max_expansions = max_expansions;
+    }
+    if args.output_extension.is_some() {
+        options.output_extension = args.output_extension.clone();
+    }
+    if args.index_name.is_some() {
+        options.index_name = args.index_name.clone();
+    }
+    if let Some(only) = &args.only {
+        // "./blog/" is the same part of the source as "blog"
+        options.only = Some(
+            only.components()
+                .filter(|component| *component != std::path::Component::CurDir)
+                .collect(),
+        );
+    }
+    if !args.passthrough.is_empty() {
+        options.passthrough = args.passthrough.clone();
+    }
+    if args.strip_name_suffix.is_some() {
+        options.strip_name_suffix = args.strip_name_suffix.clone();
+    }
+    if let Some(element_overrides) = args.element_overrides {
+        options.element_overrides = element_overrides;
+    }
+}
 
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        if entry.file_name().to_str().unwrap().starts_with(".") {
-            println!(
-                "Not deleting \"{}\" at \"{}\"",
-                entry.file_name().to_str().unwrap(),
-                path.display()
-            );
-            continue;
+// Run the post-build command in the shell, exiting with its exit code if it
+// fails. Its output goes to standard error when standard output is for JSON.
+fn run_post_build(
+    command: &str,
+    source: &std::path::Path,
+    destination: &std::path::Path,
+    report: &Report,
+) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = std::process::Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell
+        .arg(command)
+        .env("BAUMKUCHEN_SOURCE", source)
+        .env("BAUMKUCHEN_DESTINATION", destination);
+    if let Report::Json(_) = report {
+        shell.stdout(std::io::stderr());
+    }
+    match shell.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            eprintln!("Error: the post-build command failed ({})", status);
+            std::process::exit(status.code().unwrap_or(1));
         }
-        let entry_type = entry.file_type()?;
-        if entry_type.is_file() {
-            fs::remove_file(entry.path())?;
-        } else if entry_type.is_dir() {
-            fs::remove_dir_all(entry.path())?;
+        Err(err) => {
+            eprintln!("Error: failed to run the post-build command: {}", err);
+            std::process::exit(BuildError::from(err).exit_code());
         }
     }
-
-    Ok(())
 }
 
-fn generate_folder(
-    xot: &mut Xot,
-    source_root: &path::Path,
-    source_path: &std::path::Path,
-    dst_path: &std::path::Path,
-    library: &ElementLibrary,
-) -> Result<(), io::Error> {
-    if !source_path.is_dir() {
-        panic!("Source path must be a directory: {}", source_path.display());
-    }
-
-    // if dst_path.exists() {
-    //     panic!("Output directory already exists: {}", dst_path.display());
-    // }
-
-    if !dst_path.exists() {
-        fs::create_dir(dst_path)?;
-    }
-
-    for entry in fs::read_dir(source_path)? {
-        let entry = entry?;
-        let entry_path = entry.path();
-        let entry_type = entry.file_type()?;
-        let entry_name = entry_path.file_name().unwrap();
-        if entry_type.is_dir() {
-            generate_folder(
-                xot,
-                source_root,
-                &entry_path,
-                &dst_path.join(entry_name),
-                library,
-            )?;
-        } else if entry_type.is_file() {
-            if let Some(ext) = entry_path.extension() {
-                if ext == "html" {
-                    generate_file(
-                        xot,
-                        source_root,
-                        &entry_path,
-                        &dst_path.join(entry_name),
-                        library,
-                    )?;
-                    continue;
-                }
-            }
+// Find the version of the site when none was given, from a VERSION file in the
+// working directory, or else from the latest git tag
+fn detect_version() -> Option<String> {
+    if let Ok(version) = std::fs::read_to_string("VERSION") {
+        return Some(version.trim().to_string()).filter(|version| !version.is_empty());
+    }
+    let output = std::process::Command::new("git")
+        .args(["describe", "--tags", "--always"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version = String::from_utf8(output.stdout).ok()?;
+    Some(version.trim().to_string()).filter(|version| !version.is_empty())
+}
 
-            fs::copy(&entry_path, dst_path.join(entry_name))?;
+// Print the name, aliases, and file of every element in the library, sorted
+// by name
+fn print_library(library: &ElementLibrary) {
+    let mut elements: Vec<&ElementDefinition> = library.elements().values().collect();
+    elements.sort_by(|a, b| a.name().cmp(b.name()));
+    for defn in elements {
+        let aliases: Vec<&str> = defn.aliases().collect();
+        if aliases.is_empty() {
+            println!("{} {}", defn.name(), defn.path().display());
+        } else {
+            println!(
+                "{} (also {}) {}",
+                defn.name(),
+                aliases.join(", "),
+                defn.path().display()
+            );
         }
     }
-    Ok(())
 }
 
-#[derive(Parser, Debug)]
-#[command(about)]
-struct Args {
-    source: std::path::PathBuf,
-    elements: std::path::PathBuf,
-    destination: std::path::PathBuf,
+// Print the dead links and unused elements found by the build, if asked to
+fn print_report(generator: &Generator, check_links: bool, report_unused: bool, quiet: bool) {
+    if check_links {
+        for (page_path, link) in generator.dead_links() {
+            println!("Dead link in {}: {}", page_path, link);
+        }
+        if generator.dead_links().is_empty() && !quiet {
+            println!("No dead links found");
+        }
+    }
+    if report_unused {
+        let unused = generator.unused_elements();
+        if unused.is_empty() && !quiet {
+            println!("All elements were used");
+        }
+        for defn in unused {
+            println!(
+                "Unused element \"{}\" defined at {}",
+                defn.name(),
+                defn.path().display()
+            );
+        }
+    }
 }
 
-fn main() {
-    let args = Args::parse();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut xot = Xot::new();
+    // The options for a command line, on top of those of a config file
+    fn options(config: &str, args: &[&str]) -> Options {
+        let config = Config::parse(config).unwrap();
+        let args = Args::try_parse_from(["html-generator"].iter().chain(args)).unwrap();
+        let mut options = Options::default();
+        config.apply(&mut options);
+        apply_args(&args, &mut options);
+        options
+    }
+
+    #[test]
+    fn command_line_flags_override_the_config_either_way() {
+        let config = "minify = false\nstrict = true\n";
 
-    // Disable text consolidation (merging of text nodes while modifying)
-    // because it wreacks havoc when modifying nodes while iterating.
-    // See https://github.com/faassen/xot/issues/25
-    xot.set_text_consolidation(false);
+        let from_config = options(config, &[]);
+        assert!(!from_config.minify);
+        assert!(from_config.strict);
 
-    let library =
-        ElementLibrary::from_folder(&mut xot, &args.elements).expect("Failed to load elements");
+        let overridden = options(config, &["--no-minify=false", "--strict=false"]);
+        assert!(overridden.minify);
+        assert!(!overridden.strict);
 
-    clean_folder(&args.destination).expect("Failed to clean output directory");
+        let turned_on = options("strict = false\n", &["--strict"]);
+        assert!(turned_on.strict);
+    }
 
-    generate_folder(
-        &mut xot,
-        &args.source,
-        &args.source,
-        &args.destination,
-        &library,
-    )
-    .expect("Failed to generate");
+    #[test]
+    fn flags_without_a_value_leave_the_positional_arguments_alone() {
+        let args =
+            Args::try_parse_from(["html-generator", "--strict", "pages", "elements", "output"])
+                .unwrap();
+        assert_eq!(args.strict, Some(true));
+        assert_eq!(args.source, Some(std::path::PathBuf::from("pages")));
+        assert_eq!(args.destination, Some(std::path::PathBuf::from("output")));
+    }
 }
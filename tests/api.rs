@@ -0,0 +1,200 @@
+//! Tests using baumkuchen the way another program would, as a library
+
+use html_generator::{
+    generate, generate_with_diagnostics, Context, ElementHandler, Generator, Options, Severity,
+};
+use std::fs;
+
+#[test]
+fn generates_a_site_through_the_public_api() {
+    let root = std::env::temp_dir().join(format!("baumkuchen-api-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("pages/blog")).unwrap();
+    fs::create_dir_all(root.join("elements")).unwrap();
+    fs::write(
+        root.join("elements/greeting.html"),
+        r#"<p class="greeting">Hello, <self.name />!</p>"#,
+    )
+    .unwrap();
+    fs::write(
+        root.join("pages/blog/index.html"),
+        r#"<html><body><greeting name="World" /></body></html>"#,
+    )
+    .unwrap();
+
+    let result = generate(
+        &root.join("pages"),
+        &[root.join("elements")],
+        &root.join("output"),
+        Options {
+            quiet: true,
+            ..Options::default()
+        },
+    );
+    let page = fs::read_to_string(root.join("output/blog/index.html"));
+    fs::remove_dir_all(&root).unwrap();
+
+    result.unwrap();
+    assert_eq!(
+        page.unwrap(),
+        "<!DOCTYPE html><html><body><p class=\"greeting\">Hello, World!</p></body></html>"
+    );
+}
+
+// Replaces <uppercase>text</uppercase> with TEXT
+struct Uppercase;
+
+impl ElementHandler for Uppercase {
+    fn handle(
+        &self,
+        xot: &mut xot::Xot,
+        invocation: xot::Node,
+        _context: &Context,
+    ) -> Result<Option<Vec<xot::Node>>, xot::Error> {
+        let text: String = xot
+            .descendants(invocation)
+            .filter_map(|node| xot.text(node).map(|text| text.get().to_string()))
+            .collect();
+        Ok(Some(vec![xot.new_text(&text.to_uppercase())]))
+    }
+}
+
+#[test]
+fn expands_elements_with_a_registered_handler() {
+    let root = std::env::temp_dir().join(format!("baumkuchen-handler-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("pages")).unwrap();
+    fs::create_dir_all(root.join("elements")).unwrap();
+    // Handlers win over elements of the same name in the library
+    fs::write(
+        root.join("elements/uppercase.html"),
+        "<i><self.inner /></i>",
+    )
+    .unwrap();
+    fs::write(
+        root.join("pages/index.html"),
+        "<html><body><p><uppercase>Hello, <b>world</b></uppercase>!</p></body></html>",
+    )
+    .unwrap();
+
+    let result = Generator::new(
+        &root.join("pages"),
+        &[root.join("elements")],
+        &root.join("output"),
+        Options {
+            quiet: true,
+            ..Options::default()
+        },
+    )
+    .and_then(|mut generator| {
+        generator.register_handler("uppercase", Uppercase);
+        generator.run()
+    });
+    let page = fs::read_to_string(root.join("output/index.html"));
+    fs::remove_dir_all(&root).unwrap();
+
+    result.unwrap();
+    assert_eq!(
+        page.unwrap(),
+        "<!DOCTYPE html><html><body><p>HELLO, WORLD!</p></body></html>"
+    );
+}
+
+// Shouts the text of <shout loud="">, leaving any other <shout> to the library
+struct Shout;
+
+impl ElementHandler for Shout {
+    fn handle(
+        &self,
+        xot: &mut xot::Xot,
+        invocation: xot::Node,
+        _context: &Context,
+    ) -> Result<Option<Vec<xot::Node>>, xot::Error> {
+        let loud = xot
+            .name("loud")
+            .is_some_and(|name| xot.attributes(invocation).get(name).is_some());
+        if !loud {
+            return Ok(None);
+        }
+        let text: String = xot
+            .descendants(invocation)
+            .filter_map(|node| xot.text(node).map(|text| text.get().to_string()))
+            .collect();
+        Ok(Some(vec![xot.new_text(&text.to_uppercase())]))
+    }
+}
+
+#[test]
+fn leaves_elements_a_handler_declines_to_the_library() {
+    let root = std::env::temp_dir().join(format!("baumkuchen-declined-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("pages")).unwrap();
+    fs::create_dir_all(root.join("elements")).unwrap();
+    fs::write(root.join("elements/shout.html"), "<b><self.inner /></b>").unwrap();
+    fs::write(
+        root.join("pages/index.html"),
+        r#"<html><body><p><shout>hi</shout> <shout loud="">hi</shout></p></body></html>"#,
+    )
+    .unwrap();
+
+    // Declining doesn't count as an expansion, so two are enough
+    let result = Generator::new(
+        &root.join("pages"),
+        &[root.join("elements")],
+        &root.join("output"),
+        Options {
+            quiet: true,
+            max_expansions: 2,
+            ..Options::default()
+        },
+    )
+    .and_then(|mut generator| {
+        generator.register_handler("shout", Shout);
+        generator.run()
+    });
+    let page = fs::read_to_string(root.join("output/index.html"));
+    fs::remove_dir_all(&root).unwrap();
+
+    result.unwrap();
+    assert_eq!(
+        page.unwrap(),
+        "<!DOCTYPE html><html><body><p><b>hi</b> HI</p></body></html>"
+    );
+}
+
+#[test]
+fn returns_warnings_from_a_successful_build() {
+    let root = std::env::temp_dir().join(format!("baumkuchen-diagnostics-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("pages")).unwrap();
+    fs::create_dir_all(root.join("elements")).unwrap();
+    fs::write(
+        root.join("elements/greeting.html"),
+        r#"<p class="greeting">Hello, <self.name />!</p>"#,
+    )
+    .unwrap();
+    fs::write(
+        root.join("pages/index.html"),
+        "<html><body><greeting /></body></html>",
+    )
+    .unwrap();
+
+    let report = generate_with_diagnostics(
+        &root.join("pages"),
+        &[root.join("elements")],
+        &root.join("output"),
+        Options {
+            quiet: true,
+            ..Options::default()
+        },
+    );
+    let page_exists = root.join("output/index.html").exists();
+    fs::remove_dir_all(&root).unwrap();
+
+    report.result.unwrap();
+    assert!(page_exists);
+    assert_eq!(report.diagnostics.len(), 1, "{:?}", report.diagnostics);
+    let warning = &report.diagnostics[0];
+    assert_eq!(warning.severity, Severity::Warning);
+    assert!(warning.message.contains("name"), "{}", warning.message);
+}
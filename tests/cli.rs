@@ -0,0 +1,194 @@
+//! Tests running the html-generator binary itself
+
+use std::{fs, path, process};
+
+// A temporary directory with a source, an elements, and a destination
+// directory in it, deleted again when the test is done
+struct Site {
+    root: path::PathBuf,
+}
+
+impl Site {
+    fn new(name: &str) -> Site {
+        let root = std::env::temp_dir().join(format!("baumkuchen-cli-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["pages", "elements", "output"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        Site { root }
+    }
+
+    fn path(&self, path: &str) -> path::PathBuf {
+        self.root.join(path)
+    }
+
+    fn write(&self, path: &str, contents: &str) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    // Run the generator on the three directories with some more arguments
+    fn run(&self, args: &[&str]) -> process::Output {
+        process::Command::new(env!("CARGO_BIN_EXE_html-generator"))
+            .current_dir(&self.root)
+            .args(["pages", "elements", "output"])
+            .args(args)
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for Site {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+// Everything the generator printed, to either stream
+fn printed(output: &process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr)
+}
+
+#[test]
+fn quiet_only_prints_errors() {
+    let site = Site::new("quiet");
+    site.write("output/.keep", "");
+    site.write("pages/index.html", "<html><body>Hi</body></html>");
+
+    let output = site.run(&[]);
+    assert!(output.status.success());
+    assert!(printed(&output).contains("Not deleting"));
+
+    let output = site.run(&["--quiet"]);
+    assert!(output.status.success());
+    assert_eq!(printed(&output), "");
+
+    site.write("pages/broken.html", "<html><body></html>");
+    let output = site.run(&["--quiet"]);
+    assert!(!output.status.success());
+    assert!(!printed(&output).contains("Not deleting"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("broken.html"));
+}
+
+#[test]
+fn clean_only_empties_the_destination_without_generating() {
+    let site = Site::new("clean-only");
+    site.write("pages/index.html", "<html><body>Hi</body></html>");
+    site.write("output/old.html", "old");
+
+    let output = site.run(&["--clean-only", "--quiet"]);
+    assert!(output.status.success());
+    assert_eq!(fs::read_dir(site.path("output")).unwrap().count(), 0);
+
+    site.write("output/old.html", "old");
+    let output = site.run(&["--no-clean", "--quiet"]);
+    assert!(output.status.success());
+    assert!(site.path("output/old.html").exists());
+    assert!(site.path("output/index.html").exists());
+}
+
+#[test]
+fn reports_diagnostics_as_json() {
+    let site = Site::new("json");
+    site.write("elements/card.html", r#"<div title="${self.missing}" />"#);
+    site.write("pages/index.html", "<html><body><card /></body></html>");
+
+    let output = site.run(&["--diagnostics", "json"]);
+    assert!(output.status.success());
+    let diagnostics: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let diagnostics = diagnostics.as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert_eq!(diagnostics[0]["severity"], "warning");
+    assert!(diagnostics[0]["file"]
+        .as_str()
+        .unwrap()
+        .ends_with("index.html"));
+    assert!(diagnostics[0]["message"]
+        .as_str()
+        .unwrap()
+        .contains("missing"));
+
+    site.write("pages/broken.html", "<html><body></html>");
+    let output = site.run(&["--diagnostics", "json"]);
+    assert_eq!(output.status.code(), Some(2));
+    let diagnostics: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let last = diagnostics.as_array().unwrap().last().unwrap();
+    assert_eq!(last["severity"], "error");
+    assert!(last["file"].as_str().unwrap().ends_with("broken.html"));
+}
+
+#[test]
+fn progress_counts_up_to_the_number_of_pages() {
+    let site = Site::new("progress");
+    for name in ["a", "b", "c"] {
+        site.write(
+            &format!("pages/{}.html", name),
+            "<html><body>Hi</body></html>",
+        );
+    }
+    site.write("pages/style.css", "body {}");
+
+    let output = site.run(&["--progress", "--quiet"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut counts: Vec<&str> = stderr
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    counts.sort();
+    assert_eq!(counts, ["[1/3]", "[2/3]", "[3/3]"]);
+    let mut pages: Vec<&str> = stderr
+        .lines()
+        .map(|line| line.split(' ').nth(1).unwrap())
+        .collect();
+    pages.sort();
+    assert_eq!(pages, ["/a.html", "/b.html", "/c.html"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn post_build_runs_after_the_build_and_its_failure_fails_it() {
+    let site = Site::new("post-build");
+    site.write("pages/index.html", "<html><body>Hi</body></html>");
+
+    let output = site.run(&[
+        "--quiet",
+        "--post-build",
+        "cp \"$BAUMKUCHEN_DESTINATION/index.html\" copied.html",
+    ]);
+    assert!(output.status.success(), "{}", printed(&output));
+    assert_eq!(
+        fs::read_to_string(site.path("copied.html")).unwrap(),
+        fs::read_to_string(site.path("output/index.html")).unwrap()
+    );
+
+    let output = site.run(&["--quiet", "--post-build", "exit 7"]);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(printed(&output).contains("post-build command failed"));
+}
+
+#[test]
+fn print_library_lists_the_elements_without_generating() {
+    let site = Site::new("print-library");
+    site.write("pages/index.html", "<html><body><sitecard /></body></html>");
+    site.write("elements/sitecard.html", "<div class=\"card\"></div>");
+    site.write(
+        "elements/fancylink.html",
+        "<config alias=\"flink\" /><a class=\"fancy\"></a>",
+    );
+
+    let output = site.run(&["--print-library"]);
+    assert!(output.status.success(), "{}", printed(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    let sep = path::MAIN_SEPARATOR;
+    assert_eq!(
+        lines,
+        [
+            format!("fancylink (also flink) elements{}fancylink.html", sep),
+            format!("sitecard elements{}sitecard.html", sep),
+        ]
+    );
+    assert!(!site.path("output/index.html").exists());
+}